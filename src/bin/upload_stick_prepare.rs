@@ -1,14 +1,36 @@
 extern crate upload_stick;
 
+use std::env;
+use std::path::Path;
 use std::process::{self, Command};
-use std::str;
+use upload_stick::config::Config;
+use upload_stick::file_system::{self, DEFAULT_VOLUME_LABEL, FileSystemType};
+use upload_stick::parted::{self, DiskLayout, PartitionTable};
+use upload_stick::prepare::{Journal, Step, print_plan, rollback, run_steps};
+use upload_stick::size::{Size, Unit};
+use upload_stick::storage::{self, StorageBackend};
 use upload_stick::upload_command::*;
 
+struct Options {
+    label: PartitionTable,
+    file_system: FileSystemType,
+    volume_label: String,
+    dry_run: bool,
+}
+
 fn main() {
     println!("Preparing mass storage volume");
 
-    process::exit(match prepare() {
-        Ok(_) => {
+    let result = parse_options(env::args().skip(1))
+        .and_then(|options| Config::load().map(|config| (options, config)))
+        .and_then(|(options, config)| prepare(&options, &config).map(|_| options.dry_run));
+
+    process::exit(match result {
+        Ok(true) => {
+            println!("Dry run finished without changing anything");
+            0
+        },
+        Ok(false) => {
             println!("Successfully prepared mass storage volume");
             0
         },
@@ -19,96 +41,160 @@ fn main() {
     });
 }
 
-fn prepare() -> Result<()> {
-    println!("Resizing root partition");
-    command_stdout(
-        Command::new("parted")
-            .arg("--script")
-            .arg("/dev/mmcblk0")
-            .arg("resizepart").arg("2").arg("2GiB")
-    )?;
-
-    println!("Resizing root file system");
-    command_stdout(Command::new("resize2fs").arg("/dev/mmcblk0p2"))?;
-
-    println!("Getting SD partitions");
-    let parted_output = command_stdout(
-        Command::new("parted")
-            .arg("--script")
-            .arg("--machine")
-            .arg("/dev/mmcblk0")
-            .arg("unit").arg("MB")
-            .arg("print").arg("free")
-    )?;
-
-    let (from_str, to_str) = parted_find_last_free(&parted_output)?;
-
-    println!("Adding SD partition");
-    command_stdout(
-        Command::new("parted")
-            .arg("--script")
-            .arg("/dev/mmcblk0")
-            .arg("mkpart").arg("primary").arg("").arg(from_str).arg(to_str)
-    )?;
-
-    println!("Making PV");
-    command_stdout(Command::new("pvcreate").arg("/dev/mmcblk0p3"))?;
-
-    println!("Making VG");
-    command_stdout(Command::new("vgcreate").arg("data").arg("/dev/mmcblk0p3"))?;
-
-    println!("Making LV");
-    command_stdout(
-        Command::new("lvcreate")
-            .arg("--extents").arg("70%FREE").arg("--name").arg("mass_storage_root").arg("data")
-    )?;
-
-    println!("Writing mass storage partition label");
-    command_stdout(
-        Command::new("parted")
-            .arg("--script")
-            .arg("/dev/data/mass_storage_root")
-            .arg("mklabel").arg("msdos")
-    )?;
-
-    println!("Adding mass storage partition");
-    command_stdout(
-        Command::new("parted")
-            .arg("--script")
-            .arg("/dev/data/mass_storage_root")
-            .arg("--")
-            .arg("mkpart").arg("primary").arg("fat32").arg("4MiB").arg("-1s")
-    )?;
-
-    map_lv_partition("mass_storage_root", "mass_storage_partition", MapMode::ReadWrite)?;
-
-    println!("Initializing file system");
-    command_stdout(
-        Command::new("mkfs.fat")
-            .arg("/dev/mapper/mass_storage_partition")
-            .arg("-F").arg("32")
-            .arg("-n").arg("PI_UPLOAD")
-    )?;
+fn parse_options<I: Iterator<Item = String>>(mut args: I) -> Result<Options> {
+    let mut options = Options {
+        label: PartitionTable::Msdos,
+        file_system: FileSystemType::Fat,
+        volume_label: DEFAULT_VOLUME_LABEL.to_string(),
+        dry_run: false,
+    };
+
+    while let Some(arg) = args.next() {
+        match arg.as_str() {
+            "--label" => {
+                options.label = match args.next().as_deref() {
+                    Some("msdos") => PartitionTable::Msdos,
+                    Some("gpt") => PartitionTable::Gpt,
+                    other => return Err(Error::InvalidArgument(format!("--label {}", other.unwrap_or("")))),
+                };
+            },
+            "--file-system" => {
+                options.file_system = match args.next() {
+                    Some(name) => FileSystemType::parse(&name)?,
+                    None => return Err(Error::InvalidArgument("--file-system".to_string())),
+                };
+            },
+            "--volume-label" => {
+                options.volume_label = args.next()
+                    .ok_or_else(|| Error::InvalidArgument("--volume-label".to_string()))?;
+            },
+            "--dry-run" => options.dry_run = true,
+            _ => return Err(Error::InvalidArgument(arg)),
+        }
+    }
+
+    options.file_system.check_label(&options.volume_label)?;
+
+    Ok(options)
+}
+
+fn prepare(options: &Options, config: &Config) -> Result<()> {
+    let backend = storage::from_config(config);
+
+    let journal = Journal::new(Path::new(JOURNAL_PATH));
+    if options.dry_run {
+        print_plan(&backend.create_steps(), &journal)?;
+        return print_plan(&volume_steps(options, &backend.inspect_origin()), &journal);
+    }
+
+    if let Err(err) = create_volume(options, &*backend, &journal) {
+        println!("Rolling back after failure: {}", err);
+        // Nothing may hold the origin open while it is removed
+        unmap_partition(MAPPED_PARTITION, CommandCheck::IgnoreOutput)?;
+        backend.deactivate()?;
+        rollback(&backend.create_steps(), &journal)?;
+        return Err(err);
+    }
+
+    journal.clear()
+}
+
+fn create_volume(options: &Options, backend: &dyn StorageBackend, journal: &Journal) -> Result<()> {
+    run_steps(&backend.create_steps(), journal)?;
+    println!("{}", backend.summary()?);
+    let origin = backend.activate()?;
+
+    run_steps(&volume_steps(options, &origin), journal)?;
+
+    backend.deactivate()
+}
+
+const MAPPED_PARTITION: &str = "mass_storage_partition";
+const JOURNAL_PATH: &str = "/var/lib/upload-stick/prepare.journal";
+
+fn volume_steps<'a>(options: &'a Options, origin: &'a str) -> Vec<Step<'a>> {
+    vec![
+        Step::command("write-partition-label", "Writing mass storage partition label",
+            move || Ok(read_origin_layout(origin).is_some_and(|layout| layout.table == options.label)),
+            move || {
+                let mut command = Command::new("parted");
+                command
+                    .arg("--script")
+                    .arg(origin)
+                    .arg("mklabel").arg(options.label.label());
+                Ok(command)
+            }),
+        Step::command("add-partition", "Adding mass storage partition",
+            move || Ok(read_origin_layout(origin).is_some_and(|layout| !layout.partitions.is_empty())),
+            move || {
+                let mut command = Command::new("parted");
+                command
+                    .arg("--script")
+                    .arg(origin)
+                    .arg("--")
+                    .arg("mkpart").arg(partition_name(&options.label)).arg(options.file_system.parted_type())
+                    .arg(Size::new(4.0, Unit::Mebibytes).to_string())
+                    .arg(partition_end(&options.label).to_string());
+                Ok(command)
+            }),
+        Step::new("make-file-system", &format!("Initializing {} file system labelled {}", options.file_system.name(), options.volume_label),
+            move || has_file_system(origin, options.file_system),
+            move || make_file_system(options, origin))
+            .with_plan(move || Ok(command_line(&mkfs_command(options))))
+            // mkfs writes the signature that has_file_system looks for before it finishes
+            .with_journalled_start(),
+    ]
+}
+
+// An unlabelled or missing device has no layout yet
+fn read_origin_layout(origin: &str) -> Option<DiskLayout> {
+    parted::read_layout(origin, Unit::Bytes).ok()
+}
+
+fn has_file_system(origin: &str, file_system: FileSystemType) -> Result<bool> {
+    let layout = match read_origin_layout(origin) {
+        Some(layout) => layout,
+        None => return Ok(false),
+    };
+    let partition = match layout.partitions.first() {
+        Some(partition) => partition,
+        None => return Ok(false),
+    };
+    let offset = partition.start.to_bytes(layout.logical_sector_size)?;
+    Ok(file_system::detect_at(Path::new(origin), offset)? == Some(file_system))
+}
+
+fn make_file_system(options: &Options, origin: &str) -> Result<()> {
+    // A previous interrupted run may have left the mapping behind
+    unmap_partition(MAPPED_PARTITION, CommandCheck::IgnoreOutput)?;
+    map_device_partition(origin, MAPPED_PARTITION, MapMode::ReadWrite)?;
+
+    command_stdout(&mut mkfs_command(options))?;
 
     command_stdout(&mut Command::new("sync"))?;
 
-    unmap_partition("mass_storage_partition", CommandCheck::Retry {
+    unmap_partition(MAPPED_PARTITION, CommandCheck::Retry {
         count: 5,
         interval: std::time::Duration::from_secs(3)
-    })?;
+    })
+}
 
-    Ok(())
+fn mkfs_command(options: &Options) -> Command {
+    options.file_system.mkfs_command("/dev/mapper/mass_storage_partition", &options.volume_label)
 }
 
-fn parted_find_last_free(parted_output: &str) -> Result<(String, String)> {
-    let free_line = parted_output.lines()
-        .filter(|line| line.trim().ends_with("free;"))
-        .last()
-        .ok_or(Error::PartitionFreeNotFound(parted_output.to_string()))?;
+fn partition_name(label: &PartitionTable) -> &'static str {
+    match label {
+        PartitionTable::Gpt => "PI_UPLOAD",
+        _ => "primary",
+    }
+}
 
-    match free_line.split(":").take(3).collect::<Vec<&str>>().as_slice() {
-        [_, from, to] => Ok((String::from(from.trim()), String::from(to.trim()))),
-        _ => Err(Error::PartitionFreeFieldsNotFound(free_line.to_string()))
+fn partition_end(label: &PartitionTable) -> Size {
+    match label {
+        // Leave room for the backup GPT at the end of the device
+        PartitionTable::Gpt => Size::new(100.0, Unit::Percent),
+        _ => Size::new(-1.0, Unit::Sectors),
     }
 }
 
@@ -116,17 +202,38 @@ fn parted_find_last_free(parted_output: &str) -> Result<(String, String)> {
 mod tests {
     use super::*;
 
+    fn args(args: &[&str]) -> std::vec::IntoIter<String> {
+        args.iter().map(|arg| arg.to_string()).collect::<Vec<String>>().into_iter()
+    }
+
+    #[test]
+    fn test_parse_options() {
+        assert_eq!(parse_options(args(&[])).unwrap().label, PartitionTable::Msdos);
+        assert_eq!(parse_options(args(&["--label", "gpt"])).unwrap().label, PartitionTable::Gpt);
+        assert!(parse_options(args(&["--label", "sun"])).is_err());
+        assert!(parse_options(args(&["--label"])).is_err());
+        assert!(parse_options(args(&["--unknown"])).is_err());
+    }
+
+    #[test]
+    fn test_parse_options_dry_run() {
+        assert!(!parse_options(args(&[])).unwrap().dry_run);
+        assert!(parse_options(args(&["--dry-run", "--label", "gpt"])).unwrap().dry_run);
+    }
+
     #[test]
-    fn test_parted_find_last_free() {
-        let (from, to) = parted_find_last_free("
-            BYT;
-            /dev/mmcblk0:31915MB:sd/mmc:512:512:msdos:SD ACLCD:;
-            1:0.02MB:4.19MB:4.18MB:free;
-            1:4.19MB:46.1MB:41.9MB:fat16::boot, lba;
-            2:46.1MB:201MB:155MB:ext3::;
-            1:201MB:31915MB:31714MB:free;
-        ").unwrap();
-        assert_eq!(from, "201MB");
-        assert_eq!(to, "31915MB");
+    fn test_parse_options_file_system() {
+        let options = parse_options(args(&[])).unwrap();
+        assert_eq!(options.file_system, FileSystemType::Fat);
+        assert_eq!(options.volume_label, "PI_UPLOAD");
+
+        let options = parse_options(args(&["--file-system", "exfat", "--volume-label", "Recordings"])).unwrap();
+        assert_eq!(options.file_system, FileSystemType::Exfat);
+        assert_eq!(options.volume_label, "Recordings");
+
+        assert!(parse_options(args(&["--file-system", "ext4"])).is_err());
+        assert!(parse_options(args(&["--volume-label"])).is_err());
+        assert!(parse_options(args(&["--volume-label", "MULTITRACK_SESSIONS"])).is_err());
+        assert!(parse_options(args(&["--file-system", "ntfs", "--volume-label", "MULTITRACK_SESSIONS"])).is_ok());
     }
 }
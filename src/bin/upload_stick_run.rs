@@ -1,17 +1,32 @@
+extern crate chrono;
 extern crate upload_stick;
 
 use std::fs::{self, File};
 use std::io::prelude::*;
 use std::path::{Path, PathBuf};
 use std::process::{self, Command};
+use std::time::{Duration, Instant};
+use chrono::Local;
+use upload_stick::config::{Config, ScanReader, UploadConfig};
+use upload_stick::fat_check::{self, FatCheck};
+use upload_stick::file_name;
+use upload_stick::file_system::{self, FileSystemType};
+use upload_stick::gadget::{self, Lun};
+use upload_stick::retention;
+use upload_stick::status::{self, Status};
+use upload_stick::stick_config::{self, Outcome};
+use upload_stick::storage::{self, StorageBackend};
+use upload_stick::udc::{self, UdcWatcher};
 use upload_stick::upload_command::*;
 use upload_stick::upload_db;
+use upload_stick::upload_rules;
+use upload_stick::volume::{FatVolume, MountedVolume, Volume, VolumeEntry};
 
-const GPIO_GREEN: &'static str = "23";
-const GPIO_YELLOW: &'static str = "25";
-const GPIO_BLUE: &'static str = "12";
-const GPIO_RED: &'static str = "20";
-const GPIO_ALL: [&'static str; 4] = [GPIO_GREEN, GPIO_YELLOW, GPIO_BLUE, GPIO_RED];
+const GPIO_GREEN: &str = "23";
+const GPIO_YELLOW: &str = "25";
+const GPIO_BLUE: &str = "12";
+const GPIO_RED: &str = "20";
+const GPIO_ALL: [&str; 4] = [GPIO_GREEN, GPIO_YELLOW, GPIO_BLUE, GPIO_RED];
 
 fn main() {
     println!("Starting monitoring and upload of files");
@@ -29,12 +44,15 @@ fn main() {
 }
 
 fn run() -> Result<()> {
+    let config = Config::load()?;
+    let backend = storage::from_config(&config);
+
     prepare_leds()?;
     set_leds(&[GPIO_GREEN])?;
 
-    clean_snapshot()?;
+    clean_snapshot(backend.as_ref())?;
 
-    match main_loop() {
+    match main_loop(&config, backend.as_ref()) {
         Ok(_) => {
             println!("File monitoring finished unexpectedly");
         },
@@ -47,30 +65,96 @@ fn run() -> Result<()> {
     Ok(())
 }
 
-fn clean_snapshot() -> Result<()> {
-    command_ignore_output(
-        Command::new("umount").arg("/mnt")
-    )?;
+const SNAP_PARTITION_PREFIX: &str = "mass_storage_snap_partition";
 
-    unmap_partition("mass_storage_snap_partition", CommandCheck::IgnoreOutput)?;
+fn mount_root() -> PathBuf {
+    PathBuf::from("/mnt")
+}
 
-    command_ignore_output(
-        Command::new("lvremove")
-            .arg("--yes")
-            .arg("data/mass_storage_snap")
-    )?;
+fn partition_mount_path(number: u32) -> PathBuf {
+    mount_root().join(format!("partition{}", number))
+}
 
-    Ok(())
+const SNAPSHOT_POLL_INTERVAL: Duration = Duration::from_secs(2);
+// How long the cleanup waits for a host to stop reading before trying again next cycle
+const UNUSED_WAIT_LIMIT: Duration = Duration::from_secs(60);
+
+fn clean_snapshot(backend: &dyn StorageBackend) -> Result<()> {
+    if let Ok(entries) = fs::read_dir(mount_root()) {
+        for entry in entries.flatten() {
+            if entry.file_name().to_string_lossy().starts_with("partition") {
+                command_ignore_output(
+                    Command::new("umount").arg(entry.path())
+                )?;
+            }
+        }
+    }
+
+    for mapped_name in list_mappings(SNAP_PARTITION_PREFIX).unwrap_or_default() {
+        unmap_partition(&mapped_name, CommandCheck::IgnoreOutput)?;
+    }
+
+    backend.release(&CommandCheck::IgnoreOutput)
 }
 
-fn main_loop() -> Result<()> {
+#[derive(Debug, PartialEq)]
+enum Wait {
+    Reached,
+    // Nothing more will be written, so there is no point waiting for the writes to settle
+    HostDisconnected,
+    TimedOut,
+}
+
+fn main_loop(config: &Config, backend: &dyn StorageBackend) -> Result<()> {
+    let mut watcher = UdcWatcher::new(&udc::udc_root()).publish(&udc::status_path());
+    watcher.poll()?;
+    let mut upload = initial_upload_config(config);
+    let mut status_summary = None;
+    // Uploaded files not yet cleaned up because the host had locked the medium
+    let mut cleanup_pending = false;
     loop {
         println!("upload_new_files");
-        upload_new_files()?;
+        let uploaded = match upload_new_files(config, &mut upload, backend) {
+            Err(err @ Error::SnapshotOverflow { .. }) | Err(err @ Error::SnapshotInvalid(_)) => {
+                println!("Upload cycle aborted: {}", err);
+                set_leds(&[GPIO_RED])?;
+                clean_snapshot(backend)?;
+                update_status(config, &watcher, &mut status_summary, &format!("aborted, {}", err));
+                println!("wait_for_idle");
+                wait_for_idle(backend, &mut watcher)?;
+                continue;
+            },
+            result => result?,
+        };
+        update_status(config, &watcher, &mut status_summary, &format!("uploaded {} files", uploaded));
+        if (uploaded > 0 || cleanup_pending) && config.retention.is_enabled() && config.retention.while_running {
+            // The host may have started using the volume again during the upload
+            println!("wait_for_unused");
+            cleanup_pending = false;
+            if wait_for_unused(backend, &mut watcher)? == Wait::TimedOut {
+                println!("Postponing cleanup to the next cycle: the host is still reading");
+                cleanup_pending = true;
+            } else {
+                match clean_volume(config, backend) {
+                    Ok(()) => (),
+                    Err(err @ Error::MediumLocked(_)) => {
+                        println!("Postponing cleanup to the next cycle: {}", err);
+                        cleanup_pending = true;
+                    },
+                    Err(err) => println!("Failed to clean up uploaded files: {}", err),
+                }
+            }
+        }
+        set_host_leds(&watcher)?;
         println!("wait_for_active");
-        wait_for_active()?;
+        if wait_for_active(backend, &mut watcher)? == Wait::HostDisconnected {
+            println!("Host disconnected, scanning now");
+            continue;
+        }
         println!("wait_for_idle");
-        wait_for_idle()?;
+        if wait_for_idle(backend, &mut watcher)? == Wait::HostDisconnected {
+            println!("Host disconnected, scanning now");
+        }
     }
 }
 
@@ -95,7 +179,7 @@ fn sys_gpio_value(gpio: &str) -> PathBuf {
 }
 
 fn prepare_leds() -> Result<()> {
-    prepare_leds_io().map_err(|io_error| Error::LedSysfs(io_error))
+    prepare_leds_io().map_err(Error::LedSysfs)
 }
 
 fn prepare_leds_io() -> std::io::Result<()> {
@@ -113,7 +197,17 @@ fn prepare_leds_io() -> std::io::Result<()> {
 }
 
 fn set_leds(gpios: &[&str]) -> Result<()> {
-    set_leds_io(gpios).map_err(|io_error| Error::LedSysfs(io_error))
+    set_leds_io(gpios).map_err(Error::LedSysfs)
+}
+
+// Idle LEDs: green alone while a host has the stick configured, with yellow while waiting for one
+fn set_host_leds(watcher: &UdcWatcher) -> Result<()> {
+    // Without a device controller there is no host to wait for
+    if watcher.state().is_none() || watcher.is_connected() {
+        set_leds(&[GPIO_GREEN])
+    } else {
+        set_leds(&[GPIO_GREEN, GPIO_YELLOW])
+    }
 }
 
 fn set_leds_io(gpios: &[&str]) -> std::io::Result<()> {
@@ -124,145 +218,401 @@ fn set_leds_io(gpios: &[&str]) -> std::io::Result<()> {
     Ok(())
 }
 
-fn sys_block_stat(minor: u64) -> PathBuf {
-    PathBuf::from(format!("/sys/block/dm-{}/stat", minor))
+fn sys_block_stat(device: &str) -> Result<PathBuf> {
+    let device_path = fs::canonicalize(device)
+        .map_err(Error::StatWritesSysfs)?;
+    let device_name = device_path.file_name()
+        .ok_or_else(|| Error::StatWritesNotFound(device.to_string()))?;
+    Ok(Path::new("/sys/class/block").join(device_name).join("stat"))
 }
 
-fn stat_find_writes(stat_output: &str) -> Result<u64> {
-    stat_output
-        .split_whitespace()
-        .nth(6).ok_or(Error::StatWritesNotFound(stat_output.to_string()))
-        .and_then(|writes| writes.parse::<u64>().map_err(|err| Error::StatWritesParse(err)))
+// Sectors read and written so far, from /sys/block/*/stat
+#[derive(Debug, Clone, Copy, PartialEq)]
+struct Activity {
+    read: u64,
+    written: u64,
 }
 
-fn find_mass_storage_minor() -> Result<u64> {
-    let lvs_output = command_stdout(
-        Command::new("lvs")
-            .arg("-o").arg("kernel_minor")
-            .arg("--noheadings")
-            .arg("data/mass_storage_root")
-    )?;
-
-    lvs_output.trim().parse::<u64>()
-        .map_err(|err| Error::LvsMinorParse(err))
+fn stat_find_activity(stat_output: &str) -> Result<Activity> {
+    let field = |index: usize| stat_output
+        .split_whitespace()
+        .nth(index).ok_or_else(|| Error::StatWritesNotFound(stat_output.to_string()))
+        .and_then(|sectors| sectors.parse::<u64>().map_err(Error::StatWritesParse));
+    Ok(Activity { read: field(2)?, written: field(6)? })
 }
 
-fn wait_for_write_condition<F>(seconds: usize, mut f: F) -> Result<()>
-    where F: FnMut(&u64, &u64) -> bool
+// Also returns early when the host disconnects, or once limit has passed. With show_host, the LEDs
+// follow the host connection state.
+fn wait_for_write_condition<F>(backend: &dyn StorageBackend, watcher: &mut UdcWatcher, show_host: bool, seconds: usize,
+    limit: Option<Duration>, mut f: F) -> Result<Wait>
+    where F: FnMut(&Activity, &Activity) -> bool
 {
-    let minor = find_mass_storage_minor()?;
-    let mut stat_file = File::open(sys_block_stat(minor))
-        .map_err(|io_error| Error::StatWritesSysfs(io_error))?;
+    let started = Instant::now();
+    let mut shown_state = watcher.state().cloned();
+    let mut stat_file = File::open(sys_block_stat(&backend.origin_device()?)?)
+        .map_err(Error::StatWritesSysfs)?;
     let mut history = std::collections::VecDeque::new();
     let history_size = seconds + 1;
     loop {
         let mut stat_output = String::new();
         stat_file.seek(std::io::SeekFrom::Start(0))
-            .map_err(|io_error| Error::StatWritesSysfs(io_error))?;
+            .map_err(Error::StatWritesSysfs)?;
         stat_file.read_to_string(&mut stat_output)
-            .map_err(|io_error| Error::StatWritesSysfs(io_error))?;
-        let writes = stat_find_writes(&stat_output)?;
-        println!("Writes {}", writes);
-        history.push_front(writes);
+            .map_err(Error::StatWritesSysfs)?;
+        let activity = stat_find_activity(&stat_output)?;
+        println!("Read {} written {}", activity.read, activity.written);
+        history.push_front(activity);
         history.truncate(history_size);
 
         if history.len() == history_size && f(history.back().unwrap(), history.front().unwrap()) {
-            return Ok(());
+            return Ok(Wait::Reached);
+        }
+        if limit.is_some_and(|limit| started.elapsed() >= limit) {
+            return Ok(Wait::TimedOut);
+        }
+
+        if watcher.poll_disconnected()? {
+            return Ok(Wait::HostDisconnected);
+        }
+        if show_host && watcher.state() != shown_state.as_ref() {
+            println!("Host state {}", watcher.state().map_or("unknown", |state| state.name()));
+            set_host_leds(watcher)?;
+            shown_state = watcher.state().cloned();
         }
         std::thread::sleep(std::time::Duration::from_millis(1000));
     }
 }
 
-fn wait_for_idle() -> Result<()> {
-    wait_for_write_condition(6, |old_writes, new_writes| old_writes == new_writes)
+// Reads leave the snapshot unchanged, so a host playing recordings back does not hold up a scan
+fn wait_for_idle(backend: &dyn StorageBackend, watcher: &mut UdcWatcher) -> Result<Wait> {
+    wait_for_write_condition(backend, watcher, false, 6, None, |old, new| old.written == new.written)
 }
 
-fn wait_for_active() -> Result<()> {
-    wait_for_write_condition(1, |old_writes, new_writes| old_writes != new_writes)
+// Ejecting the medium would interrupt a host that is reading it, so the cleanup also waits for
+// reads to stop, but gives up after a while rather than holding up the next scan
+fn wait_for_unused(backend: &dyn StorageBackend, watcher: &mut UdcWatcher) -> Result<Wait> {
+    wait_for_write_condition(backend, watcher, false, 6, Some(UNUSED_WAIT_LIMIT), |old, new| old == new)
+}
+
+fn wait_for_active(backend: &dyn StorageBackend, watcher: &mut UdcWatcher) -> Result<Wait> {
+    wait_for_write_condition(backend, watcher, true, 1, None, |old, new| old.written != new.written)
 }
 
 fn is_wav(file_path: &Path) -> bool {
-    return match file_path.extension() {
+    match file_path.extension() {
         None => false,
         Some(extension) => extension == "wav"
+    }
+}
+
+// Swaps a fresh status image into the status LUN when its summary has changed since the last
+// swap. Failures only cost the host an up to date report.
+fn update_status(config: &Config, watcher: &UdcWatcher, last_summary: &mut Option<String>, last_scan: &str) {
+    if !config.gadget.status_lun {
+        return;
+    }
+    let status = Status {
+        time: Local::now(),
+        host_state: watcher.state().map_or("unknown", |state| state.name()).to_string(),
+        last_scan: last_scan.to_string(),
     };
+    let result = status::summary(&status).and_then(|summary| {
+        if last_summary.as_ref() == Some(&summary) {
+            return Ok(());
+        }
+        Lun::find(config.gadget.mode, &gadget::configfs_root(), &udc::udc_root(), gadget::STATUS_LUN)
+            .and_then(|lun| gadget::with_medium_ejected(&lun, || status::write_image(&status::image_path(), &status)))?;
+        *last_summary = Some(summary);
+        Ok(())
+    });
+    if let Err(err) = result {
+        println!("Failed to update status image: {}", err);
+    }
 }
 
-fn upload_new_files() -> Result<()> {
-    command_stdout(
-        Command::new("lvcreate")
-            .arg("--snapshot")
-            .arg("--extents").arg("100%FREE")
-            .arg("--name").arg("mass_storage_snap")
-            .arg("data/mass_storage_root")
-    )?;
+// Settings applied from the stick earlier stay in effect across restarts
+fn initial_upload_config(config: &Config) -> UploadConfig {
+    if !config.upload.stick_config {
+        return config.upload.clone();
+    }
+    match stick_config::load_overrides().and_then(|overrides| overrides.apply_to(&config.upload)) {
+        Ok(upload) => upload,
+        Err(err) => {
+            println!("Ignoring settings applied from the stick: {}", err);
+            config.upload.clone()
+        },
+    }
+}
+
+// Applies each new version of a configuration file found in the root of the volume
+fn apply_stick_config(config: &Config, upload: &mut UploadConfig, partition: u32, volume: &dyn Volume) -> Result<()> {
+    if !config.upload.stick_config {
+        return Ok(());
+    }
+    for entry in stick_config::find(volume)? {
+        let contents = match stick_config::read(volume, &entry) {
+            Ok(contents) => contents,
+            Err(err) => {
+                println!("Skipping {:?} on partition {}: {}", entry.path, partition, err);
+                continue;
+            },
+        };
+        if !stick_config::is_new(partition, &entry.file_name, &contents)? {
+            continue;
+        }
+        let outcome = match stick_config::apply(&config.upload, &entry.file_name, &contents) {
+            Ok(applied) => {
+                *upload = applied;
+                Outcome::Applied
+            },
+            Err(err) => Outcome::from_error(err),
+        };
+        println!("Configuration {:?} on partition {} {}", entry.path, partition, outcome);
+        stick_config::record(partition, &entry.file_name, &contents, &outcome, Local::now())?;
+    }
+    Ok(())
+}
+
+// Deletes uploaded files from the origin while the host cannot see it
+fn clean_volume(config: &Config, backend: &dyn StorageBackend) -> Result<()> {
+    let origin = backend.origin_device()?;
+    if config.retention.dry_run {
+        retention::clean_volume(&origin, &config.retention)?;
+        return Ok(());
+    }
+    let lun = Lun::find(config.gadget.mode, &gadget::configfs_root(), &udc::udc_root(), gadget::MEDIUM_LUN)?;
+    gadget::with_medium_ejected(&lun, || retention::clean_volume(&origin, &config.retention))?;
+    Ok(())
+}
+
+// Returns the number of files uploaded
+fn upload_new_files(config: &Config, upload: &mut UploadConfig, backend: &dyn StorageBackend) -> Result<usize> {
+    backend.snapshot()?;
+
+    let partitions = backend.map_snapshot(SNAP_PARTITION_PREFIX)?;
+    let mut mounted = Vec::new();
+    let uploaded = match upload_partitions(config, upload, backend, &partitions, &mut mounted) {
+        Ok(uploaded) => uploaded,
+        Err(err) => {
+            // Partitions mounted before the failure would otherwise hold the snapshot open
+            for number in &mounted {
+                let _ = command_ignore_output(Command::new("umount").arg(partition_mount_path(*number)));
+            }
+            return Err(err);
+        },
+    };
 
-    map_lv_partition("mass_storage_snap", "mass_storage_snap_partition", MapMode::ReadOnly)?;
+    set_leds(&[GPIO_GREEN])?;
+
+    for partition in &partitions {
+        if mounted.contains(&partition.number) {
+            command_stdout(
+                Command::new("umount").arg(partition_mount_path(partition.number))
+            )?;
+        }
+
+        unmap_partition(&partition.mapped_name, CommandCheck::ExpectZeroExitCode)?;
+    }
+
+    backend.release(&CommandCheck::ExpectZeroExitCode)?;
+    Ok(uploaded)
+}
+
+// Records each partition it mounts in mounted, even if a later one fails
+fn upload_partitions(config: &Config, upload: &mut UploadConfig, backend: &dyn StorageBackend,
+    partitions: &[MappedPartition], mounted: &mut Vec<u32>) -> Result<usize>
+{
+    let mut uploaded = 0;
+    for partition in partitions {
+        let file_system = match file_system::detect(&partition.device_path())? {
+            Some(file_system) => file_system,
+            None => {
+                println!("Skipping partition {}: no supported file system", partition.number);
+                continue;
+            },
+        };
+
+        let check = match file_system {
+            FileSystemType::Fat => match fat_check::check_partition(&partition.device_path()) {
+                Ok(check) => report_check(partition.number, check)?,
+                // Only this partition is left unread, and it is checked again next cycle
+                Err(err) => {
+                    println!("Skipping partition {} this cycle, it could not be checked: {}", partition.number, err);
+                    set_leds(&[GPIO_RED])?;
+                    fat_check::record_unchecked(partition.number, &err)?;
+                    continue;
+                },
+            },
+            _ => None,
+        };
+
+        // Only FAT can be read without the kernel
+        let volume: Box<dyn Volume> = if file_system == FileSystemType::Fat && config.scan.reader == ScanReader::Fat {
+            Box::new(FatVolume::open(&partition.device_path())?)
+        } else {
+            mount_partition(partition, file_system)?;
+            mounted.push(partition.number);
+            Box::new(MountedVolume::new(&partition_mount_path(partition.number)))
+        };
+        apply_stick_config(config, upload, partition.number, &*volume)?;
+        let partition_upload = partition_upload_config(upload, partition.number, partitions.len())?;
+        uploaded += upload_volume_files(config, backend, &partition_upload, partition.number, &*volume, check.as_ref())?;
+    }
+    Ok(uploaded)
+}
+
+// Same-named files on different partitions must not overwrite each other, so with several
+// partitions each uploads into its own folder
+fn partition_upload_config(upload: &UploadConfig, partition: u32, partition_count: usize) -> Result<UploadConfig> {
+    let mut partition_upload = upload.clone();
+    if partition_count > 1 {
+        partition_upload.remote = upload.folder_remote(&format!("p{}", partition))?;
+    }
+    Ok(partition_upload)
+}
+
+fn mount_partition(partition: &MappedPartition, file_system: FileSystemType) -> Result<()> {
+    let mount_path = partition_mount_path(partition.number);
+    fs::create_dir_all(&mount_path)
+        .map_err(Error::IteratingDirectory)?;
 
     command_stdout(
         Command::new("mount")
-            .arg("/dev/mapper/mass_storage_snap_partition")
-            .arg("/mnt")
-            .arg("-o").arg("ro")
+            .arg("-t").arg(file_system.mount_type())
+            .arg("-o").arg(file_system.mount_options())
+            .arg(partition.device_path())
+            .arg(&mount_path)
     )?;
 
-    for dir_entry in std::fs::read_dir(Path::new("/mnt"))
-            .map_err(|io_error| Error::IteratingDirectory(io_error))? {
-        let dir_entry = dir_entry
-            .map_err(|io_error| Error::IteratingDirectory(io_error))?;
+    Ok(())
+}
+
+// The host may have been mid-write when it went idle, so problems are recorded rather than fatal
+fn report_check(partition: u32, check: Option<FatCheck>) -> Result<Option<FatCheck>> {
+    let check = match check {
+        Some(check) => check,
+        None => {
+            println!("Partition {} is not FAT, skipping consistency check", partition);
+            return Ok(None);
+        },
+    };
 
-        if is_wav(&dir_entry.path()) {
-            let upload_entry = upload_db::from_dir_entry(&dir_entry).unwrap();
+    if check.dirty {
+        println!("Partition {} has the dirty flag set", partition);
+    }
+    for damaged_file in &check.damaged_files {
+        println!("Partition {} has inconsistent cluster chain: {}", partition, damaged_file);
+    }
+    if !check.damaged_files.is_empty() {
+        set_leds(&[GPIO_RED])?;
+    }
+    check.record(partition)?;
 
-            if !upload_db::is_uploaded(&upload_entry).unwrap() {
-                println!("new file: {:?}", dir_entry.path());
-                let tmp_path = Path::new("/tmp/upload-stick");
+    Ok(Some(check))
+}
 
-                if tmp_path.exists() {
-                    fs::remove_dir_all(tmp_path).unwrap();
-                }
-                fs::create_dir(tmp_path).unwrap();
-
-                let mut output_path = tmp_path.join(dir_entry.path().file_stem().expect("No file name")).with_extension("ogg");
-                println!("encode {:?} to {:?}", dir_entry.path(), output_path);
-                set_leds(&[GPIO_YELLOW])?;
-                command_stdout(
-                    Command::new("oggenc")
-                        .arg("--quality").arg("6")
-                        .arg("--downmix")
-                        .arg("--output").arg(&output_path)
-                        .arg(dir_entry.path())
-                )?;
+fn upload_volume_files(config: &Config, backend: &dyn StorageBackend, upload: &UploadConfig, partition: u32, volume: &dyn Volume, check: Option<&FatCheck>) -> Result<usize> {
+    let mut uploaded = 0;
+    for file in upload_rules::walk(volume, upload, &config.scan.exclude)? {
+        let entry = file.entry;
+        // May hold credentials, so never uploaded whatever else is
+        if stick_config::is_config_file(&entry.file_name) {
+            continue;
+        }
+        if is_wav(Path::new(&entry.file_name)) {
+            if check.is_some_and(|check| check.is_damaged(&entry)) {
+                println!("Skipping damaged file on partition {}: {:?}", partition, entry.path);
+                continue;
+            }
 
-                println!("upload {:?}", output_path);
-                set_leds(&[GPIO_BLUE])?;
-                command_stdout(
-                    Command::new("rclone")
-                        .arg("copy")
-                        .arg(&output_path)
-                        .arg("upload:/Auto_Upload/")
-                )?;
+            let upload_entry = upload_db::new_entry(partition, &entry.os_path, entry.len);
 
-                upload_db::set_uploaded(&upload_entry).unwrap();
+            if !upload_db::is_uploaded(&upload_entry).map_err(Error::UploadDb)? {
+                backend.check_snapshot()?;
+                if upload_rules::is_apple_double(volume, &entry)? {
+                    println!("Skipping macOS resource fork on partition {}: {:?}", partition, entry.path);
+                    upload_db::set_skipped(&upload_entry).map_err(Error::UploadDb)?;
+                    continue;
+                }
+                let output_name = match output_file_name(&entry.os_path) {
+                    Some(output_name) => output_name,
+                    None => {
+                        println!("Skipping file without a usable name on partition {}: {:?}", partition, entry.os_path);
+                        upload_db::set_skipped(&upload_entry).map_err(Error::UploadDb)?;
+                        continue;
+                    },
+                };
+                println!("new file on partition {}: {:?}", partition, entry.os_path);
+                let remote_path = upload_file(backend, &file.upload, volume, &entry, &output_name)?;
+                upload_db::set_verified(&upload_entry).map_err(Error::UploadDb)?;
+                upload_db::record_upload(&upload_entry, &remote_path, Local::now()).map_err(Error::UploadDb)?;
+                uploaded += 1;
             }
         }
     }
 
-    set_leds(&[GPIO_GREEN])?;
+    Ok(uploaded)
+}
+
+// Returns the remote path of the upload
+// Names that are not UTF-8 are escaped reversibly, so that each file keeps its own remote name
+fn output_file_name(path: &Path) -> Option<String> {
+    path.file_stem().map(|stem| format!("{}.ogg", file_name::escape(stem)))
+}
+
+fn upload_file(backend: &dyn StorageBackend, upload: &UploadConfig, volume: &dyn Volume, entry: &VolumeEntry, output_name: &str) -> Result<String> {
+    let tmp_path = Path::new("/tmp/upload-stick");
 
+    if tmp_path.exists() {
+        fs::remove_dir_all(tmp_path).unwrap();
+    }
+    fs::create_dir(tmp_path).unwrap();
+
+    let output_path = tmp_path.join(output_name);
+    println!("encode {:?} to {:?}", entry.os_path, output_path);
+    set_leds(&[GPIO_YELLOW])?;
+    let mut oggenc = Command::new("oggenc");
+    oggenc
+        .arg("--quality").arg(upload.quality.to_string())
+        .arg("--output").arg(&output_path);
+    if upload.downmix {
+        oggenc.arg("--downmix");
+    }
+    // oggenc only detects the input format and copies tags from a file it can open itself
+    let encoded = match volume.local_path(entry) {
+        Some(input_path) => {
+            command_stdout_monitored(oggenc.arg(input_path), SNAPSHOT_POLL_INTERVAL, || backend.check_snapshot())
+        },
+        None => {
+            let mut input = volume.open(entry)?;
+            command_stdout_with_input(oggenc.arg("-"), &mut input, SNAPSHOT_POLL_INTERVAL, || backend.check_snapshot())
+        },
+    };
+    // The encoded data is only trustworthy if the snapshot survived the whole read. An overflow
+    // also makes reads fail, so it is reported in place of the encoder error it causes.
+    backend.check_snapshot()?;
+    encoded?;
+
+    println!("upload {:?}", output_path);
+    set_leds(&[GPIO_BLUE])?;
     command_stdout(
-        Command::new("umount").arg("/mnt")
+        Command::new("rclone")
+            .arg("copy")
+            .arg(&output_path)
+            .arg(&upload.remote)
     )?;
 
-    unmap_partition("mass_storage_snap_partition", CommandCheck::ExpectZeroExitCode)?;
-
+    // Only verified uploads may later be deleted from the stick
+    println!("verify {:?}", output_path);
     command_stdout(
-        Command::new("lvremove")
-            .arg("--yes")
-            .arg("data/mass_storage_snap")
+        Command::new("rclone")
+            .arg("check")
+            .arg("--one-way")
+            .arg(tmp_path)
+            .arg(&upload.remote)
     )?;
 
-    Ok(())
+    Ok(upload.remote_path(output_name))
 }
 
 #[cfg(test)]
@@ -270,8 +620,28 @@ mod tests {
     use super::*;
 
     #[test]
-    fn test_stat_find_writes() {
-        let writes = stat_find_writes("     158        0    20232      800     2567        0    20536  1279180        0     1650  1279980").unwrap();
-        assert_eq!(writes, Ok(20536));
+    fn test_stat_find_activity() {
+        let activity = stat_find_activity("     158        0    20232      800     2567        0    20536  1279180        0     1650  1279980").unwrap();
+        assert_eq!(activity, Activity { read: 20232, written: 20536 });
+        assert!(stat_find_activity("     158        0").is_err());
+    }
+
+    #[test]
+    fn test_output_file_name() {
+        use std::ffi::OsStr;
+        use std::os::unix::ffi::OsStrExt;
+
+        assert_eq!(output_file_name(Path::new("Session/TAKE01.wav")), Some("TAKE01.ogg".to_string()));
+        assert_eq!(output_file_name(Path::new("Take 1.2.wav")), Some("Take 1.2.ogg".to_string()));
+        assert_eq!(output_file_name(Path::new(OsStr::from_bytes(b"Caf\xe9.wav"))), Some("Caf%E9.ogg".to_string()));
+        assert_eq!(output_file_name(Path::new("Session/..")), None);
+    }
+
+    #[test]
+    fn test_partition_upload_config() {
+        let upload = UploadConfig::default();
+        assert_eq!(partition_upload_config(&upload, 1, 1).unwrap().remote, "upload:/Auto_Upload/");
+        assert_eq!(partition_upload_config(&upload, 1, 2).unwrap().remote, "upload:/Auto_Upload/p1");
+        assert_eq!(partition_upload_config(&upload, 2, 2).unwrap().remote, "upload:/Auto_Upload/p2");
     }
 }
@@ -1,6 +1,14 @@
+extern crate chrono;
 extern crate upload_stick;
 
-use std::process::{self, Command};
+use std::process;
+use chrono::Local;
+use upload_stick::config::{Config, GadgetMode};
+use upload_stick::gadget::{self, Gadget};
+use upload_stick::storage;
+use upload_stick::udc;
+use upload_stick::retention;
+use upload_stick::status::{self, Status};
 use upload_stick::upload_command::{Result, command_stdout};
 
 fn main() {
@@ -19,16 +27,50 @@ fn main() {
 }
 
 fn start() -> Result<()> {
-    // TODO: Clean old files to free up space
-
-    println!("Enabling mass storage module");
-    command_stdout(
-        Command::new("modprobe")
-            .arg("g_mass_storage")
-            .arg("file=/dev/data/mass_storage_root")
-            .arg("stall=0")
-            .arg("removable=yes")
-    )?;
+    let config = Config::load()?;
+    let origin = storage::from_config(&config).activate()?;
+
+    if config.retention.is_enabled() {
+        // A failed cleanup must not keep the stick from starting
+        if let Err(err) = retention::clean_volume(&origin, &config.retention) {
+            println!("Failed to clean up uploaded files: {}", err);
+        }
+    }
+
+    let status_image = if config.gadget.status_lun {
+        // The LUN needs its image before the host can see it
+        let status_image = status::image_path();
+        status::write_image(&status_image, &Status {
+            time: Local::now(),
+            host_state: "not connected yet".to_string(),
+            last_scan: "none since startup".to_string(),
+        })?;
+        Some(status_image.display().to_string())
+    } else {
+        None
+    };
+
+    let serial = gadget::serial_number(&config.gadget.identity);
+    match config.gadget.mode {
+        GadgetMode::Modprobe => {
+            println!("Enabling mass storage module");
+            command_stdout(&mut gadget::modprobe_command(&origin, &config.gadget.lun,
+                &config.gadget.identity, serial.as_deref(), status_image.as_deref()))?;
+        },
+        GadgetMode::Configfs => {
+            println!("Configuring USB gadget");
+            gadget::load_libcomposite()?;
+            let mut usb_gadget = Gadget::new(&gadget::configfs_root(), &udc::udc_root(), &origin, &config.gadget.lun)
+                .identity(&config.gadget.identity, serial);
+            for function in &config.gadget.functions {
+                usb_gadget = usb_gadget.function(*function);
+            }
+            if let Some(ref status_image) = status_image {
+                usb_gadget = usb_gadget.status_image(status_image);
+            }
+            usb_gadget.start()?;
+        },
+    }
 
     Ok(())
 }
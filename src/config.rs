@@ -0,0 +1,601 @@
+use std::fs;
+use std::io;
+use std::path::{Path, PathBuf};
+use size::Size;
+use ignore::gitignore::{Gitignore, GitignoreBuilder};
+use toml;
+use upload_command::{Error, Result};
+
+#[derive(Debug, Clone, Copy, PartialEq, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum StorageBackendKind {
+    Lvm,
+    Image,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum StorageLayout {
+    Thick,
+    Thin,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Deserialize)]
+#[serde(rename_all = "kebab-case")]
+pub enum ImageSnapshot {
+    Reflink,
+    DmSnapshot,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum ScanReader {
+    Mount,
+    Fat,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum GadgetMode {
+    Modprobe,
+    Configfs,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum GadgetFunction {
+    // CDC-ACM serial console
+    Acm,
+    // CDC-ECM network interface
+    Ecm,
+}
+
+#[derive(Debug, Clone, PartialEq, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct ImageConfig {
+    pub path: PathBuf,
+    pub size: String,
+    pub snapshot: ImageSnapshot,
+    pub cow_size: String,
+}
+
+#[derive(Debug, Clone, PartialEq, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct LvmConfig {
+    // Boot device holding the root partition; the data partition is added after it
+    pub device: String,
+    pub root_partition: u32,
+    pub root_end: String,
+    pub vg_name: String,
+    pub lv_name: String,
+    pub snapshot_name: String,
+    pub pool_name: String,
+    // Share of the VG given to the volume with the thick layout
+    pub thick_percent: u64,
+    // Share of the thin pool given to the volume with the thin layout
+    pub thin_percent: u64,
+}
+
+// The host never discards deleted blocks, so a fully written thin volume must
+// still leave room for snapshot changes below the 90% pool abort threshold
+pub const THIN_PERCENT_MAX: u64 = 75;
+
+#[derive(Debug, Clone, PartialEq, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct StorageConfig {
+    pub backend: StorageBackendKind,
+    pub layout: StorageLayout,
+    pub lvm: LvmConfig,
+    pub image: ImageConfig,
+}
+
+#[derive(Debug, Clone, PartialEq, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct ScanConfig {
+    pub reader: ScanReader,
+    // Operating system metadata never uploaded, in gitignore syntax and matched regardless of case
+    pub exclude: Vec<String>,
+}
+
+#[derive(Debug, Clone, PartialEq, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct LunConfig {
+    pub removable: bool,
+    pub ro: bool,
+    pub cdrom: bool,
+    // Ignore the host's force unit access requests
+    pub nofua: bool,
+    pub stall: bool,
+}
+
+#[derive(Debug, Clone, PartialEq, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct IdentityConfig {
+    pub vendor_id: u16,
+    pub product_id: u16,
+    pub manufacturer: String,
+    pub product: String,
+    // Defaults to the CPU serial number
+    pub serial: Option<String>,
+    // SCSI INQUIRY vendor, product and revision, only with configfs
+    pub inquiry: Option<String>,
+}
+
+#[derive(Debug, Clone, PartialEq, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct GadgetConfig {
+    pub mode: GadgetMode,
+    // Functions added alongside mass storage, only with configfs
+    pub functions: Vec<GadgetFunction>,
+    pub lun: LunConfig,
+    pub identity: IdentityConfig,
+    // Second, read-only LUN showing what was uploaded
+    pub status_lun: bool,
+}
+
+// Cleanup of uploaded files, disabled unless a limit is set
+#[derive(Debug, Clone, PartialEq, Default, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct RetentionConfig {
+    pub keep_days: Option<u64>,
+    pub max_percent: Option<u64>,
+    // Only report what would be deleted
+    pub dry_run: bool,
+    // Also clean up after upload cycles, ejecting the medium from the host meanwhile
+    pub while_running: bool,
+}
+
+#[derive(Debug, Clone, PartialEq, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct UploadConfig {
+    // rclone destination directory
+    pub remote: String,
+    // oggenc quality, from -1 to 10
+    pub quality: f32,
+    pub downmix: bool,
+    // Apply upload-stick.toml or wifi.txt found on the stick
+    pub stick_config: bool,
+}
+
+#[derive(Debug, Clone, PartialEq, Default, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct Config {
+    pub storage: StorageConfig,
+    pub scan: ScanConfig,
+    pub gadget: GadgetConfig,
+    pub retention: RetentionConfig,
+    pub upload: UploadConfig,
+}
+
+impl Default for StorageConfig {
+    fn default() -> StorageConfig {
+        StorageConfig {
+            backend: StorageBackendKind::Lvm,
+            layout: StorageLayout::Thick,
+            lvm: LvmConfig::default(),
+            image: ImageConfig::default(),
+        }
+    }
+}
+
+impl Default for LvmConfig {
+    fn default() -> LvmConfig {
+        LvmConfig {
+            device: "/dev/mmcblk0".to_string(),
+            root_partition: 2,
+            root_end: "2GiB".to_string(),
+            vg_name: "data".to_string(),
+            lv_name: "mass_storage_root".to_string(),
+            snapshot_name: "mass_storage_snap".to_string(),
+            pool_name: "mass_storage_pool".to_string(),
+            thick_percent: 70,
+            thin_percent: 70,
+        }
+    }
+}
+
+impl LvmConfig {
+    fn validate(&self) -> Result<()> {
+        if self.root_partition == 0 {
+            return Err(Error::ConfigInvalid("storage.lvm.root_partition must be at least 1".to_string()));
+        }
+        Size::parse(&self.root_end)?.to_bytes(512)?;
+        for name in &[&self.vg_name, &self.lv_name, &self.snapshot_name, &self.pool_name] {
+            if !is_lvm_name(name) {
+                return Err(Error::ConfigInvalid(format!("invalid LVM name: {}", name)));
+            }
+        }
+        for percent in &[self.thick_percent, self.thin_percent] {
+            if *percent == 0 || *percent >= 100 {
+                return Err(Error::ConfigInvalid(format!("volume percentage must be between 1 and 99: {}", percent)));
+            }
+        }
+        if self.thin_percent > THIN_PERCENT_MAX {
+            return Err(Error::ConfigInvalid(format!(
+                "storage.lvm.thin_percent must be at most {}: {}", THIN_PERCENT_MAX, self.thin_percent)));
+        }
+        Ok(())
+    }
+}
+
+fn is_lvm_name(name: &str) -> bool {
+    !name.is_empty() && !name.starts_with('-') &&
+        name.chars().all(|c| c.is_ascii_alphanumeric() || "+_.-".contains(c))
+}
+
+impl Default for ImageConfig {
+    fn default() -> ImageConfig {
+        ImageConfig {
+            path: PathBuf::from("/var/lib/upload-stick/mass_storage.img"),
+            size: "8GiB".to_string(),
+            snapshot: ImageSnapshot::DmSnapshot,
+            cow_size: "2GiB".to_string(),
+        }
+    }
+}
+
+// What macOS, Windows and Linux desktops leave on removable drives
+const DEFAULT_EXCLUDE: [&str; 12] = [
+    "._*",
+    ".DS_Store",
+    ".Spotlight-V100/",
+    ".Trashes/",
+    ".fseventsd/",
+    ".TemporaryItems/",
+    ".DocumentRevisions-V100/",
+    "System Volume Information/",
+    "$RECYCLE.BIN/",
+    "Thumbs.db",
+    "desktop.ini",
+    ".Trash-*/",
+];
+
+impl ScanConfig {
+    fn validate(&self) -> Result<()> {
+        exclude_matcher(&self.exclude).map(|_| ())
+    }
+}
+
+// Matches the operating system metadata that is never uploaded, at any depth. A .uploadignore can
+// still include such files again.
+pub fn exclude_matcher(patterns: &[String]) -> Result<Gitignore> {
+    let mut builder = GitignoreBuilder::new("");
+    builder.case_insensitive(true).map_err(|err| Error::ConfigInvalid(err.to_string()))?;
+    for pattern in patterns {
+        builder.add_line(None, pattern).map_err(|err| Error::ConfigInvalid(format!("scan.exclude: {}", err)))?;
+    }
+    builder.build().map_err(|err| Error::ConfigInvalid(err.to_string()))
+}
+
+impl Default for ScanConfig {
+    fn default() -> ScanConfig {
+        ScanConfig {
+            reader: ScanReader::Mount,
+            exclude: DEFAULT_EXCLUDE.iter().map(|pattern| pattern.to_string()).collect(),
+        }
+    }
+}
+
+impl Default for LunConfig {
+    fn default() -> LunConfig {
+        LunConfig {
+            removable: true,
+            ro: false,
+            cdrom: false,
+            nofua: false,
+            stall: false,
+        }
+    }
+}
+
+impl Default for GadgetConfig {
+    fn default() -> GadgetConfig {
+        GadgetConfig {
+            mode: GadgetMode::Modprobe,
+            functions: Vec::new(),
+            lun: LunConfig::default(),
+            identity: IdentityConfig::default(),
+            status_lun: false,
+        }
+    }
+}
+
+impl Default for IdentityConfig {
+    // The identity of g_mass_storage
+    fn default() -> IdentityConfig {
+        IdentityConfig {
+            vendor_id: 0x0525,
+            product_id: 0xa4a5,
+            manufacturer: "Linux".to_string(),
+            product: "File-Stor Gadget".to_string(),
+            serial: None,
+            inquiry: None,
+        }
+    }
+}
+
+impl GadgetConfig {
+    fn validate(&self) -> Result<()> {
+        if self.mode == GadgetMode::Modprobe && !self.functions.is_empty() {
+            return Err(Error::ConfigInvalid("gadget.functions needs gadget.mode = \"configfs\"".to_string()));
+        }
+        if let Some(ref inquiry) = self.identity.inquiry {
+            // g_mass_storage has no parameter for the inquiry string
+            if self.mode == GadgetMode::Modprobe {
+                return Err(Error::ConfigInvalid("gadget.identity.inquiry needs gadget.mode = \"configfs\"".to_string()));
+            }
+            if inquiry.len() > 28 || !inquiry.chars().all(|c| c.is_ascii() && !c.is_ascii_control()) {
+                return Err(Error::ConfigInvalid(format!("inquiry string must have at most 28 printable ASCII characters: {}", inquiry)));
+            }
+        }
+        Ok(())
+    }
+}
+
+impl RetentionConfig {
+    pub fn is_enabled(&self) -> bool {
+        self.keep_days.is_some() || self.max_percent.is_some()
+    }
+
+    fn validate(&self) -> Result<()> {
+        if let Some(percent) = self.max_percent {
+            if percent == 0 || percent >= 100 {
+                return Err(Error::ConfigInvalid(format!("retention.max_percent must be between 1 and 99: {}", percent)));
+            }
+        }
+        Ok(())
+    }
+}
+
+impl Default for UploadConfig {
+    fn default() -> UploadConfig {
+        UploadConfig {
+            remote: "upload:/Auto_Upload/".to_string(),
+            quality: 6.0,
+            downmix: true,
+            stick_config: true,
+        }
+    }
+}
+
+impl UploadConfig {
+    pub fn validate(&self) -> Result<()> {
+        // A remote starting with '-' would be taken as an rclone option
+        if !self.remote.contains(':') || self.remote.starts_with('-') || self.remote.chars().any(|c| c.is_control()) {
+            return Err(Error::ConfigInvalid(format!("upload.remote must be an rclone remote such as \"upload:/Auto_Upload/\": {}", self.remote)));
+        }
+        if !(-1.0..=10.0).contains(&self.quality) {
+            return Err(Error::ConfigInvalid(format!("upload.quality must be between -1 and 10: {}", self.quality)));
+        }
+        Ok(())
+    }
+
+    // Remote directory of a folder below this one. Settings left on the stick may only choose
+    // such a folder, never another remote or a path outside this one.
+    pub fn folder_remote(&self, folder: &str) -> Result<String> {
+        let folder = folder.trim_end_matches('/');
+        let valid = !folder.starts_with('/') && !folder.contains(':') && !folder.chars().any(|c| c.is_control()) &&
+            folder.split('/').all(|component| !component.is_empty() && component != "." && component != "..");
+        if !valid {
+            return Err(Error::ConfigInvalid(format!("remote_folder must be a relative path without ., .. or ':': {}", folder)));
+        }
+        Ok(self.remote_path(folder))
+    }
+
+    // Path of a file uploaded into the remote directory
+    pub fn remote_path(&self, file_name: &str) -> String {
+        if self.remote.ends_with(':') || self.remote.ends_with('/') {
+            format!("{}{}", self.remote, file_name)
+        } else {
+            format!("{}/{}", self.remote, file_name)
+        }
+    }
+}
+
+pub fn config_path() -> PathBuf {
+    PathBuf::from("/etc/upload-stick.toml")
+}
+
+impl Config {
+    pub fn parse(config_toml: &str) -> Result<Config> {
+        let config: Config = toml::from_str(config_toml).map_err(Error::ConfigParse)?;
+        config.storage.lvm.validate()?;
+        config.scan.validate()?;
+        config.gadget.validate()?;
+        config.retention.validate()?;
+        config.upload.validate()?;
+        Ok(config)
+    }
+
+    pub fn load_from(path: &Path) -> Result<Config> {
+        match fs::read_to_string(path) {
+            Ok(config_toml) => Config::parse(&config_toml),
+            Err(ref err) if err.kind() == io::ErrorKind::NotFound => Ok(Config::default()),
+            Err(err) => Err(Error::ConfigRead(err)),
+        }
+    }
+
+    pub fn load() -> Result<Config> {
+        Config::load_from(&config_path())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_parse_empty() {
+        assert_eq!(Config::parse("").unwrap(), Config::default());
+    }
+
+    #[test]
+    fn test_parse_thin() {
+        let config = Config::parse("
+            [storage]
+            layout = \"thin\"
+        ").unwrap();
+        assert_eq!(config.storage.layout, StorageLayout::Thin);
+    }
+
+    #[test]
+    fn test_parse_image() {
+        let config = Config::parse("
+            [storage]
+            backend = \"image\"
+
+            [storage.image]
+            path = \"/srv/stick.img\"
+            snapshot = \"reflink\"
+        ").unwrap();
+        assert_eq!(config.storage.backend, StorageBackendKind::Image);
+        assert_eq!(config.storage.image.path, PathBuf::from("/srv/stick.img"));
+        assert_eq!(config.storage.image.snapshot, ImageSnapshot::Reflink);
+        assert_eq!(config.storage.image.size, "8GiB");
+    }
+
+    #[test]
+    fn test_parse_scan_reader() {
+        let config = Config::parse("
+            [scan]
+            reader = \"fat\"
+        ").unwrap();
+        assert_eq!(config.scan.reader, ScanReader::Fat);
+        assert_eq!(Config::default().scan.reader, ScanReader::Mount);
+    }
+
+    #[test]
+    fn test_parse_scan_exclude() {
+        assert!(Config::default().scan.exclude.contains(&"._*".to_string()));
+        let config = Config::parse("
+            [scan]
+            exclude = [\".DS_Store\", \"*.pkf\"]
+        ").unwrap();
+        assert_eq!(config.scan.exclude, vec![".DS_Store", "*.pkf"]);
+        assert!(Config::parse("[scan]\nexclude = [\"{a,b\"]\n").is_err());
+    }
+
+    #[test]
+    fn test_parse_lvm() {
+        let config = Config::parse("
+            [storage.lvm]
+            device = \"/dev/sda\"
+            root_partition = 3
+            root_end = \"8GiB\"
+            vg_name = \"recordings\"
+            thick_percent = 60
+        ").unwrap();
+        assert_eq!(config.storage.lvm.device, "/dev/sda");
+        assert_eq!(config.storage.lvm.root_partition, 3);
+        assert_eq!(config.storage.lvm.root_end, "8GiB");
+        assert_eq!(config.storage.lvm.vg_name, "recordings");
+        assert_eq!(config.storage.lvm.lv_name, "mass_storage_root");
+        assert_eq!(config.storage.lvm.thick_percent, 60);
+    }
+
+    #[test]
+    fn test_parse_lvm_invalid() {
+        assert!(Config::parse("[storage.lvm]\nroot_partition = 0\n").is_err());
+        assert!(Config::parse("[storage.lvm]\nroot_end = \"50%\"\n").is_err());
+        assert!(Config::parse("[storage.lvm]\nvg_name = \"my data\"\n").is_err());
+        assert!(Config::parse("[storage.lvm]\nthin_percent = 100\n").is_err());
+        assert!(Config::parse("[storage.lvm]\nthin_percent = 90\n").is_err());
+        assert!(Config::parse("[storage.lvm]\nthin_percent = 75\n").is_ok());
+    }
+
+    #[test]
+    fn test_parse_gadget() {
+        let config = Config::parse("
+            [gadget]
+            mode = \"configfs\"
+            functions = [\"acm\", \"ecm\"]
+            status_lun = true
+
+            [gadget.lun]
+            ro = true
+        ").unwrap();
+        assert_eq!(config.gadget.mode, GadgetMode::Configfs);
+        assert_eq!(config.gadget.functions, vec![GadgetFunction::Acm, GadgetFunction::Ecm]);
+        assert!(config.gadget.lun.ro);
+        assert!(config.gadget.lun.removable);
+        assert!(config.gadget.status_lun);
+        assert!(!Config::default().gadget.status_lun);
+        assert_eq!(Config::default().gadget.mode, GadgetMode::Modprobe);
+        assert!(Config::parse("[gadget]\nfunctions = [\"acm\"]\n").is_err());
+        assert!(Config::parse("[gadget]\nmode = \"configfs\"\nfunctions = [\"hid\"]\n").is_err());
+    }
+
+    #[test]
+    fn test_parse_gadget_identity() {
+        let config = Config::parse("
+            [gadget]
+            mode = \"configfs\"
+
+            [gadget.identity]
+            vendor_id = 0x1209
+            product_id = 0x0001
+            product = \"Rehearsal room stick\"
+            inquiry = \"Band    Upload Stick    1.0\"
+        ").unwrap();
+        let identity = &config.gadget.identity;
+        assert_eq!(identity.vendor_id, 0x1209);
+        assert_eq!(identity.product_id, 0x0001);
+        assert_eq!(identity.manufacturer, "Linux");
+        assert_eq!(identity.product, "Rehearsal room stick");
+        assert_eq!(identity.serial, None);
+        assert_eq!(identity.inquiry, Some("Band    Upload Stick    1.0".to_string()));
+
+        assert!(Config::parse("[gadget.identity]\ninquiry = \"Band\"\n").is_err());
+        assert!(Config::parse("[gadget]\nmode = \"configfs\"\n[gadget.identity]\ninquiry = \"Band    Upload Stick    1.0 extra\"\n").is_err());
+        assert!(Config::parse("[gadget.identity]\nvendor_id = 0x10000\n").is_err());
+    }
+
+    #[test]
+    fn test_parse_retention() {
+        let config = Config::parse("
+            [retention]
+            keep_days = 30
+            max_percent = 80
+            dry_run = true
+            while_running = true
+        ").unwrap();
+        assert_eq!(config.retention.keep_days, Some(30));
+        assert_eq!(config.retention.max_percent, Some(80));
+        assert!(config.retention.dry_run);
+        assert!(config.retention.while_running);
+        assert!(config.retention.is_enabled());
+        assert!(!Config::default().retention.is_enabled());
+        assert!(Config::parse("[retention]\nmax_percent = 100\n").is_err());
+    }
+
+    #[test]
+    fn test_parse_upload() {
+        let config = Config::parse("
+            [upload]
+            remote = \"b2:band-recordings\"
+            quality = 4
+            downmix = false
+        ").unwrap();
+        assert_eq!(config.upload.remote, "b2:band-recordings");
+        assert_eq!(config.upload.quality, 4.0);
+        assert!(!config.upload.downmix);
+        assert!(config.upload.stick_config);
+        assert_eq!(config.upload.remote_path("Take 1.ogg"), "b2:band-recordings/Take 1.ogg");
+        assert_eq!(Config::default().upload.remote_path("Take 1.ogg"), "upload:/Auto_Upload/Take 1.ogg");
+
+        assert!(Config::parse("[upload]\nremote = \"/srv/uploads\"\n").is_err());
+        assert!(Config::parse("[upload]\nremote = \"--config=x:\"\n").is_err());
+        assert!(Config::parse("[upload]\nquality = 11\n").is_err());
+    }
+
+    #[test]
+    fn test_parse_rejects_unknown() {
+        assert!(Config::parse("[storage]\nlayout = \"raid\"\n").is_err());
+        assert!(Config::parse("[storage]\nlayuot = \"thin\"\n").is_err());
+    }
+
+    #[test]
+    fn test_load_missing() {
+        assert_eq!(Config::load_from(Path::new("/nonexistent/upload-stick.toml")).unwrap(), Config::default());
+    }
+}
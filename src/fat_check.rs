@@ -0,0 +1,167 @@
+use std::fs;
+use std::io;
+use std::path::{Path, PathBuf};
+use std::process::Command;
+use upload_command::{Error, Result, command_stdout};
+use volume::{FatVolume, VolumeEntry};
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct FatCheck {
+    pub dirty: bool,
+    // Absolute paths, as printed by fsck.fat, of files with inconsistent cluster chains
+    pub damaged_files: Vec<String>,
+    pub fsck_output: String,
+}
+
+impl FatCheck {
+    pub fn is_damaged(&self, entry: &VolumeEntry) -> bool {
+        let long_path = format!("/{}", entry.path);
+        let short_path = entry.short_path.as_ref().map(|short_path| format!("/{}", short_path));
+        self.damaged_files.iter().any(|damaged| {
+            damaged.eq_ignore_ascii_case(&long_path) ||
+                short_path.as_ref().is_some_and(|short_path| damaged.eq_ignore_ascii_case(short_path))
+        })
+    }
+
+    pub fn record(&self, partition: u32) -> Result<()> {
+        fs::create_dir_all(report_dir())
+            .map_err(Error::FsckRecord)?;
+        let report = format!("dirty: {}\ndamaged files: {}\n\n{}",
+            self.dirty, self.damaged_files.join(", "), self.fsck_output);
+        fs::write(report_path(partition), report)
+            .map_err(Error::FsckRecord)
+    }
+}
+
+// Takes the place of the last check's result, so the report never describes an older snapshot
+pub fn record_unchecked(partition: u32, err: &Error) -> Result<()> {
+    fs::create_dir_all(report_dir())
+        .map_err(Error::FsckRecord)?;
+    fs::write(report_path(partition), format!("not checked: {}\n", err))
+        .map_err(Error::FsckRecord)
+}
+
+fn report_dir() -> PathBuf {
+    PathBuf::from("/var/lib/upload-stick/fsck")
+}
+
+pub fn report_path(partition: u32) -> PathBuf {
+    report_dir().join(format!("partition{}.txt", partition))
+}
+
+// Returns None when the partition does not hold a FAT file system
+pub fn check_partition(device: &Path) -> Result<Option<FatCheck>> {
+    let dirty = match FatVolume::open(device) {
+        Ok(volume) => volume.is_dirty()?,
+        Err(_) => return Ok(None),
+    };
+
+    let fsck_output = match fsck_output(command_stdout(Command::new("fsck.fat").arg("-n").arg(device)))? {
+        Some(fsck_output) => fsck_output,
+        None => {
+            println!("fsck.fat is not installed, so only the dirty flag of {} is checked", device.display());
+            NOT_INSTALLED.to_string()
+        },
+    };
+    Ok(Some(FatCheck {
+        dirty,
+        damaged_files: fsck_find_damaged_files(&fsck_output),
+        fsck_output,
+    }))
+}
+
+const NOT_INSTALLED: &str = "fsck.fat not installed\n";
+
+// The result of fsck.fat -n, which opens the device read-only and never asks questions. Only a
+// check that could not run at all on an installed fsck.fat is an error; None means it is missing.
+fn fsck_output(result: Result<String>) -> Result<Option<String>> {
+    match result {
+        Ok(stdout) => Ok(Some(stdout)),
+        // Exit code 1 means that problems were found, which -n leaves for the host to repair
+        Err(Error::CommandNonZeroExitCode { code: 1, stdout, stderr }) => Ok(Some(stdout + &stderr)),
+        Err(Error::CommandOther(ref err)) if err.kind() == io::ErrorKind::NotFound => Ok(None),
+        Err(err) => Err(err),
+    }
+}
+
+// fsck.fat prints the affected paths, then the problem indented below them
+fn fsck_find_damaged_files(fsck_output: &str) -> Vec<String> {
+    let mut damaged_files: Vec<String> = Vec::new();
+    let mut paths = Vec::new();
+    let mut in_problem = false;
+    for line in fsck_output.lines() {
+        if line.starts_with('/') {
+            if in_problem {
+                paths.clear();
+                in_problem = false;
+            }
+            paths.push(line.trim_end().trim_end_matches(" and").to_string());
+        } else if line.starts_with(' ') {
+            in_problem = true;
+            if line.contains("cluster") {
+                for path in &paths {
+                    if !damaged_files.contains(path) {
+                        damaged_files.push(path.clone());
+                    }
+                }
+            }
+        } else {
+            paths.clear();
+            in_problem = false;
+        }
+    }
+    damaged_files
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn entry(path: &str, short_path: Option<&str>) -> VolumeEntry {
+        VolumeEntry {
+            path: path.to_string(),
+            file_name: path.rsplit('/').next().unwrap().to_string(),
+            os_path: PathBuf::from(path),
+            short_path: short_path.map(String::from),
+            len: 0,
+            is_dir: false,
+        }
+    }
+
+    #[test]
+    fn test_fsck_find_damaged_files_clean() {
+        assert!(fsck_find_damaged_files(include_str!("../testdata/fsck/clean.txt")).is_empty());
+    }
+
+    #[test]
+    fn test_fsck_find_damaged_files() {
+        assert_eq!(
+            fsck_find_damaged_files(include_str!("../testdata/fsck/mid_write.txt")),
+            vec!["/ZOOM0003.WAV", "/SESSION/TAKE02.WAV", "/SESSION/TAKE03.WAV", "/SESSION/NOTES.TXT"]
+        );
+    }
+
+    #[test]
+    fn test_fsck_output() {
+        assert_eq!(fsck_output(Ok("clean\n".to_string())).unwrap(), Some("clean\n".to_string()));
+        let found = Error::CommandNonZeroExitCode { code: 1, stdout: "found\n".to_string(), stderr: "fixed\n".to_string() };
+        assert_eq!(fsck_output(Err(found)).unwrap(), Some("found\nfixed\n".to_string()));
+        let missing = Error::CommandOther(io::Error::new(io::ErrorKind::NotFound, "fsck.fat"));
+        assert_eq!(fsck_output(Err(missing)).unwrap(), None);
+        let unreadable = Error::CommandNonZeroExitCode { code: 2, stdout: String::new(), stderr: "read failed\n".to_string() };
+        assert!(fsck_output(Err(unreadable)).is_err());
+    }
+
+    #[test]
+    fn test_is_damaged() {
+        let check = FatCheck {
+            dirty: true,
+            damaged_files: fsck_find_damaged_files(include_str!("../testdata/fsck/mid_write.txt")),
+            fsck_output: String::new(),
+        };
+        assert!(check.is_damaged(&entry("zoom0003.wav", None)));
+        assert!(check.is_damaged(&entry("Session/Second take.wav", Some("SESSION/TAKE03.WAV"))));
+        assert!(!check.is_damaged(&entry("ZOOM0001.WAV", Some("ZOOM0001.WAV"))));
+        assert!(!check.is_damaged(&entry("FOUND.000", Some("FOUND.000"))));
+    }
+}
@@ -0,0 +1,97 @@
+use std::ffi::{OsStr, OsString};
+use std::os::unix::ffi::{OsStrExt, OsStringExt};
+
+// Turns a name that may not be UTF-8 into one that is, for remote names and reports. UTF-8 names
+// are kept as they are, except that a '%' followed by two hex digits becomes "%25", and every byte
+// that is not part of a UTF-8 character becomes '%' and two upper case hex digits. unescape
+// reverses it exactly.
+pub fn escape(name: &OsStr) -> String {
+    let bytes = name.as_bytes();
+    let mut escaped = String::new();
+    let mut rest = bytes;
+    while !rest.is_empty() {
+        let (valid, invalid) = match ::std::str::from_utf8(rest) {
+            Ok(valid) => (valid, &[][..]),
+            Err(err) => {
+                let (valid, after) = rest.split_at(err.valid_up_to());
+                let invalid_len = err.error_len().unwrap_or(after.len());
+                (::std::str::from_utf8(valid).expect("Checked UTF-8"), &after[..invalid_len])
+            },
+        };
+        for (index, c) in valid.char_indices() {
+            if c == '%' && is_hex_pair(&rest[index + 1..]) {
+                escaped.push_str("%25");
+            } else {
+                escaped.push(c);
+            }
+        }
+        for byte in invalid {
+            escaped.push_str(&format!("%{:02X}", byte));
+        }
+        rest = &rest[valid.len() + invalid.len()..];
+    }
+    escaped
+}
+
+pub fn unescape(escaped: &str) -> OsString {
+    let bytes = escaped.as_bytes();
+    let mut name = Vec::new();
+    let mut index = 0;
+    while index < bytes.len() {
+        if bytes[index] == b'%' && is_hex_pair(&bytes[index + 1..]) {
+            let hex = ::std::str::from_utf8(&bytes[index + 1..index + 3]).expect("Checked hex digits");
+            name.push(u8::from_str_radix(hex, 16).expect("Checked hex digits"));
+            index += 3;
+        } else {
+            name.push(bytes[index]);
+            index += 1;
+        }
+    }
+    OsString::from_vec(name)
+}
+
+fn is_hex_pair(bytes: &[u8]) -> bool {
+    bytes.len() >= 2 && bytes[0].is_ascii_hexdigit() && bytes[1].is_ascii_hexdigit()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use proptest::prelude::*;
+
+    fn escape_bytes(bytes: &[u8]) -> String {
+        escape(OsStr::from_bytes(bytes))
+    }
+
+    #[test]
+    fn test_escape() {
+        assert_eq!(escape_bytes(b"Take 1.wav"), "Take 1.wav");
+        assert_eq!(escape_bytes("Caf\u{e9} 100%.wav".as_bytes()), "Caf\u{e9} 100%.wav");
+        assert_eq!(escape_bytes(b"Caf\xe9.wav"), "Caf%E9.wav");
+        assert_eq!(escape_bytes(b"Caf\xe9 \xff\xfe.wav"), "Caf%E9 %FF%FE.wav");
+        assert_eq!(escape_bytes(b"50%E9.wav"), "50%25E9.wav");
+        assert_eq!(escape_bytes(b"%\xe9"), "%%E9");
+        // A truncated UTF-8 sequence at the end
+        assert_eq!(escape_bytes(b"Take\xe2\x82"), "Take%E2%82");
+    }
+
+    #[test]
+    fn test_unescape() {
+        assert_eq!(unescape("Caf%E9.wav"), OsStr::from_bytes(b"Caf\xe9.wav"));
+        assert_eq!(unescape("50%25E9.wav"), OsStr::from_bytes(b"50%E9.wav"));
+        assert_eq!(unescape("100%.wav"), OsStr::from_bytes(b"100%.wav"));
+    }
+
+    proptest! {
+        #[test]
+        fn test_escape_round_trip(bytes in proptest::collection::vec(any::<u8>(), 0..32)) {
+            let escaped = escape_bytes(&bytes);
+            prop_assert_eq!(unescape(&escaped).into_vec(), bytes);
+        }
+
+        #[test]
+        fn test_escape_keeps_plain_names(name in "[^%]*") {
+            prop_assert_eq!(escape_bytes(name.as_bytes()), name);
+        }
+    }
+}
@@ -0,0 +1,216 @@
+use std::fs;
+use std::path::Path;
+use std::process::Command;
+use upload_command::{Error, Result, command_stdout};
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum FileSystemType {
+    Fat,
+    Exfat,
+    Ntfs,
+}
+
+pub const DEFAULT_VOLUME_LABEL: &str = "PI_UPLOAD";
+
+// Nothing on the volume may be run or used to gain privileges
+const MOUNT_OPTIONS: &str = "noexec,nosuid,nodev";
+
+impl FileSystemType {
+    // Names accepted by upload_stick_prepare
+    pub fn parse(name: &str) -> Result<FileSystemType> {
+        match name {
+            "fat32" => Ok(FileSystemType::Fat),
+            "exfat" => Ok(FileSystemType::Exfat),
+            "ntfs" => Ok(FileSystemType::Ntfs),
+            _ => Err(Error::InvalidArgument(format!("file system {}", name))),
+        }
+    }
+
+    // Names reported by blkid
+    pub fn from_blkid(name: &str) -> Option<FileSystemType> {
+        match name {
+            "vfat" => Some(FileSystemType::Fat),
+            "exfat" => Some(FileSystemType::Exfat),
+            "ntfs" => Some(FileSystemType::Ntfs),
+            _ => None,
+        }
+    }
+
+    pub fn name(&self) -> &'static str {
+        match self {
+            FileSystemType::Fat => "FAT32",
+            FileSystemType::Exfat => "exFAT",
+            FileSystemType::Ntfs => "NTFS",
+        }
+    }
+
+    // File system type for parted mkpart, which sets the MBR partition type
+    pub fn parted_type(&self) -> &'static str {
+        match self {
+            FileSystemType::Fat => "fat32",
+            // exFAT shares the MBR partition type of NTFS
+            FileSystemType::Exfat | FileSystemType::Ntfs => "ntfs",
+        }
+    }
+
+    fn max_label_len(&self) -> usize {
+        match self {
+            FileSystemType::Fat | FileSystemType::Exfat => 11,
+            FileSystemType::Ntfs => 32,
+        }
+    }
+
+    pub fn check_label(&self, volume_label: &str) -> Result<()> {
+        if volume_label.is_empty() || volume_label.chars().count() > self.max_label_len() {
+            return Err(Error::InvalidArgument(format!("volume label {} must have 1 to {} characters",
+                volume_label, self.max_label_len())));
+        }
+        if *self == FileSystemType::Fat && !volume_label.chars().all(|c| c.is_ascii() && !"\"*+,./:;<=>?[\\]|".contains(c)) {
+            return Err(Error::InvalidArgument(format!("volume label {} contains characters not allowed on FAT", volume_label)));
+        }
+        Ok(())
+    }
+
+    pub fn mkfs_command(&self, device: &str, volume_label: &str) -> Command {
+        match self {
+            FileSystemType::Fat => {
+                let mut command = Command::new("mkfs.fat");
+                command.arg("-F").arg("32").arg("-n").arg(volume_label.to_uppercase()).arg(device);
+                command
+            },
+            FileSystemType::Exfat => {
+                let mut command = Command::new("mkfs.exfat");
+                command.arg("--volume-label").arg(volume_label).arg(device);
+                command
+            },
+            FileSystemType::Ntfs => {
+                let mut command = Command::new("mkfs.ntfs");
+                command.arg("--quick").arg("--label").arg(volume_label).arg(device);
+                command
+            },
+        }
+    }
+
+    pub fn mount_type(&self) -> &'static str {
+        match self {
+            FileSystemType::Fat => "vfat",
+            FileSystemType::Exfat => "exfat",
+            FileSystemType::Ntfs => if kernel_supports("ntfs3") { "ntfs3" } else { "ntfs-3g" },
+        }
+    }
+
+    // Mounts of the snapshot must never change it
+    pub fn mount_options(&self) -> String {
+        self.options("ro")
+    }
+
+    // For deleting uploaded files while no host can see the volume
+    pub fn writable_mount_options(&self) -> String {
+        self.options("rw")
+    }
+
+    fn options(&self, mode: &str) -> String {
+        match self {
+            // Keep names as the host wrote them rather than lowercasing 8.3 names. FAT times are
+            // local to the host, so they are passed through unshifted and converted by the reader.
+            FileSystemType::Fat => format!("{},{},shortname=mixed,utf8,tz=UTC", mode, MOUNT_OPTIONS),
+            FileSystemType::Exfat => format!("{},{},iocharset=utf8", mode, MOUNT_OPTIONS),
+            FileSystemType::Ntfs => format!("{},{}", mode, MOUNT_OPTIONS),
+        }
+    }
+}
+
+fn kernel_supports(file_system: &str) -> bool {
+    fs::read_to_string("/proc/filesystems")
+        .map(|file_systems| proc_filesystems_contains(&file_systems, file_system))
+        .unwrap_or(false)
+}
+
+fn proc_filesystems_contains(file_systems: &str, file_system: &str) -> bool {
+    file_systems.lines()
+        .any(|line| line.split_whitespace().last() == Some(file_system))
+}
+
+// Returns None when blkid does not recognise a supported file system
+pub fn detect(device: &Path) -> Result<Option<FileSystemType>> {
+    detect_at(device, 0)
+}
+
+// Looks for a file system starting at a byte offset into the device
+pub fn detect_at(device: &Path, offset: u64) -> Result<Option<FileSystemType>> {
+    Ok(probe_type_at(device, offset)?.and_then(|name| FileSystemType::from_blkid(&name)))
+}
+
+// The type as blkid names it, including file systems not supported for the volume
+pub fn probe_type(device: &Path) -> Result<Option<String>> {
+    probe_type_at(device, 0)
+}
+
+fn probe_type_at(device: &Path, offset: u64) -> Result<Option<String>> {
+    // Probe the device directly rather than trusting the blkid cache, which knows nothing of snapshots
+    match command_stdout(
+        Command::new("blkid")
+            .arg("--probe")
+            .arg("--offset").arg(offset.to_string())
+            .arg("--output").arg("value")
+            .arg("--match-tag").arg("TYPE")
+            .arg(device)
+    ) {
+        Ok(output) => Ok(Some(output.trim().to_string())),
+        // Exit code 2 means that nothing was found
+        Err(Error::CommandNonZeroExitCode { code: 2, .. }) => Ok(None),
+        Err(err) => Err(err),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_parse() {
+        assert_eq!(FileSystemType::parse("fat32").unwrap(), FileSystemType::Fat);
+        assert_eq!(FileSystemType::parse("exfat").unwrap(), FileSystemType::Exfat);
+        assert_eq!(FileSystemType::parse("ntfs").unwrap(), FileSystemType::Ntfs);
+        assert!(FileSystemType::parse("ext4").is_err());
+    }
+
+    #[test]
+    fn test_from_blkid() {
+        assert_eq!(FileSystemType::from_blkid("vfat"), Some(FileSystemType::Fat));
+        assert_eq!(FileSystemType::from_blkid("exfat"), Some(FileSystemType::Exfat));
+        assert_eq!(FileSystemType::from_blkid("ntfs"), Some(FileSystemType::Ntfs));
+        assert_eq!(FileSystemType::from_blkid("ext4"), None);
+    }
+
+    #[test]
+    fn test_check_label() {
+        assert!(FileSystemType::Fat.check_label("PI_UPLOAD").is_ok());
+        assert!(FileSystemType::Fat.check_label("RECORDINGS_2").is_err());
+        assert!(FileSystemType::Fat.check_label("A/B").is_err());
+        assert!(FileSystemType::Fat.check_label("").is_err());
+        assert!(FileSystemType::Exfat.check_label("Aufnahmen").is_ok());
+        assert!(FileSystemType::Ntfs.check_label("Multitrack recordings").is_ok());
+    }
+
+    #[test]
+    fn test_mkfs_command() {
+        let command = format!("{:?}", FileSystemType::Fat.mkfs_command("/dev/mapper/part", "pi_upload"));
+        assert_eq!(command, "\"mkfs.fat\" \"-F\" \"32\" \"-n\" \"PI_UPLOAD\" \"/dev/mapper/part\"");
+        let command = format!("{:?}", FileSystemType::Exfat.mkfs_command("/dev/mapper/part", "Recordings"));
+        assert_eq!(command, "\"mkfs.exfat\" \"--volume-label\" \"Recordings\" \"/dev/mapper/part\"");
+    }
+
+    #[test]
+    fn test_mount_options() {
+        assert_eq!(FileSystemType::Fat.mount_options(), "ro,noexec,nosuid,nodev,shortname=mixed,utf8,tz=UTC");
+        assert_eq!(FileSystemType::Ntfs.writable_mount_options(), "rw,noexec,nosuid,nodev");
+    }
+
+    #[test]
+    fn test_proc_filesystems_contains() {
+        let file_systems = "nodev\tsysfs\nnodev\ttmpfs\n\tvfat\n\texfat\n\tntfs3\nnodev\tfuseblk\n";
+        assert!(proc_filesystems_contains(file_systems, "ntfs3"));
+        assert!(!proc_filesystems_contains(file_systems, "ntfs"));
+    }
+}
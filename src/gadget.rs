@@ -0,0 +1,561 @@
+use std::fs;
+use std::io;
+use std::os::unix::fs::symlink;
+use std::path::{Path, PathBuf};
+use std::process::Command;
+use config::{GadgetFunction, GadgetMode, IdentityConfig, LunConfig};
+use udc;
+use upload_command::{Error, Result, command_stdout};
+
+const GADGET_NAME: &str = "upload_stick";
+const CONFIG_NAME: &str = "c.1";
+const MASS_STORAGE_FUNCTION: &str = "mass_storage.usb0";
+// The volume the host records to, and the optional read-only status volume
+pub const MEDIUM_LUN: u32 = 0;
+pub const STATUS_LUN: u32 = 1;
+// US English, the only language hosts reliably ask for
+const STRINGS_LANGUAGE: &str = "0x409";
+
+pub fn configfs_root() -> PathBuf {
+    PathBuf::from("/sys/kernel/config/usb_gadget")
+}
+
+// The configured serial number, or else the CPU serial number
+pub fn serial_number(identity: &IdentityConfig) -> Option<String> {
+    identity.serial.clone().or_else(|| {
+        fs::read_to_string("/proc/cpuinfo").ok()
+            .and_then(|cpuinfo| cpuinfo_serial(&cpuinfo))
+    })
+}
+
+fn cpuinfo_serial(cpuinfo: &str) -> Option<String> {
+    cpuinfo.lines()
+        .filter_map(|line| {
+            let mut fields = line.splitn(2, ':');
+            match (fields.next(), fields.next()) {
+                (Some(key), Some(value)) if key.trim() == "Serial" => Some(value.trim().to_string()),
+                _ => None,
+            }
+        })
+        .find(|serial| !serial.is_empty())
+}
+
+// Options for the legacy g_mass_storage module, which takes one value per LUN
+pub fn modprobe_command(file: &str, lun: &LunConfig, identity: &IdentityConfig, serial: Option<&str>,
+    status_image: Option<&str>) -> Command
+{
+    let per_lun = |medium: &str, status: &str| match status_image {
+        Some(_) => format!("{},{}", medium, status),
+        None => medium.to_string(),
+    };
+    let mut command = Command::new("modprobe");
+    command
+        .arg("g_mass_storage")
+        .arg(format!("file={}", per_lun(file, status_image.unwrap_or(""))))
+        .arg(format!("stall={}", flag(lun.stall)))
+        .arg(format!("removable={}", per_lun(flag(lun.removable), "1")))
+        .arg(format!("ro={}", per_lun(flag(lun.ro), "1")))
+        .arg(format!("cdrom={}", per_lun(flag(lun.cdrom), "0")))
+        .arg(format!("nofua={}", per_lun(flag(lun.nofua), "0")))
+        .arg(format!("idVendor={}", hex_id(identity.vendor_id)))
+        .arg(format!("idProduct={}", hex_id(identity.product_id)))
+        .arg(format!("iManufacturer={}", identity.manufacturer))
+        .arg(format!("iProduct={}", identity.product));
+    if let Some(serial) = serial {
+        command.arg(format!("iSerialNumber={}", serial));
+    }
+    command
+}
+
+// A composite gadget assembled in configfs through libcomposite
+pub struct Gadget {
+    path: PathBuf,
+    udc_root: PathBuf,
+    file: String,
+    lun: LunConfig,
+    identity: IdentityConfig,
+    serial: Option<String>,
+    status_image: Option<String>,
+    functions: Vec<GadgetFunction>,
+}
+
+impl Gadget {
+    pub fn new(configfs_root: &Path, udc_root: &Path, file: &str, lun: &LunConfig) -> Gadget {
+        Gadget {
+            path: configfs_root.join(GADGET_NAME),
+            udc_root: udc_root.to_path_buf(),
+            file: file.to_string(),
+            lun: lun.clone(),
+            identity: IdentityConfig::default(),
+            serial: None,
+            status_image: None,
+            functions: Vec::new(),
+        }
+    }
+
+    // Adds a read-only LUN exposing the given image
+    pub fn status_image(mut self, path: &str) -> Gadget {
+        self.status_image = Some(path.to_string());
+        self
+    }
+
+    pub fn identity(mut self, identity: &IdentityConfig, serial: Option<String>) -> Gadget {
+        self.identity = identity.clone();
+        self.serial = serial;
+        self
+    }
+
+    // Adds a function alongside mass storage
+    pub fn function(mut self, function: GadgetFunction) -> Gadget {
+        if !self.functions.contains(&function) {
+            self.functions.push(function);
+        }
+        self
+    }
+
+    // Creates or reconfigures the gadget, then binds it to the first UDC
+    pub fn start(&self) -> Result<()> {
+        let udc = self.find_udc()?;
+        self.configure().map_err(Error::GadgetConfigfs)?;
+        println!("Binding USB gadget to {}", udc);
+        write_attribute(&self.path.join("UDC"), &udc).map_err(Error::GadgetConfigfs)
+    }
+
+    pub fn stop(&self) -> Result<()> {
+        self.unbind().map_err(Error::GadgetConfigfs)
+    }
+
+    fn find_udc(&self) -> Result<String> {
+        udc::first_udc(&self.udc_root)?
+            .ok_or_else(|| Error::UdcNotFound(self.udc_root.display().to_string()))
+    }
+
+    // The gadget must be unbound before its functions can change
+    fn unbind(&self) -> io::Result<()> {
+        let udc_path = self.path.join("UDC");
+        match fs::read_to_string(&udc_path) {
+            Ok(ref udc) if !udc.trim().is_empty() => write_attribute(&udc_path, ""),
+            Ok(_) => Ok(()),
+            Err(ref err) if err.kind() == io::ErrorKind::NotFound => Ok(()),
+            Err(err) => Err(err),
+        }
+    }
+
+    fn configure(&self) -> io::Result<()> {
+        self.unbind()?;
+
+        fs::create_dir_all(&self.path)?;
+        write_attribute(&self.path.join("idVendor"), &hex_id(self.identity.vendor_id))?;
+        write_attribute(&self.path.join("idProduct"), &hex_id(self.identity.product_id))?;
+        write_attribute(&self.path.join("bcdUSB"), "0x0200")?;
+
+        let strings = self.path.join("strings").join(STRINGS_LANGUAGE);
+        fs::create_dir_all(&strings)?;
+        write_attribute(&strings.join("manufacturer"), &self.identity.manufacturer)?;
+        write_attribute(&strings.join("product"), &self.identity.product)?;
+        write_attribute(&strings.join("serialnumber"), self.serial.as_deref().unwrap_or(""))?;
+
+        let config_path = self.path.join("configs").join(CONFIG_NAME);
+        let config_strings = config_path.join("strings").join(STRINGS_LANGUAGE);
+        fs::create_dir_all(&config_strings)?;
+        write_attribute(&config_strings.join("configuration"), &self.configuration_name())?;
+        write_attribute(&config_path.join("MaxPower"), "250")?;
+
+        // The function's attributes cannot change while it is linked into the configuration
+        let mass_storage_link = config_path.join(MASS_STORAGE_FUNCTION);
+        if fs::symlink_metadata(&mass_storage_link).is_ok() {
+            fs::remove_file(&mass_storage_link)?;
+        }
+        self.configure_mass_storage()?;
+        let mut linked = vec![MASS_STORAGE_FUNCTION];
+        for function in &self.functions {
+            fs::create_dir_all(self.path.join("functions").join(function_name(*function)))?;
+            linked.push(function_name(*function));
+        }
+
+        // Drop functions left over from a previous configuration
+        for entry in fs::read_dir(&config_path)? {
+            let entry = entry?;
+            if entry.file_type()?.is_symlink() && !linked.iter().any(|name| entry.file_name() == **name) {
+                fs::remove_file(entry.path())?;
+            }
+        }
+        for name in linked {
+            let link = config_path.join(name);
+            if fs::symlink_metadata(&link).is_err() {
+                symlink(self.path.join("functions").join(name), link)?;
+            }
+        }
+        Ok(())
+    }
+
+    fn configure_mass_storage(&self) -> io::Result<()> {
+        let function_path = self.path.join("functions").join(MASS_STORAGE_FUNCTION);
+        let lun_path = function_path.join(format!("lun.{}", MEDIUM_LUN));
+        let status_lun_path = function_path.join(format!("lun.{}", STATUS_LUN));
+        fs::create_dir_all(&lun_path)?;
+        // The other LUN options cannot change while the backing file is open
+        close_lun_file(&lun_path)?;
+        close_lun_file(&status_lun_path)?;
+
+        update_attribute(&function_path.join("stall"), flag(self.lun.stall))?;
+        update_attribute(&lun_path.join("removable"), flag(self.lun.removable))?;
+        update_attribute(&lun_path.join("cdrom"), flag(self.lun.cdrom))?;
+        update_attribute(&lun_path.join("ro"), flag(self.lun.ro))?;
+        update_attribute(&lun_path.join("nofua"), flag(self.lun.nofua))?;
+        if let Some(ref inquiry) = self.identity.inquiry {
+            update_attribute(&lun_path.join("inquiry_string"), inquiry)?;
+        }
+        write_attribute(&lun_path.join("file"), &self.file)?;
+
+        match self.status_image {
+            Some(ref status_image) => {
+                fs::create_dir_all(&status_lun_path)?;
+                update_attribute(&status_lun_path.join("removable"), "1")?;
+                update_attribute(&status_lun_path.join("ro"), "1")?;
+                write_attribute(&status_lun_path.join("file"), status_image)
+            },
+            // LUN 0 cannot be removed, but others can while the gadget is unbound
+            None if status_lun_path.exists() => fs::remove_dir(&status_lun_path),
+            None => Ok(()),
+        }
+    }
+
+    fn configuration_name(&self) -> String {
+        let mut names = vec!["Mass Storage"];
+        for function in &self.functions {
+            names.push(match function {
+                GadgetFunction::Acm => "ACM",
+                GadgetFunction::Ecm => "ECM",
+            });
+        }
+        names.join(" + ")
+    }
+}
+
+fn function_name(function: GadgetFunction) -> &'static str {
+    match function {
+        GadgetFunction::Acm => "acm.usb0",
+        GadgetFunction::Ecm => "ecm.usb0",
+    }
+}
+
+const EBUSY: i32 = 16;
+
+fn write_attribute(path: &Path, value: &str) -> io::Result<()> {
+    fs::write(path, format!("{}\n", value))
+}
+
+// Some attributes reject any write while in use, even one that changes nothing
+fn update_attribute(path: &Path, value: &str) -> io::Result<()> {
+    match fs::read_to_string(path) {
+        Ok(ref current) if current.trim_end() == value => Ok(()),
+        _ => write_attribute(path, value),
+    }
+}
+
+fn close_lun_file(lun_path: &Path) -> io::Result<()> {
+    match fs::read_to_string(lun_path.join("file")) {
+        Ok(ref file) if !file.trim_end().is_empty() => write_attribute(&lun_path.join("file"), ""),
+        Ok(_) => Ok(()),
+        Err(ref err) if err.kind() == io::ErrorKind::NotFound => Ok(()),
+        Err(err) => Err(err),
+    }
+}
+
+fn hex_id(id: u16) -> String {
+    format!("0x{:04x}", id)
+}
+
+fn flag(value: bool) -> &'static str {
+    if value { "1" } else { "0" }
+}
+
+pub fn load_libcomposite() -> Result<()> {
+    command_stdout(Command::new("modprobe").arg("libcomposite")).map(|_| ())
+}
+
+// The exported medium of the mass storage function
+pub struct Lun {
+    path: PathBuf,
+}
+
+impl Lun {
+    pub fn find(mode: GadgetMode, configfs_root: &Path, udc_root: &Path, index: u32) -> Result<Lun> {
+        let path = match mode {
+            GadgetMode::Configfs => configfs_root.join(GADGET_NAME).join("functions").join(MASS_STORAGE_FUNCTION)
+                .join(format!("lun.{}", index)),
+            GadgetMode::Modprobe => find_legacy_lun(udc_root, index)?,
+        };
+        if !path.join("file").exists() {
+            return Err(Error::LunNotFound(path.display().to_string()));
+        }
+        Ok(Lun { path })
+    }
+
+    // Empty when no medium is inserted
+    pub fn file(&self) -> Result<String> {
+        fs::read_to_string(self.path.join("file"))
+            .map(|file| file.trim_end().to_string())
+            .map_err(Error::GadgetConfigfs)
+    }
+
+    // The host sees the medium removed, even if it had locked it in
+    pub fn eject(&self) -> Result<()> {
+        let forced_eject = self.path.join("forced_eject");
+        if forced_eject.exists() {
+            return write_attribute(&forced_eject, "1").map_err(Error::GadgetConfigfs);
+        }
+        // Kernels before forced_eject can only eject media the host has not locked
+        // with PREVENT MEDIUM REMOVAL
+        write_attribute(&self.path.join("file"), "").map_err(|err| self.eject_error(err))
+    }
+
+    fn eject_error(&self, err: io::Error) -> Error {
+        if err.raw_os_error() == Some(EBUSY) {
+            return Error::MediumLocked(self.path.display().to_string());
+        }
+        Error::GadgetConfigfs(err)
+    }
+
+    pub fn insert(&self, file: &str) -> Result<()> {
+        write_attribute(&self.path.join("file"), file).map_err(Error::GadgetConfigfs)
+    }
+}
+
+// g_mass_storage puts its LUNs under the gadget device of the controller
+fn find_legacy_lun(udc_root: &Path, index: u32) -> Result<PathBuf> {
+    let lun_name = format!("lun{}", index);
+    let udc = udc::first_udc(udc_root)?
+        .ok_or_else(|| Error::UdcNotFound(udc_root.display().to_string()))?;
+    let device_path = udc_root.join(udc).join("device");
+    for entry in fs::read_dir(&device_path).map_err(Error::GadgetConfigfs)? {
+        let entry = entry.map_err(Error::GadgetConfigfs)?;
+        if entry.file_name().to_string_lossy().starts_with("gadget") && entry.path().join(&lun_name).is_dir() {
+            return Ok(entry.path().join(&lun_name));
+        }
+    }
+    Err(Error::LunNotFound(device_path.display().to_string()))
+}
+
+// Hides the medium from the host while changing it, then inserts it again so the
+// host rereads it instead of trusting its cache. The medium is reinserted even if
+// the change fails.
+pub fn with_medium_ejected<F, T>(lun: &Lun, change: F) -> Result<T>
+    where F: FnOnce() -> Result<T>
+{
+    let file = lun.file()?;
+    if file.is_empty() {
+        return change();
+    }
+    println!("Ejecting {} from the host", file);
+    lun.eject()?;
+    let result = change();
+    println!("Inserting {} again", file);
+    lun.insert(&file)?;
+    result
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use test_util::TempDir;
+
+    // An empty configfs gadget directory and a sysfs UDC class with one controller
+    fn fake_sysfs(name: &str) -> TempDir {
+        let root = TempDir::new(name);
+        fs::create_dir_all(root.join("usb_gadget")).unwrap();
+        fs::create_dir_all(root.join("udc/20980000.usb")).unwrap();
+        root
+    }
+
+    fn read(path: PathBuf) -> String {
+        fs::read_to_string(path).unwrap().trim_end().to_string()
+    }
+
+    fn gadget(root: &Path, lun: &LunConfig) -> Gadget {
+        Gadget::new(&root.join("usb_gadget"), &root.join("udc"), "/dev/data/mass_storage_root", lun)
+    }
+
+    #[test]
+    fn test_start() {
+        let root = fake_sysfs("gadget_start");
+        let lun = LunConfig { ro: true, ..LunConfig::default() };
+        gadget(&root, &lun).function(GadgetFunction::Acm).start().unwrap();
+
+        let path = root.join("usb_gadget/upload_stick");
+        assert_eq!(read(path.join("idVendor")), "0x0525");
+        assert_eq!(read(path.join("idProduct")), "0xa4a5");
+        assert_eq!(read(path.join("UDC")), "20980000.usb");
+        assert_eq!(read(path.join("configs/c.1/strings/0x409/configuration")), "Mass Storage + ACM");
+
+        let lun_path = path.join("functions/mass_storage.usb0/lun.0");
+        assert_eq!(read(lun_path.join("file")), "/dev/data/mass_storage_root");
+        assert_eq!(read(lun_path.join("removable")), "1");
+        assert_eq!(read(lun_path.join("ro")), "1");
+        assert_eq!(read(lun_path.join("cdrom")), "0");
+        assert_eq!(read(path.join("functions/mass_storage.usb0/stall")), "0");
+
+        assert_eq!(fs::read_link(path.join("configs/c.1/acm.usb0")).unwrap(), path.join("functions/acm.usb0"));
+        assert!(path.join("configs/c.1/mass_storage.usb0").exists());
+        assert!(!path.join("configs/c.1/ecm.usb0").exists());
+    }
+
+    #[test]
+    fn test_start_reconfigures() {
+        let root = fake_sysfs("gadget_reconfigure");
+        gadget(&root, &LunConfig::default()).function(GadgetFunction::Acm).start().unwrap();
+        let lun = LunConfig { ro: true, ..LunConfig::default() };
+        gadget(&root, &lun).function(GadgetFunction::Ecm).start().unwrap();
+
+        let config_path = root.join("usb_gadget/upload_stick/configs/c.1");
+        assert!(fs::symlink_metadata(config_path.join("acm.usb0")).is_err());
+        assert!(config_path.join("ecm.usb0").exists());
+        assert!(config_path.join("mass_storage.usb0").exists());
+        let lun_path = root.join("usb_gadget/upload_stick/functions/mass_storage.usb0/lun.0");
+        assert_eq!(read(lun_path.join("ro")), "1");
+        assert_eq!(read(lun_path.join("file")), "/dev/data/mass_storage_root");
+
+        gadget(&root, &LunConfig::default()).stop().unwrap();
+        assert_eq!(read(root.join("usb_gadget/upload_stick/UDC")), "");
+    }
+
+    #[test]
+    fn test_start_without_udc() {
+        let root = fake_sysfs("gadget_no_udc");
+        fs::remove_dir(root.join("udc/20980000.usb")).unwrap();
+        assert!(gadget(&root, &LunConfig::default()).start().is_err());
+        assert!(!root.join("usb_gadget/upload_stick").exists());
+    }
+
+    #[test]
+    fn test_start_identity() {
+        let root = fake_sysfs("gadget_identity");
+        let identity = IdentityConfig {
+            vendor_id: 0x1209,
+            product_id: 0x0001,
+            product: "Rehearsal room stick".to_string(),
+            inquiry: Some("Band    Upload Stick    1.0".to_string()),
+            ..IdentityConfig::default()
+        };
+        gadget(&root, &LunConfig::default())
+            .identity(&identity, Some("00000000a1b2c3d4".to_string()))
+            .start().unwrap();
+
+        let path = root.join("usb_gadget/upload_stick");
+        assert_eq!(read(path.join("idVendor")), "0x1209");
+        assert_eq!(read(path.join("idProduct")), "0x0001");
+        assert_eq!(read(path.join("strings/0x409/manufacturer")), "Linux");
+        assert_eq!(read(path.join("strings/0x409/product")), "Rehearsal room stick");
+        assert_eq!(read(path.join("strings/0x409/serialnumber")), "00000000a1b2c3d4");
+        assert_eq!(read(path.join("functions/mass_storage.usb0/lun.0/inquiry_string")), "Band    Upload Stick    1.0");
+    }
+
+    fn fake_legacy_lun(root: &Path, forced_eject: bool) -> PathBuf {
+        let lun_path = root.join("udc/20980000.usb/device/gadget.0/lun0");
+        fs::create_dir_all(&lun_path).unwrap();
+        fs::write(lun_path.join("file"), "/dev/data/mass_storage_root\n").unwrap();
+        if forced_eject {
+            fs::write(lun_path.join("forced_eject"), "").unwrap();
+        }
+        lun_path
+    }
+
+    #[test]
+    fn test_with_medium_ejected() {
+        let root = fake_sysfs("gadget_eject");
+        let lun_path = fake_legacy_lun(&root, true);
+        let lun = Lun::find(GadgetMode::Modprobe, &root.join("usb_gadget"), &root.join("udc"), MEDIUM_LUN).unwrap();
+
+        let deleted = with_medium_ejected(&lun, || {
+            assert_eq!(read(lun_path.join("forced_eject")), "1");
+            Ok(3)
+        }).unwrap();
+        assert_eq!(deleted, 3);
+        assert_eq!(read(lun_path.join("file")), "/dev/data/mass_storage_root");
+    }
+
+    #[test]
+    fn test_with_medium_ejected_reinserts_after_failure() {
+        let root = fake_sysfs("gadget_eject_failure");
+        let lun_path = fake_legacy_lun(&root, false);
+        let lun = Lun::find(GadgetMode::Modprobe, &root.join("usb_gadget"), &root.join("udc"), MEDIUM_LUN).unwrap();
+
+        let result: Result<()> = with_medium_ejected(&lun, || {
+            assert_eq!(lun.file().unwrap(), "");
+            Err(Error::InvalidArgument("cleanup".to_string()))
+        });
+        assert!(result.is_err());
+        assert_eq!(read(lun_path.join("file")), "/dev/data/mass_storage_root");
+    }
+
+    #[test]
+    fn test_eject_error() {
+        let lun = Lun { path: PathBuf::from("/sys/devices/gadget/lun0") };
+        match lun.eject_error(io::Error::from_raw_os_error(EBUSY)) {
+            Error::MediumLocked(path) => assert_eq!(path, "/sys/devices/gadget/lun0"),
+            err => panic!("expected MediumLocked, got {}", err),
+        }
+        match lun.eject_error(io::Error::from_raw_os_error(13)) {
+            Error::GadgetConfigfs(_) => (),
+            err => panic!("expected GadgetConfigfs, got {}", err),
+        }
+    }
+
+    #[test]
+    fn test_lun_configfs() {
+        let root = fake_sysfs("gadget_lun");
+        assert!(Lun::find(GadgetMode::Configfs, &root.join("usb_gadget"), &root.join("udc"), MEDIUM_LUN).is_err());
+        gadget(&root, &LunConfig::default()).start().unwrap();
+        let lun = Lun::find(GadgetMode::Configfs, &root.join("usb_gadget"), &root.join("udc"), MEDIUM_LUN).unwrap();
+        assert_eq!(lun.file().unwrap(), "/dev/data/mass_storage_root");
+    }
+
+    #[test]
+    fn test_start_status_lun() {
+        let root = fake_sysfs("gadget_status_lun");
+        gadget(&root, &LunConfig::default())
+            .status_image("/var/lib/upload-stick/status.img")
+            .start().unwrap();
+
+        let status_lun_path = root.join("usb_gadget/upload_stick/functions/mass_storage.usb0/lun.1");
+        assert_eq!(read(status_lun_path.join("file")), "/var/lib/upload-stick/status.img");
+        assert_eq!(read(status_lun_path.join("ro")), "1");
+        let lun = Lun::find(GadgetMode::Configfs, &root.join("usb_gadget"), &root.join("udc"), STATUS_LUN).unwrap();
+        assert_eq!(lun.file().unwrap(), "/var/lib/upload-stick/status.img");
+    }
+
+    #[test]
+    fn test_modprobe_command_status_lun() {
+        let lun = LunConfig { nofua: true, ..LunConfig::default() };
+        let command = format!("{:?}", modprobe_command("/dev/data/mass_storage_root", &lun,
+            &IdentityConfig::default(), None, Some("/var/lib/upload-stick/status.img")));
+        assert!(command.contains("\"file=/dev/data/mass_storage_root,/var/lib/upload-stick/status.img\" \
+            \"stall=0\" \"removable=1,1\" \"ro=0,1\" \"cdrom=0,0\" \"nofua=1,0\""));
+        assert!(!command.contains("iSerialNumber"));
+    }
+
+    #[test]
+    fn test_cpuinfo_serial() {
+        let cpuinfo = "processor\t: 0\nmodel name\t: ARMv6-compatible processor rev 7 (v6l)\n\n\
+            Hardware\t: BCM2835\nRevision\t: 9000c1\nSerial\t\t: 00000000a1b2c3d4\nModel\t\t: Raspberry Pi Zero W Rev 1.1\n";
+        assert_eq!(cpuinfo_serial(cpuinfo), Some("00000000a1b2c3d4".to_string()));
+        assert_eq!(cpuinfo_serial("processor\t: 0\nflags\t\t: fpu vme\n"), None);
+    }
+
+    #[test]
+    fn test_serial_number_configured() {
+        let identity = IdentityConfig { serial: Some("STICK-7".to_string()), ..IdentityConfig::default() };
+        assert_eq!(serial_number(&identity), Some("STICK-7".to_string()));
+    }
+
+    #[test]
+    fn test_modprobe_command() {
+        let command = format!("{:?}", modprobe_command("/dev/data/mass_storage_root", &LunConfig::default(),
+            &IdentityConfig::default(), Some("00000000a1b2c3d4"), None));
+        assert_eq!(command, "\"modprobe\" \"g_mass_storage\" \"file=/dev/data/mass_storage_root\" \
+            \"stall=0\" \"removable=1\" \"ro=0\" \"cdrom=0\" \"nofua=0\" \
+            \"idVendor=0x0525\" \"idProduct=0xa4a5\" \"iManufacturer=Linux\" \"iProduct=File-Stor Gadget\" \
+            \"iSerialNumber=00000000a1b2c3d4\"");
+    }
+}
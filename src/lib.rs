@@ -1,2 +1,34 @@
+extern crate chrono;
+extern crate fatfs;
+extern crate ignore;
+extern crate serde;
+#[macro_use]
+extern crate serde_derive;
+extern crate serde_json;
+extern crate sha2;
+extern crate toml;
+
+#[cfg(test)]
+extern crate proptest;
+
+pub mod config;
+pub mod fat_check;
+pub mod file_name;
+pub mod file_system;
+pub mod gadget;
+pub mod lvm;
+pub mod parted;
+pub mod prepare;
+pub mod retention;
+pub mod size;
+pub mod status;
+pub mod stick_config;
+pub mod storage;
+pub mod udc;
 pub mod upload_db;
+pub mod upload_rules;
 pub mod upload_command;
+pub mod volume;
+
+#[cfg(test)]
+mod test_util;
@@ -0,0 +1,374 @@
+use std::process::Command;
+use serde::de::DeserializeOwned;
+use serde_json;
+use size::Size;
+use upload_command::{Error, Result, command_stdout};
+
+const LV_FIELDS: &str = "lv_name,vg_name,lv_attr,lv_size,lv_kernel_major,lv_kernel_minor,origin,pool_lv,data_percent,metadata_percent";
+const VG_FIELDS: &str = "vg_name,vg_size,vg_free,vg_extent_size,vg_extent_count,vg_free_count";
+const PV_FIELDS: &str = "pv_name,vg_name,pv_size,pv_free";
+
+#[derive(Deserialize)]
+struct Report<T> {
+    report: Vec<T>,
+}
+
+#[derive(Deserialize)]
+struct LvReport {
+    lv: Vec<LvRow>,
+}
+
+#[derive(Deserialize)]
+struct VgReport {
+    vg: Vec<VgRow>,
+}
+
+#[derive(Deserialize)]
+struct PvReport {
+    pv: Vec<PvRow>,
+}
+
+#[derive(Deserialize)]
+struct LvRow {
+    lv_name: String,
+    vg_name: String,
+    lv_attr: String,
+    lv_size: String,
+    lv_kernel_major: String,
+    lv_kernel_minor: String,
+    origin: String,
+    pool_lv: String,
+    data_percent: String,
+    metadata_percent: String,
+}
+
+#[derive(Deserialize)]
+struct VgRow {
+    vg_name: String,
+    vg_size: String,
+    vg_free: String,
+    vg_extent_size: String,
+    vg_extent_count: String,
+    vg_free_count: String,
+}
+
+#[derive(Deserialize)]
+struct PvRow {
+    pv_name: String,
+    vg_name: String,
+    pv_size: String,
+    pv_free: String,
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct LvAttributes {
+    pub volume_type: char,
+    pub permissions: char,
+    pub state: char,
+    pub open: bool,
+    pub raw: String,
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct LogicalVolume {
+    pub name: String,
+    pub vg_name: String,
+    pub size: Size,
+    pub kernel_major: Option<u32>,
+    pub kernel_minor: Option<u32>,
+    pub origin: Option<String>,
+    pub pool_lv: Option<String>,
+    pub data_percent: Option<f64>,
+    pub metadata_percent: Option<f64>,
+    pub attributes: LvAttributes,
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct VolumeGroup {
+    pub name: String,
+    pub size: Size,
+    pub free: Size,
+    pub extent_size: Size,
+    pub extent_count: u64,
+    pub free_count: u64,
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct PhysicalVolume {
+    pub name: String,
+    pub vg_name: Option<String>,
+    pub size: Size,
+    pub free: Size,
+}
+
+impl LvAttributes {
+    fn parse(lv_attr: &str) -> Result<LvAttributes> {
+        let chars = lv_attr.chars().collect::<Vec<char>>();
+        if chars.len() < 6 {
+            return Err(Error::LvmFieldParse(lv_attr.to_string()));
+        }
+
+        Ok(LvAttributes {
+            volume_type: chars[0],
+            permissions: chars[1],
+            state: chars[4],
+            open: chars[5] == 'o',
+            raw: lv_attr.to_string(),
+        })
+    }
+
+    pub fn is_snapshot(&self) -> bool {
+        self.volume_type == 's' || self.volume_type == 'S'
+    }
+
+    pub fn is_thin_pool(&self) -> bool {
+        self.volume_type == 't'
+    }
+
+    pub fn is_thin_volume(&self) -> bool {
+        self.volume_type == 'V'
+    }
+
+    pub fn is_active(&self) -> bool {
+        self.state == 'a'
+    }
+
+    pub fn is_invalid_snapshot(&self) -> bool {
+        self.volume_type == 'S' || (self.is_snapshot() && self.state == 'I')
+    }
+}
+
+impl LogicalVolume {
+    fn from_row(row: LvRow) -> Result<LogicalVolume> {
+        Ok(LogicalVolume {
+            attributes: LvAttributes::parse(&row.lv_attr)?,
+            size: Size::parse(&row.lv_size)?,
+            kernel_major: parse_device_number(&row.lv_kernel_major)?,
+            kernel_minor: parse_device_number(&row.lv_kernel_minor)?,
+            origin: non_empty(row.origin),
+            pool_lv: non_empty(row.pool_lv),
+            data_percent: parse_optional(&row.data_percent)?,
+            metadata_percent: parse_optional(&row.metadata_percent)?,
+            name: row.lv_name,
+            vg_name: row.vg_name,
+        })
+    }
+
+    pub fn full_name(&self) -> String {
+        format!("{}/{}", self.vg_name, self.name)
+    }
+}
+
+impl VolumeGroup {
+    fn from_row(row: VgRow) -> Result<VolumeGroup> {
+        Ok(VolumeGroup {
+            size: Size::parse(&row.vg_size)?,
+            free: Size::parse(&row.vg_free)?,
+            extent_size: Size::parse(&row.vg_extent_size)?,
+            extent_count: parse_field(&row.vg_extent_count)?,
+            free_count: parse_field(&row.vg_free_count)?,
+            name: row.vg_name,
+        })
+    }
+}
+
+impl PhysicalVolume {
+    fn from_row(row: PvRow) -> Result<PhysicalVolume> {
+        Ok(PhysicalVolume {
+            size: Size::parse(&row.pv_size)?,
+            free: Size::parse(&row.pv_free)?,
+            vg_name: non_empty(row.vg_name),
+            name: row.pv_name,
+        })
+    }
+}
+
+pub fn logical_volumes(selector: &str) -> Result<Vec<LogicalVolume>> {
+    parse_lvs(&report_command("lvs", LV_FIELDS, selector)?)
+}
+
+pub fn logical_volume(full_name: &str) -> Result<LogicalVolume> {
+    logical_volumes(full_name)?
+        .into_iter()
+        .next()
+        .ok_or_else(|| Error::LvNotFound(full_name.to_string()))
+}
+
+pub fn volume_groups(selector: &str) -> Result<Vec<VolumeGroup>> {
+    parse_vgs(&report_command("vgs", VG_FIELDS, selector)?)
+}
+
+pub fn volume_group(name: &str) -> Result<VolumeGroup> {
+    volume_groups(name)?
+        .into_iter()
+        .next()
+        .ok_or_else(|| Error::VgNotFound(name.to_string()))
+}
+
+pub fn physical_volumes(selector: &str) -> Result<Vec<PhysicalVolume>> {
+    parse_pvs(&report_command("pvs", PV_FIELDS, selector)?)
+}
+
+// Listing everything avoids the error the LVM tools report for unknown names
+pub fn logical_volume_exists(full_name: &str) -> Result<bool> {
+    Ok(logical_volumes("")?.iter().any(|lv| lv.full_name() == full_name))
+}
+
+pub fn volume_group_exists(name: &str) -> Result<bool> {
+    Ok(volume_groups("")?.iter().any(|vg| vg.name == name))
+}
+
+pub fn physical_volume_exists(name: &str) -> Result<bool> {
+    Ok(physical_volumes("")?.iter().any(|pv| pv.name == name))
+}
+
+fn report_command(command: &str, fields: &str, selector: &str) -> Result<String> {
+    let mut report_command = Command::new(command);
+    report_command
+        .arg("--reportformat").arg("json")
+        .arg("--units").arg("b")
+        .arg("-o").arg(fields);
+    if !selector.is_empty() {
+        report_command.arg(selector);
+    }
+    command_stdout(&mut report_command)
+}
+
+fn parse_report<T: DeserializeOwned>(output: &str) -> Result<Vec<T>> {
+    serde_json::from_str::<Report<T>>(output)
+        .map(|report| report.report)
+        .map_err(Error::LvmReportParse)
+}
+
+fn parse_lvs(output: &str) -> Result<Vec<LogicalVolume>> {
+    parse_report::<LvReport>(output)?
+        .into_iter()
+        .flat_map(|report| report.lv)
+        .map(LogicalVolume::from_row)
+        .collect()
+}
+
+fn parse_vgs(output: &str) -> Result<Vec<VolumeGroup>> {
+    parse_report::<VgReport>(output)?
+        .into_iter()
+        .flat_map(|report| report.vg)
+        .map(VolumeGroup::from_row)
+        .collect()
+}
+
+fn parse_pvs(output: &str) -> Result<Vec<PhysicalVolume>> {
+    parse_report::<PvReport>(output)?
+        .into_iter()
+        .flat_map(|report| report.pv)
+        .map(PhysicalVolume::from_row)
+        .collect()
+}
+
+fn non_empty(field: String) -> Option<String> {
+    if field.is_empty() {
+        None
+    } else {
+        Some(field)
+    }
+}
+
+fn parse_field<T: ::std::str::FromStr>(field: &str) -> Result<T> {
+    field.parse::<T>().map_err(|_| Error::LvmFieldParse(field.to_string()))
+}
+
+fn parse_optional<T: ::std::str::FromStr>(field: &str) -> Result<Option<T>> {
+    if field.is_empty() {
+        Ok(None)
+    } else {
+        parse_field(field).map(Some)
+    }
+}
+
+// Inactive volumes report -1 for their kernel device numbers
+fn parse_device_number(field: &str) -> Result<Option<u32>> {
+    match parse_optional::<i64>(field)? {
+        Some(number) if number >= 0 => Ok(Some(number as u32)),
+        _ => Ok(None),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use size::Unit;
+
+    #[test]
+    fn test_parse_lvs_snapshot() {
+        let lvs = parse_lvs(include_str!("../testdata/lvm/lvs_snapshot.json")).unwrap();
+        assert_eq!(lvs.len(), 2);
+
+        let root = &lvs[0];
+        assert_eq!(root.full_name(), "data/mass_storage_root");
+        assert_eq!(root.size, Size::new(20937965568.0, Unit::Bytes));
+        assert_eq!(root.kernel_major, Some(254));
+        assert_eq!(root.kernel_minor, Some(0));
+        assert_eq!(root.origin, None);
+        assert_eq!(root.data_percent, None);
+        assert!(root.attributes.open);
+        assert!(!root.attributes.is_snapshot());
+
+        let snap = &lvs[1];
+        assert_eq!(snap.origin, Some("mass_storage_root".to_string()));
+        assert_eq!(snap.data_percent, Some(12.47));
+        assert!(snap.attributes.is_snapshot());
+        assert!(snap.attributes.is_active());
+        assert!(!snap.attributes.is_invalid_snapshot());
+    }
+
+    #[test]
+    fn test_parse_lvs_inactive() {
+        let lvs = parse_lvs(include_str!("../testdata/lvm/lvs_inactive.json")).unwrap();
+        assert_eq!(lvs[0].kernel_minor, None);
+        assert!(!lvs[0].attributes.is_active());
+    }
+
+    #[test]
+    fn test_parse_lvs_invalid_snapshot() {
+        let lvs = parse_lvs(include_str!("../testdata/lvm/lvs_invalid_snapshot.json")).unwrap();
+        assert!(lvs[0].attributes.is_invalid_snapshot());
+        assert_eq!(lvs[0].data_percent, Some(100.0));
+    }
+
+    #[test]
+    fn test_parse_lvs_thin() {
+        let lvs = parse_lvs(include_str!("../testdata/lvm/lvs_thin.json")).unwrap();
+        assert_eq!(lvs.len(), 3);
+        assert!(lvs[0].attributes.is_thin_pool());
+        assert_eq!(lvs[0].data_percent, Some(41.02));
+        assert_eq!(lvs[0].metadata_percent, Some(3.11));
+        assert!(lvs[1].attributes.is_thin_volume());
+        assert_eq!(lvs[1].pool_lv, Some("mass_storage_pool".to_string()));
+        assert!(lvs[2].attributes.is_thin_volume());
+        assert_eq!(lvs[2].origin, Some("mass_storage_root".to_string()));
+    }
+
+    #[test]
+    fn test_parse_vgs() {
+        let vgs = parse_vgs(include_str!("../testdata/lvm/vgs.json")).unwrap();
+        assert_eq!(vgs, vec![VolumeGroup {
+            name: "data".to_string(),
+            size: Size::new(29909974016.0, Unit::Bytes),
+            free: Size::new(0.0, Unit::Bytes),
+            extent_size: Size::new(4194304.0, Unit::Bytes),
+            extent_count: 7131,
+            free_count: 0,
+        }]);
+    }
+
+    #[test]
+    fn test_parse_pvs() {
+        let pvs = parse_pvs(include_str!("../testdata/lvm/pvs.json")).unwrap();
+        assert_eq!(pvs[0].name, "/dev/mmcblk0p3");
+        assert_eq!(pvs[0].vg_name, Some("data".to_string()));
+    }
+
+    #[test]
+    fn test_parse_rejects_text_output() {
+        assert!(parse_lvs("  0\n").is_err());
+    }
+}
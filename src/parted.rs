@@ -0,0 +1,470 @@
+use std::fs::File;
+use std::io::Read;
+use std::process::Command;
+use size::{Size, Unit};
+use upload_command::{Error, Result, command_stdout};
+
+#[derive(Debug, Clone, PartialEq)]
+pub enum PartitionTable {
+    Msdos,
+    Gpt,
+    Loop,
+    Unknown,
+    Other(String),
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct Partition {
+    pub number: u32,
+    pub start: Size,
+    pub end: Size,
+    pub size: Size,
+    pub file_system: String,
+    pub name: String,
+    pub flags: Vec<String>,
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct FreeRegion {
+    pub start: Size,
+    pub end: Size,
+    pub size: Size,
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct DiskLayout {
+    pub device: String,
+    pub size: Size,
+    pub transport: String,
+    pub logical_sector_size: u64,
+    pub physical_sector_size: u64,
+    pub table: PartitionTable,
+    pub model: String,
+    pub partitions: Vec<Partition>,
+    pub free: Vec<FreeRegion>,
+}
+
+const DATA_FILE_SYSTEMS: [&str; 5] = ["fat12", "fat16", "fat32", "exfat", "ntfs"];
+
+const MBR_SIGNATURE_OFFSET: usize = 510;
+const MBR_PARTITION_TYPE_OFFSETS: [usize; 4] = [0x1c2, 0x1d2, 0x1e2, 0x1f2];
+const MBR_TYPE_GPT_PROTECTIVE: u8 = 0xee;
+
+impl PartitionTable {
+    pub fn parse(field: &str) -> PartitionTable {
+        match field {
+            "msdos" => PartitionTable::Msdos,
+            "gpt" => PartitionTable::Gpt,
+            "loop" => PartitionTable::Loop,
+            "unknown" => PartitionTable::Unknown,
+            other => PartitionTable::Other(other.to_string()),
+        }
+    }
+
+    pub fn label(&self) -> &str {
+        match self {
+            PartitionTable::Msdos => "msdos",
+            PartitionTable::Gpt => "gpt",
+            PartitionTable::Loop => "loop",
+            PartitionTable::Unknown => "unknown",
+            PartitionTable::Other(other) => other,
+        }
+    }
+}
+
+impl Partition {
+    pub fn has_data_file_system(&self) -> bool {
+        DATA_FILE_SYSTEMS.contains(&self.file_system.as_str())
+            && !self.flags.iter().any(|flag| flag == "esp")
+    }
+}
+
+impl DiskLayout {
+    pub fn parse(parted_output: &str) -> Result<DiskLayout> {
+        let mut lines = parted_output.lines()
+            .map(|line| line.trim())
+            .filter(|line| !line.is_empty());
+
+        match lines.next() {
+            Some("BYT;") => {},
+            Some(header) => return Err(Error::PartedUnitsNotSupported(header.to_string())),
+            None => return Err(Error::PartedDeviceNotFound(parted_output.to_string())),
+        }
+
+        let device_line = lines.next()
+            .ok_or_else(|| Error::PartedDeviceNotFound(parted_output.to_string()))?;
+        let mut layout = match split_fields(device_line).as_slice() {
+            [device, size, transport, logical, physical, table, model, ..] => DiskLayout {
+                device: device.to_string(),
+                size: Size::parse(size)?,
+                transport: transport.to_string(),
+                logical_sector_size: parse_number(logical, device_line)?,
+                physical_sector_size: parse_number(physical, device_line)?,
+                table: PartitionTable::parse(table),
+                model: model.to_string(),
+                partitions: Vec::new(),
+                free: Vec::new(),
+            },
+            _ => return Err(Error::PartedFieldsNotFound(device_line.to_string())),
+        };
+
+        for line in lines {
+            match split_fields(line).as_slice() {
+                [_, start, end, size, "free"] => layout.free.push(FreeRegion {
+                    start: Size::parse(start)?,
+                    end: Size::parse(end)?,
+                    size: Size::parse(size)?,
+                }),
+                [number, start, end, size, file_system, name, flags] => layout.partitions.push(Partition {
+                    number: parse_number(number, line)?,
+                    start: Size::parse(start)?,
+                    end: Size::parse(end)?,
+                    size: Size::parse(size)?,
+                    file_system: file_system.to_string(),
+                    name: name.to_string(),
+                    flags: flags.split(',')
+                        .map(|flag| flag.trim().to_string())
+                        .filter(|flag| !flag.is_empty())
+                        .collect(),
+                }),
+                _ => return Err(Error::PartedFieldsNotFound(line.to_string())),
+            }
+        }
+
+        Ok(layout)
+    }
+
+    pub fn partition(&self, number: u32) -> Option<&Partition> {
+        self.partitions.iter().find(|partition| partition.number == number)
+    }
+
+    pub fn data_partitions(&self) -> Result<Vec<&Partition>> {
+        let partitions = self.partitions.iter()
+            .filter(|partition| partition.has_data_file_system())
+            .collect::<Vec<&Partition>>();
+        if !partitions.is_empty() {
+            return Ok(partitions);
+        }
+
+        match self.partitions.as_slice() {
+            [partition] => Ok(vec![partition]),
+            _ => Err(Error::DataPartitionNotFound(self.device.clone())),
+        }
+    }
+
+    pub fn data_partition(&self) -> Result<&Partition> {
+        Ok(self.data_partitions()?[0])
+    }
+}
+
+// Kernel name of a partition: sda1, but mmcblk0p1 and nvme0n1p1 after a trailing digit
+pub fn partition_device(device: &str, number: u32) -> String {
+    if device.starts_with("/dev/disk/by-") {
+        format!("{}-part{}", device, number)
+    } else if device.ends_with(|c: char| c.is_ascii_digit()) {
+        format!("{}p{}", device, number)
+    } else {
+        format!("{}{}", device, number)
+    }
+}
+
+pub fn is_protective_mbr(first_sector: &[u8]) -> bool {
+    if first_sector.len() < MBR_SIGNATURE_OFFSET + 2
+        || first_sector[MBR_SIGNATURE_OFFSET..MBR_SIGNATURE_OFFSET + 2] != [0x55, 0xaa] {
+        return false;
+    }
+
+    MBR_PARTITION_TYPE_OFFSETS.iter().any(|&offset| first_sector[offset] == MBR_TYPE_GPT_PROTECTIVE)
+}
+
+pub fn read_data_partition(device: &str) -> Result<(DiskLayout, Partition)> {
+    let (layout, mut partitions) = read_data_partitions(device)?;
+    Ok((layout, partitions.remove(0)))
+}
+
+pub fn read_data_partitions(device: &str) -> Result<(DiskLayout, Vec<Partition>)> {
+    let layout = read_layout(device, Unit::Sectors)?;
+
+    if layout.table != PartitionTable::Gpt {
+        let mut first_sector = [0u8; 512];
+        File::open(device)
+            .and_then(|mut file| file.read_exact(&mut first_sector))
+            .map_err(Error::PartitionTableRead)?;
+        if is_protective_mbr(&first_sector) {
+            return Err(Error::GptHeaderNotFound(device.to_string()));
+        }
+    }
+
+    let partitions = layout.data_partitions()?.into_iter().cloned().collect();
+    Ok((layout, partitions))
+}
+
+pub fn read_layout(device: &str, unit: Unit) -> Result<DiskLayout> {
+    let parted_output = command_stdout(
+        Command::new("parted")
+            .arg("--script")
+            .arg("--machine")
+            .arg(device)
+            .arg("unit").arg(unit.suffix())
+            .arg("print").arg("free")
+    )?;
+
+    DiskLayout::parse(&parted_output)
+}
+
+fn split_fields(line: &str) -> Vec<&str> {
+    line.trim_end_matches(';').split(':').map(|field| field.trim()).collect()
+}
+
+fn parse_number<T: ::std::str::FromStr>(field: &str, line: &str) -> Result<T> {
+    field.parse::<T>().map_err(|_| Error::PartedNumberParse(line.to_string()))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use proptest::prelude::*;
+
+    const CORPUS: [(&str, &str); 8] = [
+        ("emmc_bytes", include_str!("../testdata/parted/emmc_bytes.txt")),
+        ("lv_gpt_two_volumes_s", include_str!("../testdata/parted/lv_gpt_two_volumes_s.txt")),
+        ("lv_msdos_print_free_s", include_str!("../testdata/parted/lv_msdos_print_free_s.txt")),
+        ("lv_msdos_s", include_str!("../testdata/parted/lv_msdos_s.txt")),
+        ("lv_unlabelled_s", include_str!("../testdata/parted/lv_unlabelled_s.txt")),
+        ("rpi_sd_prepared_s", include_str!("../testdata/parted/rpi_sd_prepared_s.txt")),
+        ("rpi_sd_print_free_mb", include_str!("../testdata/parted/rpi_sd_print_free_mb.txt")),
+        ("usb_ssd_gpt_print_free_s", include_str!("../testdata/parted/usb_ssd_gpt_print_free_s.txt")),
+    ];
+
+    fn corpus(name: &str) -> DiskLayout {
+        let (_, output) = CORPUS.iter().find(|(corpus_name, _)| *corpus_name == name).unwrap();
+        DiskLayout::parse(output).unwrap()
+    }
+
+    fn render(layout: &DiskLayout) -> String {
+        let mut output = format!("BYT;\n{}:{}:{}:{}:{}:{}:{}:;\n",
+            layout.device, layout.size, layout.transport,
+            layout.logical_sector_size, layout.physical_sector_size, layout.table.label(), layout.model);
+        for free in &layout.free {
+            output += &format!("1:{}:{}:{}:free;\n", free.start, free.end, free.size);
+        }
+        for partition in &layout.partitions {
+            output += &format!("{}:{}:{}:{}:{}:{}:{};\n",
+                partition.number, partition.start, partition.end, partition.size,
+                partition.file_system, partition.name, partition.flags.join(", "));
+        }
+        output
+    }
+
+    #[test]
+    fn test_parse_corpus() {
+        for (name, output) in CORPUS.iter() {
+            let layout = DiskLayout::parse(output)
+                .unwrap_or_else(|err| panic!("{}: {}", name, err));
+            assert!(layout.device.starts_with("/dev/"), "{}", name);
+            assert_eq!(layout.logical_sector_size, 512, "{}", name);
+        }
+    }
+
+    #[test]
+    fn test_partition_device() {
+        assert_eq!(partition_device("/dev/sda", 3), "/dev/sda3");
+        assert_eq!(partition_device("/dev/mmcblk0", 3), "/dev/mmcblk0p3");
+        assert_eq!(partition_device("/dev/nvme0n1", 2), "/dev/nvme0n1p2");
+        assert_eq!(partition_device("/dev/disk/by-id/usb-Samsung_SSD_T7-0:0", 3), "/dev/disk/by-id/usb-Samsung_SSD_T7-0:0-part3");
+    }
+
+    #[test]
+    fn test_parse_lv_msdos() {
+        let layout = corpus("lv_msdos_s");
+        assert_eq!(layout.device, "/dev/dm-4");
+        assert_eq!(layout.size, Size::parse("30900224s").unwrap());
+        assert_eq!(layout.table, PartitionTable::Msdos);
+        assert_eq!(layout.partitions, vec![Partition {
+            number: 1,
+            start: Size::parse("8192s").unwrap(),
+            end: Size::parse("30900223s").unwrap(),
+            size: Size::parse("30892032s").unwrap(),
+            file_system: "".to_string(),
+            name: "".to_string(),
+            flags: vec!["lba".to_string()],
+        }]);
+        assert!(layout.free.is_empty());
+    }
+
+    #[test]
+    fn test_parse_rpi_sd_free() {
+        let layout = corpus("rpi_sd_print_free_mb");
+        assert_eq!(layout.model, "SD ACLCD");
+        assert_eq!(layout.partitions.len(), 2);
+        assert_eq!(layout.partition(1).unwrap().flags, vec!["boot", "lba"]);
+        assert_eq!(layout.partition(2).unwrap().file_system, "ext3");
+        let last_free = layout.free.last().unwrap();
+        assert_eq!(last_free.start, Size::parse("201MB").unwrap());
+        assert_eq!(last_free.end, Size::parse("31915MB").unwrap());
+    }
+
+    #[test]
+    fn test_parse_gpt() {
+        let layout = corpus("usb_ssd_gpt_print_free_s");
+        assert_eq!(layout.table, PartitionTable::Gpt);
+        assert_eq!(layout.physical_sector_size, 4096);
+        assert_eq!(layout.partition(1).unwrap().name, "EFI System Partition");
+        assert_eq!(layout.partition(1).unwrap().flags, vec!["boot", "esp"]);
+        assert_eq!(layout.free.len(), 2);
+    }
+
+    #[test]
+    fn test_parse_unlabelled() {
+        let layout = corpus("lv_unlabelled_s");
+        assert_eq!(layout.table, PartitionTable::Unknown);
+        assert!(layout.partitions.is_empty());
+    }
+
+    #[test]
+    fn test_parse_rejects_chs() {
+        match DiskLayout::parse("CHS;\n/dev/sda:1023,254,63:scsi:512:512:msdos:Disk:;\n") {
+            Err(Error::PartedUnitsNotSupported(header)) => assert_eq!(header, "CHS;"),
+            _ => panic!("expected PartedUnitsNotSupported"),
+        }
+    }
+
+    #[test]
+    fn test_parse_rejects_truncated_partition() {
+        match DiskLayout::parse("BYT;\n/dev/sda:100s:scsi:512:512:msdos:Disk:;\n1:8s:99s;\n") {
+            Err(Error::PartedFieldsNotFound(line)) => assert_eq!(line, "1:8s:99s;"),
+            _ => panic!("expected PartedFieldsNotFound"),
+        }
+    }
+
+    #[test]
+    fn test_data_partition_msdos_undetected() {
+        let layout = corpus("lv_msdos_s");
+        assert_eq!(layout.data_partition().unwrap().number, 1);
+    }
+
+    #[test]
+    fn test_data_partition_gpt_skips_esp() {
+        let layout = corpus("usb_ssd_gpt_print_free_s");
+        assert!(layout.data_partition().is_err());
+    }
+
+    #[test]
+    fn test_data_partition_gpt() {
+        let layout = DiskLayout::parse("
+            BYT;
+            /dev/dm-3:31457280s:unknown:512:512:gpt:Linux device-mapper (linear):;
+            1:34s:32767s:32734s:::msftres;
+            2:32768s:31455231s:31422464s:exfat:Basic data partition:msftdata;
+        ").unwrap();
+        assert_eq!(layout.data_partition().unwrap().number, 2);
+    }
+
+    #[test]
+    fn test_data_partitions_two_volumes() {
+        let layout = corpus("lv_gpt_two_volumes_s");
+        let numbers = layout.data_partitions().unwrap().iter()
+            .map(|partition| partition.number)
+            .collect::<Vec<u32>>();
+        assert_eq!(numbers, vec![1, 2]);
+    }
+
+    #[test]
+    fn test_data_partition_none() {
+        assert!(corpus("lv_unlabelled_s").data_partition().is_err());
+    }
+
+    fn mbr_with_type(partition_type: u8) -> Vec<u8> {
+        let mut sector = vec![0u8; 512];
+        sector[0x1c2] = partition_type;
+        sector[510] = 0x55;
+        sector[511] = 0xaa;
+        sector
+    }
+
+    #[test]
+    fn test_is_protective_mbr() {
+        assert!(is_protective_mbr(&mbr_with_type(0xee)));
+        assert!(!is_protective_mbr(&mbr_with_type(0x0c)));
+        assert!(!is_protective_mbr(&[0u8; 512]));
+        assert!(!is_protective_mbr(&[0u8; 16]));
+    }
+
+    fn value_strategy() -> BoxedStrategy<Size> {
+        (any::<u32>(), prop::sample::select(vec![Unit::Sectors, Unit::Bytes, Unit::Kilobytes, Unit::Megabytes, Unit::Gigabytes, Unit::Mebibytes, Unit::Percent]))
+            .prop_map(|(value, unit)| Size::new(value as f64, unit))
+            .boxed()
+    }
+
+    fn text_strategy() -> BoxedStrategy<String> {
+        "[A-Za-z0-9 ()._-]{0,24}".prop_map(|text| text.trim().to_string()).boxed()
+    }
+
+    prop_compose! {
+        fn partition_strategy()(
+            number in 1u32..128,
+            start in value_strategy(),
+            end in value_strategy(),
+            size in value_strategy(),
+            file_system in prop::sample::select(vec!["", "fat16", "fat32", "ext4", "ntfs", "linux-swap(v1)"]),
+            name in text_strategy(),
+            flags in prop::collection::vec(prop::sample::select(vec!["boot", "lba", "esp", "lvm", "msftdata"]), 0..3),
+        ) -> Partition {
+            Partition {
+                number,
+                start,
+                end,
+                size,
+                file_system: file_system.to_string(),
+                name,
+                flags: flags.iter().map(|flag| flag.to_string()).collect(),
+            }
+        }
+    }
+
+    prop_compose! {
+        fn layout_strategy()(
+            device in "/dev/[a-z0-9/_-]{1,20}",
+            size in value_strategy(),
+            transport in prop::sample::select(vec!["scsi", "sd/mmc", "unknown", "nvme"]),
+            logical_sector_size in prop::sample::select(vec![512u64, 4096]),
+            physical_sector_size in prop::sample::select(vec![512u64, 4096]),
+            table in prop::sample::select(vec![PartitionTable::Msdos, PartitionTable::Gpt, PartitionTable::Loop, PartitionTable::Unknown]),
+            model in text_strategy(),
+            partitions in prop::collection::vec(partition_strategy(), 0..6),
+            free in prop::collection::vec(
+                (value_strategy(), value_strategy(), value_strategy())
+                    .prop_map(|(start, end, size)| FreeRegion { start, end, size }),
+                0..3),
+        ) -> DiskLayout {
+            DiskLayout {
+                device,
+                size,
+                transport: transport.to_string(),
+                logical_sector_size,
+                physical_sector_size,
+                table,
+                model,
+                partitions,
+                free,
+            }
+        }
+    }
+
+    proptest! {
+        #[test]
+        fn test_parse_rendered_layout(layout in layout_strategy()) {
+            prop_assert_eq!(DiskLayout::parse(&render(&layout)).unwrap(), layout);
+        }
+
+        #[test]
+        fn test_parse_corpus_whitespace_insensitive(indent in "[ \t]{0,8}", index in 0..CORPUS.len()) {
+            let (_, output) = CORPUS[index];
+            let indented = output.lines()
+                .map(|line| format!("{}{}", indent, line))
+                .collect::<Vec<String>>()
+                .join("\n");
+            prop_assert_eq!(DiskLayout::parse(&indented).unwrap(), DiskLayout::parse(output).unwrap());
+        }
+    }
+}
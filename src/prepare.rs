@@ -0,0 +1,449 @@
+use std::fs::{self, OpenOptions};
+use std::io::{self, Write};
+use std::path::{Path, PathBuf};
+use std::process::Command;
+use upload_command::{Error, Result, command_line, command_stdout};
+
+// A single change made while preparing the storage. Each step can tell whether
+// its result already exists, so that an interrupted prepare can simply be rerun.
+pub struct Step<'a> {
+    // Identifies the step in the journal. Unlike the description, it does not depend on the
+    // configuration, so a journal still matches after the configuration changes.
+    pub id: &'static str,
+    pub description: String,
+    is_done: Box<dyn Fn() -> Result<bool> + 'a>,
+    action: Action<'a>,
+    undo: Option<Box<dyn Fn() -> Result<()> + 'a>>,
+    is_estimate: Option<Box<dyn Fn() -> Result<bool> + 'a>>,
+    journal_start: bool,
+}
+
+enum Action<'a> {
+    // Built once each time the step runs, so that the command shown is the one that runs
+    Command(Box<dyn Fn() -> Result<Command> + 'a>),
+    Custom {
+        run: Box<dyn Fn() -> Result<()> + 'a>,
+        plan: Option<Box<dyn Fn() -> Result<String> + 'a>>,
+    },
+}
+
+impl<'a> Step<'a> {
+    pub fn new<D, R>(id: &'static str, description: &str, is_done: D, run: R) -> Step<'a>
+        where D: Fn() -> Result<bool> + 'a, R: Fn() -> Result<()> + 'a
+    {
+        Step {
+            id,
+            description: description.to_string(),
+            is_done: Box::new(is_done),
+            action: Action::Custom { run: Box::new(run), plan: None },
+            undo: None,
+            is_estimate: None,
+            journal_start: false,
+        }
+    }
+
+    // A step running one command, which is also what the plan shows
+    pub fn command<D, C>(id: &'static str, description: &str, is_done: D, make_command: C) -> Step<'a>
+        where D: Fn() -> Result<bool> + 'a, C: Fn() -> Result<Command> + 'a
+    {
+        let mut step = Step::new(id, description, is_done, || Ok(()));
+        step.action = Action::Command(Box::new(make_command));
+        step
+    }
+
+    // Details of what a custom step will do, computed from the current state of the device.
+    // Command steps show their command instead.
+    pub fn with_plan<P>(mut self, plan: P) -> Step<'a>
+        where P: Fn() -> Result<String> + 'a
+    {
+        if let Action::Custom { plan: ref mut step_plan, .. } = self.action {
+            *step_plan = Some(Box::new(plan));
+        }
+        self
+    }
+
+    // Whether the plan is based on predicted rather than actual sizes, because what the
+    // step allocates from does not exist before the earlier steps have run
+    pub fn with_estimate<E>(mut self, is_estimate: E) -> Step<'a>
+        where E: Fn() -> Result<bool> + 'a
+    {
+        self.is_estimate = Some(Box::new(is_estimate));
+        self
+    }
+
+    // Reverts the step when a later step fails
+    pub fn with_undo<U>(mut self, undo: U) -> Step<'a>
+        where U: Fn() -> Result<()> + 'a
+    {
+        self.undo = Some(Box::new(undo));
+        self
+    }
+
+    // For steps whose result looks complete before it is, such as mkfs writing the file system
+    // signature first. The start is journalled too, and an interrupted run is not taken as done.
+    pub fn with_journalled_start(mut self) -> Step<'a> {
+        self.journal_start = true;
+        self
+    }
+
+    pub fn with_undo_command<C>(self, make_command: C) -> Step<'a>
+        where C: Fn() -> Result<Command> + 'a
+    {
+        self.with_undo(move || command_stdout(&mut make_command()?).map(|_| ()))
+    }
+
+    pub fn plan(&self) -> Result<Option<String>> {
+        match self.action {
+            Action::Command(ref make_command) => Ok(Some(command_line(&make_command()?))),
+            Action::Custom { plan: Some(ref plan), .. } => plan().map(Some),
+            Action::Custom { plan: None, .. } => Ok(None),
+        }
+    }
+
+    fn is_estimate(&self) -> Result<bool> {
+        match self.is_estimate {
+            Some(ref is_estimate) => is_estimate(),
+            None => Ok(false),
+        }
+    }
+
+    pub fn is_done(&self) -> Result<bool> {
+        (self.is_done)()
+    }
+
+    fn is_complete(&self, journal: &Journal) -> Result<bool> {
+        if self.journal_start && journal.is_unfinished(self.id)? {
+            return Ok(false);
+        }
+        self.is_done()
+    }
+
+    // Shows the plan, then runs the step
+    fn execute(&self) -> Result<()> {
+        match self.action {
+            Action::Command(ref make_command) => {
+                let mut command = make_command()?;
+                println!("  {}", command_line(&command));
+                command_stdout(&mut command).map(|_| ())
+            },
+            Action::Custom { ref run, ref plan } => {
+                if let Some(ref plan) = *plan {
+                    println!("  {}", plan()?);
+                }
+                run()
+            },
+        }
+    }
+}
+
+// Steps completed by a prepare that has not yet finished, kept on disk so that
+// steps done before an interruption are rolled back too
+pub struct Journal {
+    path: PathBuf,
+}
+
+impl Journal {
+    pub fn new(path: &Path) -> Journal {
+        Journal { path: path.to_path_buf() }
+    }
+
+    pub fn entries(&self) -> Result<Vec<String>> {
+        match fs::read_to_string(&self.path) {
+            Ok(contents) => Ok(contents.lines().map(String::from).collect()),
+            Err(ref err) if err.kind() == io::ErrorKind::NotFound => Ok(Vec::new()),
+            Err(err) => Err(Error::PrepareJournal(err)),
+        }
+    }
+
+    pub fn record(&self, id: &str) -> Result<()> {
+        if let Some(parent) = self.path.parent() {
+            fs::create_dir_all(parent).map_err(Error::PrepareJournal)?;
+        }
+        let mut file = OpenOptions::new().create(true).append(true).open(&self.path)
+            .map_err(Error::PrepareJournal)?;
+        writeln!(file, "{}", id).map_err(Error::PrepareJournal)?;
+        file.sync_all().map_err(Error::PrepareJournal)
+    }
+
+    // Started but never recorded as finished
+    fn is_unfinished(&self, id: &str) -> Result<bool> {
+        let entries = self.entries()?;
+        Ok(entries.contains(&started_entry(id)) && !entries.iter().any(|entry| entry == id))
+    }
+
+    pub fn clear(&self) -> Result<()> {
+        match fs::remove_file(&self.path) {
+            Ok(()) => Ok(()),
+            Err(ref err) if err.kind() == io::ErrorKind::NotFound => Ok(()),
+            Err(err) => Err(Error::PrepareJournal(err)),
+        }
+    }
+}
+
+fn started_entry(id: &str) -> String {
+    format!("started {}", id)
+}
+
+pub fn run_steps(steps: &[Step], journal: &Journal) -> Result<()> {
+    for step in steps {
+        if step.is_complete(journal)? {
+            println!("Already done: {}", step.description);
+            continue;
+        }
+        println!("{}", step.description);
+        if step.journal_start {
+            journal.record(&started_entry(step.id))?;
+        }
+        step.execute()?;
+        journal.record(step.id)?;
+    }
+    Ok(())
+}
+
+// Undoes the journalled steps in reverse, then forgets them. Steps whose result
+// is already gone are skipped, so an interrupted rollback can be rerun. A step
+// that fails does not stop the others from being undone, but the journal is kept
+// and the first error returned, so that the rollback can be run again.
+pub fn rollback(steps: &[Step], journal: &Journal) -> Result<()> {
+    let entries = journal.entries()?;
+    let mut first_error = None;
+    for step in steps.iter().rev() {
+        let undo = match step.undo {
+            Some(ref undo) => undo,
+            None => continue,
+        };
+        if !entries.iter().any(|entry| entry == step.id) {
+            continue;
+        }
+        let result = step.is_done().and_then(|is_done| {
+            if is_done {
+                println!("Undoing: {}", step.description);
+                undo()?;
+            }
+            Ok(())
+        });
+        if let Err(err) = result {
+            println!("Failed to undo {}: {}", step.description, err);
+            first_error = first_error.or(Some(err));
+        }
+    }
+    match first_error {
+        Some(err) => Err(err),
+        None => journal.clear(),
+    }
+}
+
+// Prints what run_steps would do without changing anything
+pub fn print_plan(steps: &[Step], journal: &Journal) -> Result<()> {
+    for step in steps {
+        if step.is_complete(journal)? {
+            println!("Already done: {}", step.description);
+            continue;
+        }
+        println!("Would run: {}", step.description);
+        if let Some(plan) = step.plan()? {
+            println!("  {}", plan);
+        }
+        if step.is_estimate()? {
+            println!("  (sizes are estimated until the earlier steps have run)");
+        }
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::cell::RefCell;
+    use test_util::TempDir;
+
+    fn journal(dir: &Path) -> Journal {
+        Journal::new(&dir.join("prepare.journal"))
+    }
+
+    type Log = RefCell<Vec<&'static str>>;
+
+    // Each step is done once it has run and until it is undone, as with the real commands
+    fn tracked_step<'a>(name: &'static str, done: &'a Log, undone: &'a Log) -> Step<'a> {
+        Step::new(name, name, move || Ok(done.borrow().contains(&name)), move || { done.borrow_mut().push(name); Ok(()) })
+            .with_undo(move || {
+                done.borrow_mut().retain(|step| *step != name);
+                undone.borrow_mut().push(name);
+                Ok(())
+            })
+    }
+
+    #[test]
+    fn test_run_steps_skips_done() {
+        let ran = RefCell::new(Vec::new());
+        let steps = vec![
+            Step::new("first", "first", || Ok(true), || { ran.borrow_mut().push("first"); Ok(()) }),
+            Step::new("second", "second", || Ok(false), || { ran.borrow_mut().push("second"); Ok(()) }),
+        ];
+        let dir = TempDir::new("prepare_skips_done");
+        let journal = journal(&dir);
+        run_steps(&steps, &journal).unwrap();
+        assert_eq!(*ran.borrow(), vec!["second"]);
+        assert_eq!(journal.entries().unwrap(), vec!["second"]);
+    }
+
+    #[test]
+    fn test_print_plan_runs_nothing() {
+        let ran = RefCell::new(false);
+        let steps = vec![
+            Step::new("change", "change", || Ok(false), || { *ran.borrow_mut() = true; Ok(()) })
+                .with_plan(|| Ok("details".to_string())),
+            Step::command("command", "command", || Ok(false), || {
+                let mut command = Command::new("touch");
+                command.arg("/nonexistent/upload-stick-plan");
+                Ok(command)
+            }),
+        ];
+        let dir = TempDir::new("prepare_plan");
+        print_plan(&steps, &journal(&dir)).unwrap();
+        assert!(!*ran.borrow());
+        assert_eq!(steps[0].plan().unwrap(), Some("details".to_string()));
+        assert_eq!(steps[1].plan().unwrap(), Some("touch /nonexistent/upload-stick-plan".to_string()));
+    }
+
+    #[test]
+    fn test_run_steps_resumes() {
+        // Each step is done once it has run, as with the real commands
+        let ran = RefCell::new(Vec::new());
+        let fail_second = RefCell::new(true);
+        let steps = vec![
+            Step::new("first", "first", || Ok(ran.borrow().contains(&"first")), || { ran.borrow_mut().push("first"); Ok(()) }),
+            Step::new("second", "second", || Ok(ran.borrow().contains(&"second")), || {
+                if fail_second.replace(false) {
+                    return Err(::upload_command::Error::InvalidArgument("power loss".to_string()));
+                }
+                ran.borrow_mut().push("second");
+                Ok(())
+            }),
+        ];
+        let dir = TempDir::new("prepare_resumes");
+        let journal = journal(&dir);
+        assert!(run_steps(&steps, &journal).is_err());
+        run_steps(&steps, &journal).unwrap();
+        assert_eq!(*ran.borrow(), vec!["first", "second"]);
+        assert_eq!(journal.entries().unwrap(), vec!["first", "second"]);
+    }
+
+    #[test]
+    fn test_run_steps_builds_command_once() {
+        let built = RefCell::new(0);
+        let steps = vec![
+            Step::command("command", "command", || Ok(false), || {
+                *built.borrow_mut() += 1;
+                Ok(Command::new("true"))
+            }),
+        ];
+        let dir = TempDir::new("prepare_builds_command_once");
+        let journal = journal(&dir);
+        run_steps(&steps, &journal).unwrap();
+        assert_eq!(*built.borrow(), 1);
+    }
+
+    #[test]
+    fn test_run_steps_journalled_start() {
+        // Like mkfs, the result looks done as soon as the step has started
+        let started = RefCell::new(false);
+        let finished = RefCell::new(false);
+        let fail = RefCell::new(true);
+        let steps = vec![
+            Step::new("mkfs", "mkfs", || Ok(*started.borrow()), || {
+                *started.borrow_mut() = true;
+                if fail.replace(false) {
+                    return Err(::upload_command::Error::InvalidArgument("power loss".to_string()));
+                }
+                *finished.borrow_mut() = true;
+                Ok(())
+            }).with_journalled_start(),
+        ];
+        let dir = TempDir::new("prepare_journalled_start");
+        let journal = journal(&dir);
+        assert!(run_steps(&steps, &journal).is_err());
+        assert!(!steps[0].is_complete(&journal).unwrap());
+        run_steps(&steps, &journal).unwrap();
+        assert!(*finished.borrow());
+        assert!(steps[0].is_complete(&journal).unwrap());
+        journal.clear().unwrap();
+        assert!(steps[0].is_complete(&journal).unwrap());
+    }
+
+    #[test]
+    fn test_rollback() {
+        let done = RefCell::new(vec!["existing"]);
+        let undone = RefCell::new(Vec::new());
+        let steps = vec![
+            tracked_step("existing", &done, &undone),
+            tracked_step("partition", &done, &undone),
+            tracked_step("pv", &done, &undone),
+            Step::new("resize", "resize", || Ok(false), || Ok(())),
+            tracked_step("vg", &done, &undone),
+            Step::new("lv", "lv", || Ok(false), || Err(::upload_command::Error::InvalidArgument("lvcreate".to_string()))),
+        ];
+
+        let dir = TempDir::new("prepare_rollback");
+        let journal = journal(&dir);
+        assert!(run_steps(&steps, &journal).is_err());
+        rollback(&steps, &journal).unwrap();
+        assert_eq!(*undone.borrow(), vec!["vg", "pv", "partition"]);
+        assert_eq!(*done.borrow(), vec!["existing"]);
+        assert!(journal.entries().unwrap().is_empty());
+    }
+
+    #[test]
+    fn test_rollback_after_interruption() {
+        let done = RefCell::new(Vec::new());
+        let undone = RefCell::new(Vec::new());
+        let steps = vec![tracked_step("partition", &done, &undone), tracked_step("pv", &done, &undone)];
+        let dir = TempDir::new("prepare_interrupted");
+        let journal = journal(&dir);
+        run_steps(&steps, &journal).unwrap();
+        // An interrupted rollback already removed the PV
+        done.borrow_mut().retain(|step| *step != "pv");
+        rollback(&steps, &journal).unwrap();
+        assert_eq!(*undone.borrow(), vec!["partition"]);
+        assert!(done.borrow().is_empty());
+        assert!(journal.entries().unwrap().is_empty());
+    }
+
+    #[test]
+    fn test_rollback_continues_after_failure() {
+        let done = RefCell::new(Vec::new());
+        let undone = RefCell::new(Vec::new());
+        let steps = vec![
+            tracked_step("partition", &done, &undone),
+            Step::new("pv", "Making PV on /dev/mmcblk0p3", || Err(::upload_command::Error::InvalidArgument("pvs".to_string())), || Ok(()))
+                .with_undo(|| Ok(())),
+            tracked_step("vg", &done, &undone),
+        ];
+        let dir = TempDir::new("prepare_rollback_failure");
+        let journal = journal(&dir);
+        run_steps(&steps, &journal).unwrap_err();
+        journal.record("pv").unwrap();
+        journal.record("vg").unwrap();
+        done.borrow_mut().push("vg");
+
+        assert!(rollback(&steps, &journal).is_err());
+        assert_eq!(*undone.borrow(), vec!["vg", "partition"]);
+        // Kept so that the failed step is tried again
+        assert_eq!(journal.entries().unwrap(), vec!["partition", "pv", "vg"]);
+    }
+
+    #[test]
+    fn test_rollback_matches_ids() {
+        let done = RefCell::new(Vec::new());
+        let undone = RefCell::new(Vec::new());
+        let dir = TempDir::new("prepare_rollback_ids");
+        let journal = journal(&dir);
+        run_steps(&[tracked_step("image", &done, &undone)], &journal).unwrap();
+        // The description depends on the configuration, which changed since
+        let steps = vec![Step::new("image", "Creating image /srv/stick.img of 16GiB",
+            || Ok(done.borrow().contains(&"image")), || Ok(()))
+            .with_undo(|| { undone.borrow_mut().push("image"); Ok(()) })];
+        rollback(&steps, &journal).unwrap();
+        assert_eq!(*undone.borrow(), vec!["image"]);
+    }
+}
@@ -0,0 +1,263 @@
+use std::fs::{self, File};
+use std::io::Write;
+use std::path::{Path, PathBuf};
+use std::process::Command;
+use std::time::{Duration, SystemTime};
+use chrono::{DateTime, Local, TimeZone, Utc};
+use config::RetentionConfig;
+use file_system::{self, FileSystemType};
+use upload_command::{CommandCheck, Error, MapMode, MappedPartition, Result, command_stdout, map_device_partitions, unmap_partition};
+use upload_db;
+
+const SECONDS_PER_DAY: u64 = 24 * 60 * 60;
+
+// A file the upload database confirms was uploaded and verified
+#[derive(Debug, Clone, PartialEq)]
+pub struct Candidate {
+    pub path: PathBuf,
+    pub len: u64,
+    pub modified: SystemTime,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct Usage {
+    pub size: u64,
+    pub used: u64,
+}
+
+fn report_dir() -> PathBuf {
+    PathBuf::from("/var/lib/upload-stick/cleanup")
+}
+
+pub fn report_path(partition: u32) -> PathBuf {
+    report_dir().join(format!("partition{}.txt", partition))
+}
+
+const CLEAN_PARTITION_PREFIX: &str = "mass_storage_clean_partition";
+
+fn clean_mount_path(number: u32) -> PathBuf {
+    PathBuf::from("/mnt").join(format!("clean{}", number))
+}
+
+// No host may see the volume while it changes, so the gadget must not export it
+pub fn clean_volume(origin: &str, retention: &RetentionConfig) -> Result<usize> {
+    let mode = if retention.dry_run { MapMode::ReadOnly } else { MapMode::ReadWrite };
+    let partitions = map_device_partitions(origin, CLEAN_PARTITION_PREFIX, mode)?;
+
+    let mut result = Ok(0);
+    for partition in &partitions {
+        if let Ok(deleted) = result {
+            result = clean_partition(partition, retention).map(|partition_deleted| deleted + partition_deleted);
+        }
+    }
+    for partition in &partitions {
+        unmap_partition(&partition.mapped_name, CommandCheck::Retry {
+            count: 5,
+            interval: Duration::from_secs(3)
+        })?;
+    }
+    result
+}
+
+fn clean_partition(partition: &MappedPartition, retention: &RetentionConfig) -> Result<usize> {
+    let file_system = match file_system::detect(&partition.device_path())? {
+        Some(file_system) => file_system,
+        None => {
+            println!("Skipping cleanup of partition {}: no supported file system", partition.number);
+            return Ok(0);
+        },
+    };
+
+    let mount_path = clean_mount_path(partition.number);
+    fs::create_dir_all(&mount_path)
+        .map_err(Error::IteratingDirectory)?;
+    let options = if retention.dry_run { file_system.mount_options() } else { file_system.writable_mount_options() };
+    command_stdout(
+        Command::new("mount")
+            .arg("-t").arg(file_system.mount_type())
+            .arg("-o").arg(options)
+            .arg(partition.device_path())
+            .arg(&mount_path)
+    )?;
+
+    let result = clean(partition.number, &mount_path, file_system, retention);
+    command_stdout(Command::new("umount").arg(&mount_path))?;
+    result.map(|deleted| {
+        println!("Cleaned {} uploaded files from partition {}", deleted.len(), partition.number);
+        deleted.len()
+    })
+}
+
+// Deletes the files selected by the policy from a mounted partition, or only reports them with dry_run.
+// Each file is added to the report as soon as it is gone, so the report is complete even when a
+// later deletion fails.
+pub fn clean(partition: u32, mount_path: &Path, file_system: FileSystemType, config: &RetentionConfig) -> Result<Vec<Candidate>> {
+    let selected = select(find_candidates(partition, mount_path, file_system)?, config, usage(mount_path)?, SystemTime::now());
+
+    fs::create_dir_all(report_dir()).map_err(Error::Cleanup)?;
+    let mut report = File::create(report_path(partition)).map_err(Error::Cleanup)?;
+    let action = if config.dry_run { "Would delete" } else { "Deleted" };
+    for candidate in &selected {
+        if !config.dry_run {
+            fs::remove_file(&candidate.path).map_err(Error::Cleanup)?;
+        }
+        let line = format!("{} {} ({} bytes)", action, candidate.path.display(), candidate.len);
+        println!("{}", line);
+        writeln!(report, "{}", line).map_err(Error::Cleanup)?;
+    }
+    Ok(selected)
+}
+
+fn find_candidates(partition: u32, mount_path: &Path, file_system: FileSystemType) -> Result<Vec<Candidate>> {
+    let mut candidates = Vec::new();
+    find_candidates_in(partition, mount_path, file_system, Path::new(""), &mut candidates)?;
+    Ok(candidates)
+}
+
+// FAT records the host's local time without a zone. Mounted with tz=UTC, the kernel reports those
+// fields unchanged as UTC, so they are reinterpreted in the local time zone here.
+fn fat_modified<Tz: TimeZone>(modified: SystemTime, time_zone: &Tz) -> SystemTime {
+    let fields = DateTime::<Utc>::from(modified).naive_utc();
+    // A time skipped by a daylight saving change has no local equivalent
+    time_zone.from_local_datetime(&fields).earliest()
+        .map_or(modified, |local| SystemTime::from(local.with_timezone(&Utc)))
+}
+
+// Files in folders are recorded by their path relative to the volume root
+fn find_candidates_in(partition: u32, mount_path: &Path, file_system: FileSystemType, dir: &Path,
+        candidates: &mut Vec<Candidate>) -> Result<()> {
+    for dir_entry in fs::read_dir(mount_path.join(dir)).map_err(Error::IteratingDirectory)? {
+        let dir_entry = dir_entry.map_err(Error::IteratingDirectory)?;
+        let metadata = dir_entry.metadata().map_err(Error::IteratingDirectory)?;
+        let path = dir.join(dir_entry.file_name());
+        if metadata.is_dir() {
+            find_candidates_in(partition, mount_path, file_system, &path, candidates)?;
+            continue;
+        }
+        if !metadata.is_file() {
+            continue;
+        }
+        let entry = upload_db::new_entry(partition, &path, metadata.len());
+        if upload_db::is_verified(&entry).map_err(Error::UploadDb)? {
+            let modified = metadata.modified().map_err(Error::IteratingDirectory)?;
+            candidates.push(Candidate {
+                path: dir_entry.path(),
+                len: metadata.len(),
+                modified: match file_system {
+                    FileSystemType::Fat => fat_modified(modified, &Local),
+                    FileSystemType::Exfat | FileSystemType::Ntfs => modified,
+                },
+            });
+        }
+    }
+    Ok(())
+}
+
+// Oldest first: everything past keep_days, then more until the volume is at most max_percent full
+pub fn select(mut candidates: Vec<Candidate>, config: &RetentionConfig, usage: Usage, now: SystemTime) -> Vec<Candidate> {
+    candidates.sort_by_key(|candidate| candidate.modified);
+    let mut used = usage.used;
+    let mut selected = Vec::new();
+    for candidate in candidates {
+        let expired = config.keep_days.is_some_and(|days| {
+            now.duration_since(candidate.modified)
+                .is_ok_and(|age| age > Duration::from_secs(days * SECONDS_PER_DAY))
+        });
+        let too_full = config.max_percent.is_some_and(|percent| used * 100 > usage.size * percent);
+        if expired || too_full {
+            used = used.saturating_sub(candidate.len);
+            selected.push(candidate);
+        }
+    }
+    selected
+}
+
+fn usage(mount_path: &Path) -> Result<Usage> {
+    df_find_usage(&command_stdout(
+        Command::new("df")
+            .arg("--block-size=1")
+            .arg("--output=size,used")
+            .arg(mount_path)
+    )?)
+}
+
+fn df_find_usage(df_output: &str) -> Result<Usage> {
+    let fields = df_output.lines().nth(1)
+        .map(|line| line.split_whitespace().map(|field| field.parse::<u64>()).collect::<Vec<_>>())
+        .ok_or_else(|| Error::DfParse(df_output.to_string()))?;
+    match fields.as_slice() {
+        [Ok(size), Ok(used)] => Ok(Usage { size: *size, used: *used }),
+        _ => Err(Error::DfParse(df_output.to_string())),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const GIB: u64 = 1 << 30;
+
+    fn candidate(name: &str, days_old: u64, len: u64, now: SystemTime) -> Candidate {
+        Candidate {
+            path: PathBuf::from("/mnt/clean1").join(name),
+            len,
+            modified: now - Duration::from_secs(days_old * SECONDS_PER_DAY + 60),
+        }
+    }
+
+    fn names(selected: &[Candidate]) -> Vec<String> {
+        selected.iter().map(|candidate| candidate.path.file_name().unwrap().to_string_lossy().to_string()).collect()
+    }
+
+    #[test]
+    fn test_select_keep_days() {
+        let now = SystemTime::now();
+        let candidates = vec![candidate("NEW.WAV", 2, GIB, now), candidate("OLD.WAV", 40, GIB, now), candidate("MID.WAV", 30, GIB, now)];
+        let config = RetentionConfig { keep_days: Some(30), ..RetentionConfig::default() };
+        let usage = Usage { size: 10 * GIB, used: 9 * GIB };
+        assert_eq!(names(&select(candidates, &config, usage, now)), vec!["OLD.WAV", "MID.WAV"]);
+    }
+
+    #[test]
+    fn test_select_max_percent() {
+        let now = SystemTime::now();
+        let candidates = vec![candidate("C.WAV", 1, GIB, now), candidate("A.WAV", 3, GIB, now), candidate("B.WAV", 2, GIB, now)];
+        let config = RetentionConfig { max_percent: Some(70), ..RetentionConfig::default() };
+        let usage = Usage { size: 10 * GIB, used: 9 * GIB };
+        assert_eq!(names(&select(candidates.clone(), &config, usage, now)), vec!["A.WAV", "B.WAV"]);
+
+        // Files that are not uploaded also take space, so everything uploaded may not be enough
+        let usage = Usage { size: 10 * GIB, used: 10 * GIB };
+        assert_eq!(names(&select(candidates.clone(), &config, usage, now)).len(), 3);
+
+        let usage = Usage { size: 10 * GIB, used: 5 * GIB };
+        assert!(select(candidates, &config, usage, now).is_empty());
+    }
+
+    #[test]
+    fn test_select_disabled() {
+        let now = SystemTime::now();
+        let candidates = vec![candidate("OLD.WAV", 400, GIB, now)];
+        let usage = Usage { size: 10 * GIB, used: 10 * GIB };
+        assert!(select(candidates, &RetentionConfig::default(), usage, now).is_empty());
+    }
+
+    #[test]
+    fn test_fat_modified() {
+        use chrono::FixedOffset;
+
+        // Written at 14:30 on a host two hours ahead of UTC
+        let fields = Utc.with_ymd_and_hms(2024, 6, 1, 14, 30, 0).unwrap();
+        let time_zone = FixedOffset::east_opt(2 * 60 * 60).unwrap();
+        assert_eq!(fat_modified(SystemTime::from(fields), &time_zone),
+            SystemTime::from(Utc.with_ymd_and_hms(2024, 6, 1, 12, 30, 0).unwrap()));
+        assert_eq!(fat_modified(SystemTime::from(fields), &Utc), SystemTime::from(fields));
+    }
+
+    #[test]
+    fn test_df_find_usage() {
+        let usage = df_find_usage("    1B-blocks         Used\n 31898796032 12083359744\n").unwrap();
+        assert_eq!(usage, Usage { size: 31898796032, used: 12083359744 });
+        assert!(df_find_usage("df: /mnt/clean1: No such file or directory\n").is_err());
+    }
+}
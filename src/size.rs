@@ -0,0 +1,195 @@
+use std::fmt;
+use upload_command::{Error, Result};
+
+// Sector size used by device-mapper tables, independent of the device's logical sector size
+pub const DM_SECTOR_SIZE: u64 = 512;
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Unit {
+    Sectors,
+    Bytes,
+    Kilobytes,
+    Megabytes,
+    Gigabytes,
+    Terabytes,
+    Kibibytes,
+    Mebibytes,
+    Gibibytes,
+    Tebibytes,
+    Percent,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct Size {
+    pub value: f64,
+    pub unit: Unit,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
+pub struct Sectors(pub u64);
+
+const UNITS: [(&str, Unit); 11] = [
+    ("s", Unit::Sectors),
+    ("B", Unit::Bytes),
+    ("kB", Unit::Kilobytes),
+    ("MB", Unit::Megabytes),
+    ("GB", Unit::Gigabytes),
+    ("TB", Unit::Terabytes),
+    ("KiB", Unit::Kibibytes),
+    ("MiB", Unit::Mebibytes),
+    ("GiB", Unit::Gibibytes),
+    ("TiB", Unit::Tebibytes),
+    ("%", Unit::Percent),
+];
+
+impl Unit {
+    pub fn parse(suffix: &str) -> Option<Unit> {
+        UNITS.iter().find(|(name, _)| *name == suffix).map(|(_, unit)| *unit)
+    }
+
+    pub fn suffix(self) -> &'static str {
+        UNITS.iter().find(|(_, unit)| *unit == self).map(|(name, _)| *name).unwrap()
+    }
+
+    fn bytes(self, logical_sector_size: u64) -> Option<u64> {
+        match self {
+            Unit::Sectors => Some(logical_sector_size),
+            Unit::Bytes => Some(1),
+            Unit::Kilobytes => Some(1_000),
+            Unit::Megabytes => Some(1_000_000),
+            Unit::Gigabytes => Some(1_000_000_000),
+            Unit::Terabytes => Some(1_000_000_000_000),
+            Unit::Kibibytes => Some(1 << 10),
+            Unit::Mebibytes => Some(1 << 20),
+            Unit::Gibibytes => Some(1 << 30),
+            Unit::Tebibytes => Some(1 << 40),
+            Unit::Percent => None,
+        }
+    }
+}
+
+impl Size {
+    pub fn new(value: f64, unit: Unit) -> Size {
+        Size { value, unit }
+    }
+
+    pub fn parse(string: &str) -> Result<Size> {
+        let string = string.trim();
+        let split = string
+            .find(|c: char| !(c.is_ascii_digit() || c == '.' || c == '-'))
+            .unwrap_or(string.len());
+        let (value, suffix) = string.split_at(split);
+
+        let value = value.parse::<f64>()
+            .map_err(|_| Error::SizeParse(string.to_string()))?;
+        let unit = Unit::parse(suffix)
+            .ok_or_else(|| Error::SizeParse(string.to_string()))?;
+
+        Ok(Size { value, unit })
+    }
+
+    pub fn to_bytes(&self, logical_sector_size: u64) -> Result<u64> {
+        match self.unit.bytes(logical_sector_size) {
+            Some(multiplier) if self.value >= 0.0 => Ok((self.value * multiplier as f64).round() as u64),
+            _ => Err(Error::SizeNotAbsolute(self.to_string())),
+        }
+    }
+
+    pub fn to_sectors(&self, logical_sector_size: u64) -> Result<Sectors> {
+        let bytes = self.to_bytes(logical_sector_size)?;
+        if bytes % DM_SECTOR_SIZE != 0 {
+            return Err(Error::SizeNotSectorAligned(self.to_string()));
+        }
+        Ok(Sectors(bytes / DM_SECTOR_SIZE))
+    }
+
+    pub fn in_unit(&self, unit: Unit, logical_sector_size: u64) -> Result<Size> {
+        let bytes = self.to_bytes(logical_sector_size)?;
+        match unit.bytes(logical_sector_size) {
+            Some(multiplier) => Ok(Size::new(bytes as f64 / multiplier as f64, unit)),
+            None => Err(Error::SizeNotAbsolute(unit.suffix().to_string())),
+        }
+    }
+}
+
+impl fmt::Display for Size {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{}{}", self.value, self.unit.suffix())
+    }
+}
+
+impl fmt::Display for Sectors {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{}", self.0)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use proptest::prelude::*;
+
+    #[test]
+    fn test_parse() {
+        assert_eq!(Size::parse("30892032s").unwrap(), Size::new(30892032.0, Unit::Sectors));
+        assert_eq!(Size::parse("0.02MB").unwrap(), Size::new(0.02, Unit::Megabytes));
+        assert_eq!(Size::parse("4MiB").unwrap(), Size::new(4.0, Unit::Mebibytes));
+        assert_eq!(Size::parse("-1s").unwrap(), Size::new(-1.0, Unit::Sectors));
+        assert_eq!(Size::parse("70%").unwrap(), Size::new(70.0, Unit::Percent));
+        assert!(Size::parse("30892032").is_err());
+        assert!(Size::parse("12XB").is_err());
+        assert!(Size::parse("s").is_err());
+    }
+
+    #[test]
+    fn test_display() {
+        assert_eq!(Size::new(8192.0, Unit::Sectors).to_string(), "8192s");
+        assert_eq!(Size::new(2.0, Unit::Gibibytes).to_string(), "2GiB");
+        assert_eq!(Size::new(-1.0, Unit::Sectors).to_string(), "-1s");
+        assert_eq!(Sectors(8192).to_string(), "8192");
+    }
+
+    #[test]
+    fn test_to_sectors_uses_logical_sector_size() {
+        let size = Size::parse("8192s").unwrap();
+        assert_eq!(size.to_sectors(512).unwrap(), Sectors(8192));
+        assert_eq!(size.to_sectors(4096).unwrap(), Sectors(65536));
+    }
+
+    #[test]
+    fn test_to_sectors_from_bytes() {
+        assert_eq!(Size::parse("4194304B").unwrap().to_sectors(512).unwrap(), Sectors(8192));
+        assert_eq!(Size::parse("4MiB").unwrap().to_sectors(512).unwrap(), Sectors(8192));
+        assert!(Size::parse("4194305B").unwrap().to_sectors(512).is_err());
+    }
+
+    #[test]
+    fn test_to_sectors_rejects_relative() {
+        assert!(Size::parse("-1s").unwrap().to_sectors(512).is_err());
+        assert!(Size::parse("100%").unwrap().to_sectors(512).is_err());
+    }
+
+    #[test]
+    fn test_in_unit() {
+        assert_eq!(Size::parse("201MB").unwrap().in_unit(Unit::Bytes, 512).unwrap(), Size::new(201000000.0, Unit::Bytes));
+        assert_eq!(Size::parse("2GiB").unwrap().in_unit(Unit::Sectors, 512).unwrap(), Size::new(4194304.0, Unit::Sectors));
+        assert!(Size::parse("2GiB").unwrap().in_unit(Unit::Percent, 512).is_err());
+    }
+
+    proptest! {
+        #[test]
+        fn test_sectors_round_trip(sectors in 0u64..(1 << 40), logical_sector_size in prop::sample::select(vec![512u64, 4096])) {
+            let size = Size::new(sectors as f64, Unit::Sectors);
+            let parsed = Size::parse(&size.to_string()).unwrap();
+            prop_assert_eq!(parsed, size);
+            prop_assert_eq!(parsed.to_sectors(logical_sector_size).unwrap(), Sectors(sectors * logical_sector_size / DM_SECTOR_SIZE));
+        }
+
+        #[test]
+        fn test_bytes_conversion_round_trip(mebibytes in 0u64..(1 << 20), unit in prop::sample::select(vec![Unit::Sectors, Unit::Bytes, Unit::Kibibytes, Unit::Mebibytes])) {
+            let size = Size::new(mebibytes as f64, Unit::Mebibytes);
+            let converted = size.in_unit(unit, 512).unwrap();
+            prop_assert_eq!(converted.to_bytes(512).unwrap(), mebibytes << 20);
+        }
+    }
+}
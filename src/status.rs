@@ -0,0 +1,138 @@
+use std::fs;
+use std::io::{Cursor, Write};
+use std::path::{Path, PathBuf};
+use std::process::Command;
+use chrono::{DateTime, Local};
+use fatfs::{self, FileSystem, FormatVolumeOptions, FsOptions};
+use upload_command::{Error, Result, command_stdout};
+use upload_db;
+
+// Roomy enough for the capped upload list and log
+const IMAGE_SIZE: usize = 8 << 20;
+const VOLUME_LABEL: &[u8; 11] = b"PI_STATUS  ";
+const LOG_LINES: u32 = 200;
+
+// What the host sees in STATUS.TXT
+pub struct Status {
+    pub time: DateTime<Local>,
+    pub host_state: String,
+    pub last_scan: String,
+}
+
+pub fn image_path() -> PathBuf {
+    PathBuf::from("/var/lib/upload-stick/status.img")
+}
+
+// What the host sees apart from the generation time and the log, which change on every scan.
+// Swapping the medium makes the host warn about a disk not ejected properly, so the image is
+// only replaced when this changes.
+pub fn summary(status: &Status) -> Result<String> {
+    let total_uploads = upload_db::upload_count().map_err(Error::UploadDb)?;
+    Ok(format!("{}\n{}\n{}", status.host_state, status.last_scan, total_uploads))
+}
+
+// Writes a fresh image next to the old one and renames it into place, so a LUN
+// that still has the old image open keeps reading it until the medium is swapped
+pub fn write_image(path: &Path, status: &Status) -> Result<()> {
+    let (total_uploads, uploads) = upload_db::recent_uploads(upload_db::MAX_LOGGED_UPLOADS).map_err(Error::UploadDb)?;
+    let image = build_image(&[
+        ("STATUS.TXT", status_text(status, total_uploads)),
+        ("UPLOADS.CSV", uploads_csv(&uploads)),
+        ("LOG.TXT", recent_log()),
+    ])?;
+
+    let tmp_path = path.with_extension("img.tmp");
+    if let Some(parent) = path.parent() {
+        fs::create_dir_all(parent).map_err(Error::StatusImage)?;
+    }
+    fs::write(&tmp_path, image).map_err(Error::StatusImage)?;
+    fs::rename(&tmp_path, path).map_err(Error::StatusImage)
+}
+
+fn status_text(status: &Status, total_uploads: usize) -> String {
+    format!("Upload stick status\r\n\r\n\
+        Generated: {}\r\n\
+        Host: {}\r\n\
+        Last scan: {}\r\n\
+        Files uploaded: {}\r\n",
+        status.time.format("%Y-%m-%d %H:%M:%S %z"), status.host_state, status.last_scan, total_uploads)
+}
+
+fn uploads_csv(uploads: &[String]) -> String {
+    let mut csv = format!("{}\r\n", upload_db::UPLOADS_CSV_HEADER);
+    for upload in uploads {
+        csv.push_str(upload);
+        csv.push_str("\r\n");
+    }
+    csv
+}
+
+fn recent_log() -> String {
+    match command_stdout(
+        Command::new("journalctl")
+            .arg("--unit").arg("upload-stick-run.service")
+            .arg("--lines").arg(LOG_LINES.to_string())
+            .arg("--output").arg("short-iso")
+            .arg("--no-pager")
+    ) {
+        Ok(log) => log.replace('\n', "\r\n"),
+        Err(err) => format!("Log unavailable: {}\r\n", err),
+    }
+}
+
+// Formats a small FAT volume in memory holding the given top-level files
+fn build_image(files: &[(&str, String)]) -> Result<Vec<u8>> {
+    let mut image = Cursor::new(vec![0u8; IMAGE_SIZE]);
+    fatfs::format_volume(&mut image, FormatVolumeOptions::new().volume_label(*VOLUME_LABEL))
+        .map_err(Error::StatusImage)?;
+    {
+        let file_system = FileSystem::new(&mut image, FsOptions::new()).map_err(Error::StatusImage)?;
+        for (name, contents) in files {
+            file_system.root_dir().create_file(name)
+                .and_then(|mut file| file.write_all(contents.as_bytes()))
+                .map_err(Error::StatusImage)?;
+        }
+        file_system.unmount().map_err(Error::StatusImage)?;
+    }
+    Ok(image.into_inner())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::io::Read;
+    use volume::{FatVolume, Volume};
+
+    #[test]
+    fn test_build_image() {
+        let image = build_image(&[
+            ("STATUS.TXT", "Host: configured\r\n".to_string()),
+            ("UPLOADS.CSV", uploads_csv(&["1,\"Take, 2.wav\",2026-10-19T14:03:12+02:00,\"upload:/Auto_Upload/Take, 2.ogg\"".to_string()])),
+        ]).unwrap();
+        assert_eq!(image.len(), IMAGE_SIZE);
+
+        let volume = FatVolume::new(Cursor::new(image)).unwrap();
+        let mut names = volume.read_dir(Path::new("")).unwrap().into_iter().map(|entry| entry.file_name).collect::<Vec<_>>();
+        names.sort();
+        assert_eq!(names, vec!["STATUS.TXT", "UPLOADS.CSV"]);
+
+        let entry = volume.read_dir(Path::new("")).unwrap().into_iter().find(|entry| entry.file_name == "UPLOADS.CSV").unwrap();
+        let mut contents = String::new();
+        volume.open(&entry).unwrap().read_to_string(&mut contents).unwrap();
+        assert_eq!(contents, "partition,file,uploaded,remote_path\r\n\
+            1,\"Take, 2.wav\",2026-10-19T14:03:12+02:00,\"upload:/Auto_Upload/Take, 2.ogg\"\r\n");
+    }
+
+    #[test]
+    fn test_status_text() {
+        let status = Status {
+            time: DateTime::parse_from_rfc3339("2026-10-19T14:03:12+02:00").unwrap().with_timezone(&Local),
+            host_state: "configured".to_string(),
+            last_scan: "uploaded 2 files".to_string(),
+        };
+        let text = status_text(&status, 153);
+        assert!(text.contains("Host: configured\r\n"));
+        assert!(text.contains("Last scan: uploaded 2 files\r\n"));
+        assert!(text.contains("Files uploaded: 153\r\n"));
+    }
+}
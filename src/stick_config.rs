@@ -0,0 +1,453 @@
+use std::fmt;
+use std::fs::{self, OpenOptions};
+use std::io::{self, Write};
+use std::path::{Path, PathBuf};
+use std::process::Command;
+use chrono::{DateTime, Local, SecondsFormat};
+use sha2::{Digest, Sha256};
+use toml;
+use config::UploadConfig;
+use upload_command::{Error, Result, command_stdout};
+use volume::{self, Volume, VolumeEntry};
+
+pub const TOML_FILE_NAME: &str = "upload-stick.toml";
+pub const WIFI_FILE_NAME: &str = "wifi.txt";
+// In the order they are applied, so the TOML file wins
+const FILE_NAMES: [&str; 2] = [TOML_FILE_NAME, WIFI_FILE_NAME];
+const MAX_FILE_LEN: u64 = 64 * 1024;
+const WIFI_INTERFACE: &str = "wlan0";
+
+// Settings a user may change by dropping a file onto the stick
+#[derive(Debug, Clone, PartialEq, Default, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct StickConfig {
+    pub wifi: Option<WifiConfig>,
+    pub upload: UploadOverrides,
+}
+
+#[derive(Debug, Clone, PartialEq, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct WifiConfig {
+    pub ssid: String,
+    // Empty for an open network
+    #[serde(default)]
+    pub password: String,
+}
+
+// Kept across restarts on top of the [upload] section of /etc/upload-stick.toml
+#[derive(Debug, Clone, PartialEq, Default, Serialize, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct UploadOverrides {
+    // Relative to the configured remote, which the stick cannot change
+    pub remote_folder: Option<String>,
+    pub quality: Option<f32>,
+    pub downmix: Option<bool>,
+}
+
+impl UploadOverrides {
+    pub fn is_empty(&self) -> bool {
+        *self == UploadOverrides::default()
+    }
+
+    pub fn merge(&mut self, newer: &UploadOverrides) {
+        if newer.remote_folder.is_some() {
+            self.remote_folder = newer.remote_folder.clone();
+        }
+        if newer.quality.is_some() {
+            self.quality = newer.quality;
+        }
+        if newer.downmix.is_some() {
+            self.downmix = newer.downmix;
+        }
+    }
+
+    pub fn apply_to(&self, base: &UploadConfig) -> Result<UploadConfig> {
+        let mut upload = base.clone();
+        if let Some(ref remote_folder) = self.remote_folder {
+            upload.remote = base.folder_remote(remote_folder)?;
+        }
+        if let Some(quality) = self.quality {
+            upload.quality = quality;
+        }
+        if let Some(downmix) = self.downmix {
+            upload.downmix = downmix;
+        }
+        upload.validate()?;
+        Ok(upload)
+    }
+}
+
+// What became of one version of a configuration file
+pub enum Outcome {
+    Applied,
+    // Invalid, so it stays rejected until the file changes
+    Rejected(Error),
+    // Could not be applied right now, such as when wpa_supplicant is not running yet, so it is
+    // retried on the next scan
+    Failed(Error),
+}
+
+impl Outcome {
+    pub fn from_error(err: Error) -> Outcome {
+        match err {
+            Error::ConfigParse(_) | Error::ConfigInvalid(_) => Outcome::Rejected(err),
+            _ => Outcome::Failed(err),
+        }
+    }
+
+    // Only versions that will not be tried again count as seen
+    fn is_final(&self) -> bool {
+        !matches!(self, Outcome::Failed(_))
+    }
+}
+
+impl fmt::Display for Outcome {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            Outcome::Applied => write!(f, "applied"),
+            Outcome::Rejected(err) => write!(f, "rejected, {}", err),
+            Outcome::Failed(err) => write!(f, "failed, {}, will retry", err),
+        }
+    }
+}
+
+impl WifiConfig {
+    fn validate(&self) -> Result<()> {
+        if self.ssid.is_empty() || self.ssid.len() > 32 || self.ssid.chars().any(|c| c.is_control()) {
+            return Err(Error::ConfigInvalid(format!("wifi.ssid must have 1 to 32 bytes: {}", self.ssid)));
+        }
+        // The password itself is never logged
+        let password_len = self.password.chars().count();
+        if !self.password.is_empty() &&
+            (!(8..=63).contains(&password_len) || !self.password.chars().all(|c| c.is_ascii() && !c.is_ascii_control())) {
+            return Err(Error::ConfigInvalid("wifi.password must have 8 to 63 printable ASCII characters".to_string()));
+        }
+        Ok(())
+    }
+}
+
+// Host file systems are case-insensitive, and so are these names
+pub fn is_config_file(file_name: &str) -> bool {
+    FILE_NAMES.iter().any(|name| name.eq_ignore_ascii_case(file_name))
+}
+
+pub fn parse(file_name: &str, contents: &str) -> Result<StickConfig> {
+    let stick_config = if file_name.eq_ignore_ascii_case(WIFI_FILE_NAME) {
+        parse_wifi_txt(contents)?
+    } else {
+        toml::from_str(contents).map_err(Error::ConfigParse)?
+    };
+    if let Some(ref wifi) = stick_config.wifi {
+        wifi.validate()?;
+    }
+    Ok(stick_config)
+}
+
+// One "key = value" per line, with keys ssid and password
+fn parse_wifi_txt(contents: &str) -> Result<StickConfig> {
+    let mut ssid = None;
+    let mut password = String::new();
+    for line in contents.lines() {
+        let line = line.trim();
+        if line.is_empty() || line.starts_with('#') {
+            continue;
+        }
+        let (key, value) = match line.find('=') {
+            Some(index) => (line[..index].trim(), line[index + 1..].trim()),
+            None => return Err(Error::ConfigInvalid(format!("expected key = value in {}: {}", WIFI_FILE_NAME, line))),
+        };
+        match key.to_ascii_lowercase().as_str() {
+            "ssid" => ssid = Some(value.to_string()),
+            "password" => password = value.to_string(),
+            _ => return Err(Error::ConfigInvalid(format!("unknown key in {}: {}", WIFI_FILE_NAME, key))),
+        }
+    }
+    let ssid = ssid.ok_or_else(|| Error::ConfigInvalid(format!("no ssid in {}", WIFI_FILE_NAME)))?;
+    Ok(StickConfig {
+        wifi: Some(WifiConfig { ssid, password }),
+        upload: UploadOverrides::default(),
+    })
+}
+
+// Configuration files in the root of a volume, in the order they are applied
+pub fn find(volume: &dyn Volume) -> Result<Vec<VolumeEntry>> {
+    let mut entries = volume.read_dir(Path::new(""))?.into_iter()
+        .filter(|entry| !entry.is_dir && is_config_file(&entry.file_name))
+        .collect::<Vec<_>>();
+    entries.sort_by_key(|entry| FILE_NAMES.iter().position(|name| name.eq_ignore_ascii_case(&entry.file_name)));
+    Ok(entries)
+}
+
+pub fn read(volume: &dyn Volume, entry: &VolumeEntry) -> Result<String> {
+    volume::read_text(volume, entry, MAX_FILE_LEN)
+}
+
+fn state_dir() -> PathBuf {
+    PathBuf::from("/var/lib/upload-stick/stick-config")
+}
+
+fn overrides_path() -> PathBuf {
+    state_dir().join("overrides.toml")
+}
+
+// One line per configuration file found: when, where and whether it was applied
+pub fn history_path() -> PathBuf {
+    state_dir().join("history.txt")
+}
+
+// A digest of the contents last seen for a file, so that each version is only applied once
+// without keeping a copy of the Wi-Fi passphrase
+fn seen_path(partition: u32, file_name: &str) -> PathBuf {
+    state_dir().join(format!("partition{}-{}", partition, file_name.to_ascii_lowercase()))
+}
+
+fn contents_digest(contents: &str) -> String {
+    Sha256::digest(contents.as_bytes()).iter().map(|byte| format!("{:02x}", byte)).collect()
+}
+
+pub fn is_new(partition: u32, file_name: &str, contents: &str) -> Result<bool> {
+    match fs::read_to_string(seen_path(partition, file_name)) {
+        Ok(seen) => Ok(seen.trim_end() != contents_digest(contents)),
+        Err(ref err) if err.kind() == io::ErrorKind::NotFound => Ok(true),
+        Err(err) => Err(Error::StickConfigRecord(err)),
+    }
+}
+
+pub fn record(partition: u32, file_name: &str, contents: &str, outcome: &Outcome, time: DateTime<Local>) -> Result<()> {
+    fs::create_dir_all(state_dir()).map_err(Error::StickConfigRecord)?;
+    if outcome.is_final() {
+        fs::write(seen_path(partition, file_name), format!("{}\n", contents_digest(contents)))
+            .map_err(Error::StickConfigRecord)?;
+    }
+    OpenOptions::new().create(true).append(true).open(history_path())
+        .and_then(|mut history| history.write_all(history_line(partition, file_name, &outcome.to_string(), time).as_bytes()))
+        .map_err(Error::StickConfigRecord)
+}
+
+fn history_line(partition: u32, file_name: &str, outcome: &str, time: DateTime<Local>) -> String {
+    format!("{} partition {} {}: {}\n", time.to_rfc3339_opts(SecondsFormat::Secs, false), partition, file_name, outcome)
+}
+
+pub fn load_overrides() -> Result<UploadOverrides> {
+    match fs::read_to_string(overrides_path()) {
+        Ok(overrides_toml) => toml::from_str(&overrides_toml).map_err(Error::ConfigParse),
+        Err(ref err) if err.kind() == io::ErrorKind::NotFound => Ok(UploadOverrides::default()),
+        Err(err) => Err(Error::StickConfigRecord(err)),
+    }
+}
+
+fn save_overrides(overrides: &UploadOverrides) -> Result<()> {
+    let overrides_toml = toml::to_string(overrides)
+        .map_err(|err| Error::ConfigInvalid(err.to_string()))?;
+    fs::create_dir_all(state_dir()).map_err(Error::StickConfigRecord)?;
+    fs::write(overrides_path(), overrides_toml).map_err(Error::StickConfigRecord)
+}
+
+// Everything is validated before anything changes. Returns the upload settings now in effect.
+pub fn apply(base: &UploadConfig, file_name: &str, contents: &str) -> Result<UploadConfig> {
+    let stick_config = parse(file_name, contents)?;
+    let mut overrides = load_overrides()?;
+    overrides.merge(&stick_config.upload);
+    let upload = overrides.apply_to(base)?;
+
+    if let Some(ref wifi) = stick_config.wifi {
+        configure_wifi(wifi)?;
+    }
+    if !stick_config.upload.is_empty() {
+        save_overrides(&overrides)?;
+    }
+    Ok(upload)
+}
+
+// wpa_supplicant must run with update_config=1 for the network to be saved. Networks with the
+// same SSID are only removed once the new one has been added, so a failure leaves them in place.
+fn configure_wifi(wifi: &WifiConfig) -> Result<()> {
+    let old_ids = find_networks(&wpa_cli(&["list_networks".to_string()])?, &wifi.ssid);
+
+    let id = wpa_cli(&["add_network".to_string()])?;
+    if id.parse::<u32>().is_err() {
+        return Err(Error::WifiConfig(format!("unexpected network id from wpa_cli: {}", id)));
+    }
+    for args in wifi_commands(&id, wifi) {
+        if let Err(err) = wpa_cli(&args) {
+            let _ = wpa_cli(&["remove_network".to_string(), id]);
+            return Err(err);
+        }
+    }
+    for old_id in old_ids {
+        wpa_cli(&["remove_network".to_string(), old_id])?;
+    }
+    wpa_cli(&["save_config".to_string()]).map(|_| ())
+}
+
+// wpa_cli reports failures on stdout with a zero exit code
+fn wpa_cli(args: &[String]) -> Result<String> {
+    let output = command_stdout(
+        Command::new("wpa_cli")
+            .arg("-i").arg(WIFI_INTERFACE)
+            .args(args)
+    )?;
+    let output = output.trim();
+    if output.starts_with("FAIL") {
+        return Err(Error::WifiConfig(format!("wpa_cli {} failed", args[0])));
+    }
+    Ok(output.to_string())
+}
+
+// The SSID is hex encoded so that any characters survive wpa_cli
+fn wifi_commands(id: &str, wifi: &WifiConfig) -> Vec<Vec<String>> {
+    let set = |name: &str, value: String| vec!["set_network".to_string(), id.to_string(), name.to_string(), value];
+    let ssid_hex = wifi.ssid.bytes().map(|byte| format!("{:02x}", byte)).collect::<String>();
+    let mut commands = vec![set("ssid", ssid_hex)];
+    if wifi.password.is_empty() {
+        commands.push(set("key_mgmt", "NONE".to_string()));
+    } else {
+        commands.push(set("psk", format!("\"{}\"", wifi.password)));
+    }
+    commands.push(vec!["enable_network".to_string(), id.to_string()]);
+    commands
+}
+
+// Ids of the networks with the given SSID in the tab separated output of list_networks
+fn find_networks(list_output: &str, ssid: &str) -> Vec<String> {
+    list_output.lines()
+        .skip(1)
+        .filter_map(|line| {
+            let fields = line.split('\t').collect::<Vec<_>>();
+            match fields.as_slice() {
+                [id, network_ssid, ..] if *network_ssid == ssid => Some(id.to_string()),
+                _ => None,
+            }
+        })
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn base() -> UploadConfig {
+        UploadConfig::default()
+    }
+
+    #[test]
+    fn test_is_config_file() {
+        assert!(is_config_file("upload-stick.toml"));
+        assert!(is_config_file("WIFI.TXT"));
+        assert!(!is_config_file("wifi.txt.bak"));
+        assert!(!is_config_file("Take 1.wav"));
+    }
+
+    #[test]
+    fn test_parse_toml() {
+        let stick_config = parse("upload-stick.toml", "
+            [wifi]
+            ssid = \"Rehearsal Room\"
+            password = \"correct horse\"
+
+            [upload]
+            remote_folder = \"Gigs\"
+            quality = 3
+        ").unwrap();
+        assert_eq!(stick_config.wifi, Some(WifiConfig {
+            ssid: "Rehearsal Room".to_string(),
+            password: "correct horse".to_string(),
+        }));
+        let upload = stick_config.upload.apply_to(&base()).unwrap();
+        assert_eq!(upload.remote, "upload:/Auto_Upload/Gigs");
+        assert_eq!(upload.quality, 3.0);
+        assert!(upload.downmix);
+
+        assert!(parse("upload-stick.toml", "[upload]\nencoder = \"flac\"\n").is_err());
+        assert!(parse("upload-stick.toml", "[wifi]\npassword = \"correct horse\"\n").is_err());
+    }
+
+    #[test]
+    fn test_parse_wifi_txt() {
+        let stick_config = parse("WIFI.TXT", "# Studio network\r\nSSID = Studio 5G\r\npassword=correct horse\r\n").unwrap();
+        assert_eq!(stick_config.wifi, Some(WifiConfig {
+            ssid: "Studio 5G".to_string(),
+            password: "correct horse".to_string(),
+        }));
+        assert!(stick_config.upload.is_empty());
+
+        assert_eq!(parse("wifi.txt", "ssid=Cafe\n").unwrap().wifi.unwrap().password, "");
+        assert!(parse("wifi.txt", "password=correct horse\n").is_err());
+        assert!(parse("wifi.txt", "Studio\ncorrect horse\n").is_err());
+        assert!(parse("wifi.txt", "ssid=Studio\npassword=short\n").is_err());
+        assert!(parse("wifi.txt", "ssid=A network name far longer than allowed\n").is_err());
+    }
+
+    #[test]
+    fn test_invalid_overrides() {
+        let overrides = UploadOverrides { quality: Some(12.0), ..UploadOverrides::default() };
+        assert!(overrides.apply_to(&base()).is_err());
+        for remote_folder in &[":local:/etc", "other:/Gigs", "/Gigs", "../Gigs", "Gigs/:sftp,host=example.com:"] {
+            let overrides = UploadOverrides { remote_folder: Some(remote_folder.to_string()), ..UploadOverrides::default() };
+            assert!(overrides.apply_to(&base()).is_err(), "{}", remote_folder);
+        }
+        assert!(parse("upload-stick.toml", "[upload]\nremote = \":local:/etc\"\n").is_err());
+    }
+
+    #[test]
+    fn test_outcome() {
+        assert!(Outcome::Applied.is_final());
+        let rejected = Outcome::from_error(Error::ConfigInvalid("wifi.ssid".to_string()));
+        assert!(rejected.is_final());
+        assert_eq!(rejected.to_string(), "rejected, Invalid configuration: wifi.ssid");
+        let failed = Outcome::from_error(Error::WifiConfig("wpa_cli add_network failed".to_string()));
+        assert!(!failed.is_final());
+        assert!(failed.to_string().starts_with("failed, "));
+    }
+
+    #[test]
+    fn test_merge_overrides() {
+        let mut overrides = UploadOverrides { remote_folder: Some("Gigs".to_string()), quality: Some(3.0), downmix: None };
+        overrides.merge(&UploadOverrides { quality: Some(8.0), downmix: Some(false), ..UploadOverrides::default() });
+        assert_eq!(overrides, UploadOverrides { remote_folder: Some("Gigs".to_string()), quality: Some(8.0), downmix: Some(false) });
+
+        let overrides_toml = toml::to_string(&overrides).unwrap();
+        assert_eq!(toml::from_str::<UploadOverrides>(&overrides_toml).unwrap(), overrides);
+        let partial_toml = toml::to_string(&UploadOverrides { downmix: Some(false), ..UploadOverrides::default() }).unwrap();
+        assert_eq!(partial_toml, "downmix = false\n");
+    }
+
+    #[test]
+    fn test_wifi_commands() {
+        let wifi = WifiConfig { ssid: "Café \"1\"".to_string(), password: "correct horse".to_string() };
+        assert_eq!(wifi_commands("3", &wifi), vec![
+            vec!["set_network", "3", "ssid", "436166c3a920223122"],
+            vec!["set_network", "3", "psk", "\"correct horse\""],
+            vec!["enable_network", "3"],
+        ]);
+
+        let wifi = WifiConfig { ssid: "Open".to_string(), password: String::new() };
+        assert_eq!(wifi_commands("0", &wifi)[1], vec!["set_network", "0", "key_mgmt", "NONE"]);
+    }
+
+    #[test]
+    fn test_find_networks() {
+        let list_output = "network id / ssid / bssid / flags\n\
+            0\tHome\tany\t[CURRENT]\n\
+            1\tStudio 5G\tany\t\n\
+            2\tStudio 5G\tany\t[DISABLED]\n";
+        assert_eq!(find_networks(list_output, "Studio 5G"), vec!["1", "2"]);
+        assert!(find_networks(list_output, "Studio").is_empty());
+    }
+
+    #[test]
+    fn test_contents_digest() {
+        let digest = contents_digest("ssid=Studio\npsk=secret\n");
+        assert_eq!(digest.len(), 64);
+        assert!(!digest.contains("secret"));
+        assert_eq!(contents_digest(""), "e3b0c44298fc1c149afbf4c8996fb92427ae41e4649b934ca495991b7852b855");
+        assert_ne!(digest, contents_digest("ssid=Studio\npsk=secret2\n"));
+    }
+
+    #[test]
+    fn test_history_line() {
+        let time = DateTime::parse_from_rfc3339("2026-10-19T14:03:12+02:00").unwrap().with_timezone(&Local);
+        let line = history_line(1, "wifi.txt", "applied", time);
+        assert!(line.ends_with(" partition 1 wifi.txt: applied\n"));
+    }
+}
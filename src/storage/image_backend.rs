@@ -0,0 +1,324 @@
+use std::fs;
+use std::path::{Path, PathBuf};
+use std::process::Command;
+use config::{ImageConfig, ImageSnapshot};
+use prepare::Step;
+use size::Size;
+use storage::{SNAPSHOT_ABORT_PERCENT, StorageBackend};
+use upload_command::{CommandCheck, Error, Result, command_line, command_stdout};
+
+const ORIGIN_MAPPING: &str = "mass_storage_origin";
+const SNAPSHOT_MAPPING: &str = "mass_storage_snap";
+// Chunk size in sectors for the dm-snapshot exception store
+const COW_CHUNK_SECTORS: u32 = 8;
+
+pub struct ImageBackend {
+    config: ImageConfig,
+}
+
+impl ImageBackend {
+    pub fn new(config: ImageConfig) -> ImageBackend {
+        ImageBackend { config }
+    }
+
+    fn snapshot_image_path(&self) -> PathBuf {
+        self.config.path.with_extension("snap")
+    }
+
+    fn cow_path(&self) -> PathBuf {
+        self.config.path.with_extension("cow")
+    }
+
+    fn image_size(&self) -> Result<u64> {
+        Size::parse(&self.config.size)?.to_bytes(512)
+    }
+
+    fn image_exists(&self) -> Result<bool> {
+        match fs::metadata(&self.config.path) {
+            Ok(metadata) => Ok(metadata.len() == self.image_size()?),
+            Err(_) => Ok(false),
+        }
+    }
+
+    fn create_image(&self) -> Result<()> {
+        if let Some(parent) = self.config.path.parent() {
+            fs::create_dir_all(parent).map_err(Error::ImageCreate)?;
+        }
+        command_stdout(&mut self.truncate_command()?)?;
+        Ok(())
+    }
+
+    fn truncate_command(&self) -> Result<Command> {
+        let mut command = Command::new("truncate");
+        command
+            .arg("--size").arg(self.image_size()?.to_string())
+            .arg(&self.config.path);
+        Ok(command)
+    }
+
+    fn origin_loop_device(&self) -> Result<String> {
+        find_loop_devices(&self.config.path)?
+            .into_iter()
+            .next()
+            .ok_or_else(|| Error::LoopDeviceNotFound(self.config.path.display().to_string()))
+    }
+}
+
+impl StorageBackend for ImageBackend {
+    fn create_steps<'a>(&'a self) -> Vec<Step<'a>> {
+        vec![
+            Step::new("create-image", &format!("Creating image {} of {}", self.config.path.display(), self.config.size),
+                move || self.image_exists(),
+                move || self.create_image())
+                .with_plan(move || Ok(command_line(&self.truncate_command()?)))
+                .with_undo_command(move || {
+                    let mut command = Command::new("rm");
+                    command.arg("--force").arg(&self.config.path);
+                    Ok(command)
+                }),
+        ]
+    }
+
+    fn summary(&self) -> Result<String> {
+        Ok(format!("Created image {} of {}", self.config.path.display(), self.config.size))
+    }
+
+    fn activate(&self) -> Result<String> {
+        let loop_device = match self.origin_loop_device() {
+            Ok(loop_device) => loop_device,
+            Err(_) => attach_loop_device(&self.config.path, false)?,
+        };
+
+        match self.config.snapshot {
+            ImageSnapshot::Reflink => Ok(loop_device),
+            ImageSnapshot::DmSnapshot => {
+                // Writes must go through a snapshot-origin target so that they can be copied out to snapshots
+                if !mapping_exists(ORIGIN_MAPPING) {
+                    println!("Creating snapshot origin for {}", loop_device);
+                    command_stdout(
+                        Command::new("dmsetup")
+                            .arg("create").arg(ORIGIN_MAPPING)
+                            .arg("--table").arg(format!("0 {} snapshot-origin {}", device_sectors(&loop_device)?, loop_device))
+                    )?;
+                }
+                self.origin_device()
+            },
+        }
+    }
+
+    fn deactivate(&self) -> Result<()> {
+        if let ImageSnapshot::DmSnapshot = self.config.snapshot {
+            CommandCheck::IgnoreOutput.execute(
+                Command::new("dmsetup").arg("remove").arg(ORIGIN_MAPPING)
+            )?;
+        }
+        for loop_device in find_loop_devices(&self.config.path)? {
+            detach_loop_device(&loop_device, &CommandCheck::ExpectZeroExitCode)?;
+        }
+        Ok(())
+    }
+
+    // The image holds the same bytes as the origin device and is there before activation
+    fn inspect_origin(&self) -> String {
+        self.config.path.display().to_string()
+    }
+
+    fn origin_device(&self) -> Result<String> {
+        match self.config.snapshot {
+            ImageSnapshot::Reflink => self.origin_loop_device(),
+            ImageSnapshot::DmSnapshot => Ok(mapper_path(ORIGIN_MAPPING)),
+        }
+    }
+
+    fn snapshot(&self) -> Result<String> {
+        match self.config.snapshot {
+            ImageSnapshot::Reflink => {
+                println!("Copying image to {}", self.snapshot_image_path().display());
+                command_stdout(
+                    Command::new("cp")
+                        .arg("--reflink=always")
+                        .arg(&self.config.path)
+                        .arg(self.snapshot_image_path())
+                )?;
+                attach_loop_device(&self.snapshot_image_path(), true)
+            },
+            ImageSnapshot::DmSnapshot => {
+                let origin_loop = self.origin_loop_device()?;
+                let cow_size = Size::parse(&self.config.cow_size)?;
+                command_stdout(
+                    Command::new("truncate")
+                        .arg("--size").arg(cow_size.to_bytes(512)?.to_string())
+                        .arg(self.cow_path())
+                )?;
+                let cow_loop = attach_loop_device(&self.cow_path(), false)?;
+
+                let table = format!("0 {} snapshot {} {} P {}",
+                    device_sectors(&origin_loop)?, origin_loop, cow_loop, COW_CHUNK_SECTORS);
+
+                // The origin must be suspended so that no write slips past the new exception store
+                command_stdout(Command::new("dmsetup").arg("suspend").arg(ORIGIN_MAPPING))?;
+                let created = command_stdout(
+                    Command::new("dmsetup")
+                        .arg("create").arg(SNAPSHOT_MAPPING)
+                        .arg("--readonly")
+                        .arg("--table").arg(table)
+                );
+                command_stdout(Command::new("dmsetup").arg("resume").arg(ORIGIN_MAPPING))?;
+                created?;
+
+                self.snapshot_device()
+            },
+        }
+    }
+
+    fn snapshot_device(&self) -> Result<String> {
+        match self.config.snapshot {
+            ImageSnapshot::Reflink => find_loop_devices(&self.snapshot_image_path())?
+                .into_iter()
+                .next()
+                .ok_or_else(|| Error::LoopDeviceNotFound(self.snapshot_image_path().display().to_string())),
+            ImageSnapshot::DmSnapshot => Ok(mapper_path(SNAPSHOT_MAPPING)),
+        }
+    }
+
+    // A reflinked copy is independent of the origin, so only dm snapshots can overflow
+    fn check_snapshot(&self) -> Result<()> {
+        match self.config.snapshot {
+            ImageSnapshot::Reflink => Ok(()),
+            ImageSnapshot::DmSnapshot => {
+                let status = command_stdout(
+                    Command::new("dmsetup").arg("status").arg(SNAPSHOT_MAPPING)
+                )?;
+                check_snapshot_status(SNAPSHOT_MAPPING, &status)
+            },
+        }
+    }
+
+    fn release(&self, check: &CommandCheck) -> Result<()> {
+        let backing_path = match self.config.snapshot {
+            ImageSnapshot::Reflink => self.snapshot_image_path(),
+            ImageSnapshot::DmSnapshot => {
+                check.execute(
+                    Command::new("dmsetup").arg("remove").arg(SNAPSHOT_MAPPING)
+                )?;
+                self.cow_path()
+            },
+        };
+
+        for loop_device in find_loop_devices(&backing_path).unwrap_or_default() {
+            detach_loop_device(&loop_device, check)?;
+        }
+        check.execute(
+            Command::new("rm").arg("--force").arg(backing_path)
+        )
+    }
+}
+
+fn mapper_path(mapped_name: &str) -> String {
+    format!("/dev/mapper/{}", mapped_name)
+}
+
+fn mapping_exists(mapped_name: &str) -> bool {
+    command_stdout(Command::new("dmsetup").arg("info").arg(mapped_name)).is_ok()
+}
+
+fn device_sectors(device: &str) -> Result<u64> {
+    let output = command_stdout(
+        Command::new("blockdev").arg("--getsz").arg(device)
+    )?;
+    output.trim().parse::<u64>()
+        .map_err(|_| Error::DmStatusParse(output.clone()))
+}
+
+fn attach_loop_device(path: &Path, read_only: bool) -> Result<String> {
+    let mut command = Command::new("losetup");
+    command.arg("--find").arg("--show");
+    if read_only {
+        command.arg("--read-only");
+    }
+    let output = command_stdout(command.arg(path))?;
+    Ok(output.trim().to_string())
+}
+
+fn detach_loop_device(loop_device: &str, check: &CommandCheck) -> Result<()> {
+    check.execute(
+        Command::new("losetup").arg("--detach").arg(loop_device)
+    )
+}
+
+fn find_loop_devices(path: &Path) -> Result<Vec<String>> {
+    let output = command_stdout(
+        Command::new("losetup").arg("--associated").arg(path)
+    )?;
+    Ok(losetup_find_devices(&output))
+}
+
+fn losetup_find_devices(losetup_output: &str) -> Vec<String> {
+    losetup_output.lines()
+        .filter_map(|line| line.split(':').next())
+        .map(|device| device.trim())
+        .filter(|device| !device.is_empty())
+        .map(String::from)
+        .collect()
+}
+
+fn check_snapshot_status(mapped_name: &str, status: &str) -> Result<()> {
+    match status.split_whitespace().collect::<Vec<&str>>().as_slice() {
+        [_, _, "snapshot", "Invalid", ..] | [_, _, "snapshot", "Overflow", ..] =>
+            Err(Error::SnapshotInvalid(mapped_name.to_string())),
+        [_, _, "snapshot", usage, ..] => {
+            let data_percent = parse_snapshot_usage(usage)
+                .ok_or_else(|| Error::DmStatusParse(status.to_string()))?;
+            if data_percent >= SNAPSHOT_ABORT_PERCENT {
+                return Err(Error::SnapshotOverflow { name: mapped_name.to_string(), data_percent });
+            }
+            Ok(())
+        },
+        _ => Err(Error::DmStatusParse(status.to_string())),
+    }
+}
+
+fn parse_snapshot_usage(usage: &str) -> Option<f64> {
+    let mut parts = usage.split('/');
+    let used = parts.next()?.parse::<f64>().ok()?;
+    let total = parts.next()?.parse::<f64>().ok()?;
+    if total <= 0.0 {
+        return None;
+    }
+    Some(used * 100.0 / total)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_losetup_find_devices() {
+        assert_eq!(
+            losetup_find_devices("/dev/loop3: [2049]:1311 (/var/lib/upload-stick/mass_storage.img)\n"),
+            vec!["/dev/loop3"]
+        );
+        assert!(losetup_find_devices("").is_empty());
+    }
+
+    #[test]
+    fn test_check_snapshot_status() {
+        assert!(check_snapshot_status("snap", "0 16777216 snapshot 1032/4194304 16\n").is_ok());
+        match check_snapshot_status("snap", "0 16777216 snapshot 3800/4000 16\n") {
+            Err(Error::SnapshotOverflow { data_percent, .. }) => assert_eq!(data_percent, 95.0),
+            _ => panic!("expected SnapshotOverflow"),
+        }
+        match check_snapshot_status("snap", "0 16777216 snapshot Invalid\n") {
+            Err(Error::SnapshotInvalid(name)) => assert_eq!(name, "snap"),
+            _ => panic!("expected SnapshotInvalid"),
+        }
+        assert!(check_snapshot_status("snap", "0 16777216 linear\n").is_err());
+    }
+
+    #[test]
+    fn test_snapshot_paths() {
+        let backend = ImageBackend::new(ImageConfig::default());
+        assert_eq!(backend.snapshot_image_path(), PathBuf::from("/var/lib/upload-stick/mass_storage.snap"));
+        assert_eq!(backend.cow_path(), PathBuf::from("/var/lib/upload-stick/mass_storage.cow"));
+    }
+}
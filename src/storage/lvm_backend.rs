@@ -0,0 +1,500 @@
+use std::path::Path;
+use std::process::Command;
+use config::{LvmConfig, StorageLayout};
+use lvm::{self, LogicalVolume};
+use file_system;
+use parted::{self, DiskLayout, FreeRegion, Partition};
+use prepare::Step;
+use size::{Size, Unit};
+use storage::{SNAPSHOT_ABORT_PERCENT, StorageBackend};
+use upload_command::{CommandCheck, Error, Result, command_stdout};
+
+// LVM defaults: data starts 1MiB into the PV and extents are 4MiB
+const PV_DATA_OFFSET: u64 = 1 << 20;
+const EXTENT_SIZE: u64 = 4 << 20;
+
+pub struct LvmBackend {
+    config: LvmConfig,
+    layout: StorageLayout,
+}
+
+impl LvmBackend {
+    pub fn new(config: LvmConfig, layout: StorageLayout) -> LvmBackend {
+        LvmBackend { config, layout }
+    }
+
+    fn root_partition<'l>(&self, layout: &'l DiskLayout) -> Result<&'l Partition> {
+        layout.partition(self.config.root_partition)
+            .ok_or_else(|| Error::DataPartitionNotFound(self.config.device.clone()))
+    }
+
+    // The first partition after the root partition, which is where it is added
+    fn existing_data_partition<'l>(&self, layout: &'l DiskLayout) -> Result<Option<&'l Partition>> {
+        let sector_size = layout.logical_sector_size;
+        let root_end = self.root_partition(layout)?.end.to_bytes(sector_size)?;
+        let mut found: Option<(u64, &Partition)> = None;
+        for partition in &layout.partitions {
+            let start = partition.start.to_bytes(sector_size)?;
+            if start > root_end && found.is_none_or(|(first_start, _)| start < first_start) {
+                found = Some((start, partition));
+            }
+        }
+        Ok(found.map(|(_, partition)| partition))
+    }
+
+    // Until the data partition exists, the number parted will give it: the lowest unused one
+    fn data_partition(&self, layout: &DiskLayout) -> Result<u32> {
+        if let Some(partition) = self.existing_data_partition(layout)? {
+            return Ok(partition.number);
+        }
+        Ok((1..).find(|number| layout.partition(*number).is_none()).unwrap_or(1))
+    }
+
+    fn pv_device_in(&self, layout: &DiskLayout) -> Result<String> {
+        Ok(parted::partition_device(&self.config.device, self.data_partition(layout)?))
+    }
+
+    fn pv_device(&self) -> Result<String> {
+        self.pv_device_in(&self.read_layout()?)
+    }
+
+    fn lv_path(&self, name: &str) -> String {
+        format!("{}/{}", self.config.vg_name, name)
+    }
+
+    fn origin_lv(&self) -> String {
+        self.lv_path(&self.config.lv_name)
+    }
+
+    fn snapshot_lv(&self) -> String {
+        self.lv_path(&self.config.snapshot_name)
+    }
+
+    fn pool_lv(&self) -> String {
+        self.lv_path(&self.config.pool_name)
+    }
+
+    fn root_end(&self) -> Result<Size> {
+        Size::parse(&self.config.root_end)
+    }
+
+    fn read_layout(&self) -> Result<DiskLayout> {
+        parted::read_layout(&self.config.device, Unit::Bytes)
+    }
+
+    fn has_data_partition(&self) -> Result<bool> {
+        Ok(self.existing_data_partition(&self.read_layout()?)?.is_some())
+    }
+
+    fn is_root_partition_resized(&self) -> Result<bool> {
+        let layout = self.read_layout()?;
+        let sector_size = layout.logical_sector_size;
+        // parted reports the last byte of the partition
+        Ok(self.root_partition(&layout)?.end.to_bytes(sector_size)? + 1 >= self.root_end()?.to_bytes(sector_size)?)
+    }
+
+    fn root_device(&self) -> String {
+        parted::partition_device(&self.config.device, self.config.root_partition)
+    }
+
+    fn resize_root_command(&self) -> Result<Command> {
+        let root_device = self.root_device();
+        let file_system = file_system::probe_type(Path::new(&root_device))?;
+        let mut command;
+        match file_system.as_deref() {
+            Some("ext2") | Some("ext3") | Some("ext4") => {
+                command = Command::new("resize2fs");
+                command.arg(&root_device);
+            },
+            // These only grow while mounted, and the root file system is mounted at /
+            Some("btrfs") => {
+                command = Command::new("btrfs");
+                command.arg("filesystem").arg("resize").arg("max").arg("/");
+            },
+            Some("xfs") => {
+                command = Command::new("xfs_growfs");
+                command.arg("/");
+            },
+            other => return Err(Error::RootFileSystemUnsupported(other.unwrap_or("unknown").to_string())),
+        }
+        Ok(command)
+    }
+
+    fn parted_command(&self, args: &[&str]) -> Command {
+        let mut command = Command::new("parted");
+        command.arg("--script").arg(&self.config.device).args(args);
+        command
+    }
+
+    // First and last byte of the data partition, whether or not it exists yet
+    fn data_partition_region(&self, layout: &DiskLayout) -> Result<(u64, u64)> {
+        let sector_size = layout.logical_sector_size;
+        if let Some(partition) = self.existing_data_partition(layout)? {
+            return Ok((partition.start.to_bytes(sector_size)?, partition.end.to_bytes(sector_size)?));
+        }
+        // The root partition may not have been resized yet, so its space counts as free
+        let free = find_last_free(layout)?;
+        let start = free.start.to_bytes(sector_size)?.max(self.root_end()?.to_bytes(sector_size)?);
+        Ok((start, free.end.to_bytes(sector_size)?))
+    }
+
+    fn vg_free_extents(&self) -> Result<u64> {
+        if lvm::volume_group_exists(&self.config.vg_name)? {
+            return Ok(lvm::volume_group(&self.config.vg_name)?.free_count);
+        }
+        // Predict the VG from the partition that will hold it, for the plan
+        let (start, end) = self.data_partition_region(&self.read_layout()?)?;
+        Ok(planned_extents(end + 1 - start))
+    }
+
+    fn add_data_partition_command(&self) -> Result<Command> {
+        let (start, end) = self.data_partition_region(&self.read_layout()?)?;
+        Ok(self.parted_command(&["mkpart", "primary", "", &bytes(start), &bytes(end)]))
+    }
+
+    fn thick_lv_command(&self) -> Result<Command> {
+        let extents = self.vg_free_extents()? * self.config.thick_percent / 100;
+        let mut command = Command::new("lvcreate");
+        command
+            .arg("--extents").arg(extents.to_string())
+            .arg("--name").arg(&self.config.lv_name)
+            .arg(&self.config.vg_name);
+        Ok(command)
+    }
+
+    fn thin_pool_command(&self) -> Result<Command> {
+        let mut command = Command::new("lvcreate");
+        command
+            .arg("--type").arg("thin-pool")
+            .arg("--extents").arg("100%FREE")
+            .arg("--name").arg(&self.config.pool_name)
+            .arg(&self.config.vg_name);
+        Ok(command)
+    }
+
+    fn thin_lv_command(&self) -> Result<Command> {
+        let pool_size = if lvm::logical_volume_exists(&self.pool_lv())? {
+            lvm::logical_volume(&self.pool_lv())?.size
+        } else {
+            // The pool loses a little to its metadata, so this overestimates the planned size slightly
+            Size::new((self.vg_free_extents()? * EXTENT_SIZE) as f64, Unit::Bytes)
+        };
+        let mut command = Command::new("lvcreate");
+        command
+            .arg("--thin")
+            .arg("--virtualsize").arg(thin_virtual_size(&pool_size, self.config.thin_percent)?.to_string())
+            .arg("--name").arg(&self.config.lv_name)
+            .arg(self.pool_lv());
+        Ok(command)
+    }
+}
+
+impl StorageBackend for LvmBackend {
+    fn create_steps<'a>(&'a self) -> Vec<Step<'a>> {
+        let mut steps = vec![
+            Step::command("resize-root-partition", "Resizing root partition",
+                move || self.is_root_partition_resized(),
+                move || Ok(self.parted_command(&[
+                    "resizepart", &self.config.root_partition.to_string(), &self.config.root_end]))),
+            // The data partition is only added once the file system no longer needs the space
+            Step::command("resize-root-file-system", "Resizing root file system",
+                move || self.has_data_partition(),
+                move || self.resize_root_command()),
+            Step::command("add-data-partition", "Adding data partition",
+                move || self.has_data_partition(),
+                move || self.add_data_partition_command())
+                .with_undo_command(move || {
+                    let data_partition = self.data_partition(&self.read_layout()?)?;
+                    Ok(self.parted_command(&["rm", &data_partition.to_string()]))
+                }),
+            Step::command("create-pv", "Making PV",
+                move || lvm::physical_volume_exists(&self.pv_device()?),
+                move || {
+                    let mut command = Command::new("pvcreate");
+                    command.arg(self.pv_device()?);
+                    Ok(command)
+                })
+                .with_undo_command(move || {
+                    let mut command = Command::new("pvremove");
+                    command.arg(self.pv_device()?);
+                    Ok(command)
+                }),
+            Step::command("create-vg", "Making VG",
+                move || lvm::volume_group_exists(&self.config.vg_name),
+                move || {
+                    let mut command = Command::new("vgcreate");
+                    command.arg(&self.config.vg_name).arg(self.pv_device()?);
+                    Ok(command)
+                })
+                .with_undo_command(move || {
+                    let mut command = Command::new("vgremove");
+                    command.arg(&self.config.vg_name);
+                    Ok(command)
+                }),
+        ];
+
+        match self.layout {
+            StorageLayout::Thick => {
+                steps.push(Step::command("create-lv", "Making LV",
+                    move || lvm::logical_volume_exists(&self.origin_lv()),
+                    move || self.thick_lv_command())
+                    .with_estimate(move || Ok(!lvm::volume_group_exists(&self.config.vg_name)?))
+                    .with_undo_command(move || Ok(lvremove_command(&self.origin_lv()))));
+            },
+            StorageLayout::Thin => {
+                steps.push(Step::command("create-thin-pool", "Making thin pool",
+                    move || lvm::logical_volume_exists(&self.pool_lv()),
+                    move || self.thin_pool_command())
+                    .with_undo_command(move || Ok(lvremove_command(&self.pool_lv()))));
+                steps.push(Step::command("create-thin-lv", "Making thin LV",
+                    move || lvm::logical_volume_exists(&self.origin_lv()),
+                    move || self.thin_lv_command())
+                    .with_estimate(move || Ok(!lvm::logical_volume_exists(&self.pool_lv())?))
+                    .with_undo_command(move || Ok(lvremove_command(&self.origin_lv()))));
+            },
+        }
+
+        steps
+    }
+
+    fn summary(&self) -> Result<String> {
+        let lv = lvm::logical_volume(&self.origin_lv())?;
+        match self.layout {
+            StorageLayout::Thick => {
+                let vg = lvm::volume_group(&self.config.vg_name)?;
+                Ok(format!("Created LV {} of {}, leaving {} free in VG {} for snapshots",
+                    lv.full_name(), lv.size, vg.free, vg.name))
+            },
+            StorageLayout::Thin => {
+                let pool = lvm::logical_volume(&self.pool_lv())?;
+                Ok(format!("Created thin LV {} of {} in pool {} of {}",
+                    lv.full_name(), lv.size, pool.full_name(), pool.size))
+            },
+        }
+    }
+
+    fn activate(&self) -> Result<String> {
+        self.origin_device()
+    }
+
+    fn deactivate(&self) -> Result<()> {
+        Ok(())
+    }
+
+    fn origin_device(&self) -> Result<String> {
+        Ok(self.inspect_origin())
+    }
+
+    fn inspect_origin(&self) -> String {
+        format!("/dev/{}", self.origin_lv())
+    }
+
+    fn snapshot(&self) -> Result<String> {
+        let mut command = Command::new("lvcreate");
+        command.arg("--snapshot");
+        match self.layout {
+            StorageLayout::Thick => {
+                command.arg("--extents").arg("100%FREE");
+            },
+            StorageLayout::Thin => {
+                // Thin snapshots are skipped on activation by default
+                command.arg("--setactivationskip").arg("n");
+            },
+        }
+        command_stdout(
+            command
+                .arg("--name").arg(&self.config.snapshot_name)
+                .arg(self.origin_lv())
+        )?;
+
+        self.snapshot_device()
+    }
+
+    fn snapshot_device(&self) -> Result<String> {
+        Ok(format!("/dev/{}", self.snapshot_lv()))
+    }
+
+    // With a thin pool, snapshot changes share the pool's free space rather than a reserved COW area
+    fn check_snapshot(&self) -> Result<()> {
+        let monitored_lv = match self.layout {
+            StorageLayout::Thick => self.snapshot_lv(),
+            StorageLayout::Thin => self.pool_lv(),
+        };
+        check_snapshot_lv(&lvm::logical_volume(&monitored_lv)?)
+    }
+
+    fn release(&self, check: &CommandCheck) -> Result<()> {
+        check.execute(&mut lvremove_command(&self.snapshot_lv()))
+    }
+}
+
+fn lvremove_command(lv: &str) -> Command {
+    let mut command = Command::new("lvremove");
+    command.arg("--yes").arg(lv);
+    command
+}
+
+fn bytes(value: u64) -> String {
+    Size::new(value as f64, Unit::Bytes).to_string()
+}
+
+fn planned_extents(pv_bytes: u64) -> u64 {
+    pv_bytes.saturating_sub(PV_DATA_OFFSET) / EXTENT_SIZE
+}
+
+// Blocks changed while a snapshot exists need space in the pool, so the
+// volume must not be able to fill the pool on its own
+fn thin_virtual_size(pool_size: &Size, percent: u64) -> Result<Size> {
+    let pool_bytes = pool_size.to_bytes(512)?;
+    let virtual_mebibytes = (pool_bytes * percent / 100) >> 20;
+    Ok(Size::new(virtual_mebibytes as f64, Unit::Mebibytes))
+}
+
+fn find_last_free(layout: &DiskLayout) -> Result<&FreeRegion> {
+    layout.free.last()
+        .ok_or_else(|| Error::PartitionFreeNotFound(layout.device.clone()))
+}
+
+fn check_snapshot_lv(snap: &LogicalVolume) -> Result<()> {
+    if snap.attributes.is_invalid_snapshot() {
+        return Err(Error::SnapshotInvalid(snap.full_name()));
+    }
+    for data_percent in snap.data_percent.iter().chain(snap.metadata_percent.iter()) {
+        if *data_percent >= SNAPSHOT_ABORT_PERCENT {
+            return Err(Error::SnapshotOverflow { name: snap.full_name(), data_percent: *data_percent });
+        }
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use config::THIN_PERCENT_MAX;
+    use lvm::LvAttributes;
+
+    #[test]
+    fn test_thin_virtual_size() {
+        let virtual_size = thin_virtual_size(&Size::new(29850861568.0, Unit::Bytes), 70).unwrap();
+        assert_eq!(virtual_size, Size::new(19927.0, Unit::Mebibytes));
+        assert_eq!(virtual_size.to_string(), "19927MiB");
+    }
+
+    #[test]
+    fn test_find_last_free() {
+        let layout = DiskLayout::parse(include_str!("../../testdata/parted/rpi_sd_print_free_mb.txt")).unwrap();
+        let free = find_last_free(&layout).unwrap();
+        assert_eq!(free.start.to_string(), "201MB");
+        assert_eq!(free.end.to_string(), "31915MB");
+    }
+
+    fn backend(config: LvmConfig) -> LvmBackend {
+        LvmBackend::new(config, StorageLayout::Thick)
+    }
+
+    #[test]
+    fn test_data_partition_region_planned() {
+        let layout = DiskLayout::parse(include_str!("../../testdata/parted/rpi_sd_print_free_mb.txt")).unwrap();
+        assert_eq!(backend(LvmConfig::default()).data_partition_region(&layout).unwrap(), (2147483648, 31915000000));
+    }
+
+    #[test]
+    fn test_data_partition_region_existing() {
+        let layout = DiskLayout::parse(include_str!("../../testdata/parted/rpi_sd_prepared_s.txt")).unwrap();
+        let partition = layout.partition(3).unwrap();
+        assert_eq!(backend(LvmConfig::default()).data_partition_region(&layout).unwrap(),
+            (partition.start.to_bytes(512).unwrap(), partition.end.to_bytes(512).unwrap()));
+    }
+
+    #[test]
+    fn test_usb_ssd_names() {
+        let ssd = backend(LvmConfig {
+            device: "/dev/sda".to_string(),
+            vg_name: "recordings".to_string(),
+            ..LvmConfig::default()
+        });
+        let layout = DiskLayout::parse(include_str!("../../testdata/parted/rpi_sd_prepared_s.txt")).unwrap();
+        assert_eq!(ssd.pv_device_in(&layout).unwrap(), "/dev/sda3");
+        assert_eq!(ssd.origin_device().unwrap(), "/dev/recordings/mass_storage_root");
+        assert_eq!(ssd.snapshot_device().unwrap(), "/dev/recordings/mass_storage_snap");
+        assert_eq!(ssd.pool_lv(), "recordings/mass_storage_pool");
+        assert_eq!(backend(LvmConfig::default()).pv_device_in(&layout).unwrap(), "/dev/mmcblk0p3");
+    }
+
+    #[test]
+    fn test_data_partition_from_layout() {
+        let backend = backend(LvmConfig::default());
+        let planned = DiskLayout::parse(include_str!("../../testdata/parted/rpi_sd_print_free_mb.txt")).unwrap();
+        assert!(backend.existing_data_partition(&planned).unwrap().is_none());
+        assert_eq!(backend.data_partition(&planned).unwrap(), 3);
+
+        // Numbers need not follow the order on the disk
+        let mut renumbered = DiskLayout::parse(include_str!("../../testdata/parted/rpi_sd_prepared_s.txt")).unwrap();
+        renumbered.partitions[0].number = 3;
+        renumbered.partitions[2].number = 1;
+        assert_eq!(backend.data_partition(&renumbered).unwrap(), 1);
+
+        // Without a data partition, the lowest unused number is taken
+        renumbered.partitions.remove(2);
+        assert_eq!(backend.data_partition(&renumbered).unwrap(), 1);
+    }
+
+    #[test]
+    fn test_planned_extents() {
+        assert_eq!(planned_extents(29850861568 + (1 << 20)), 7117);
+        assert_eq!(planned_extents(1 << 19), 0);
+    }
+
+    #[test]
+    fn test_find_last_free_none() {
+        let layout = DiskLayout::parse(include_str!("../../testdata/parted/rpi_sd_prepared_s.txt")).unwrap();
+        assert!(find_last_free(&layout).is_err());
+    }
+
+    fn snapshot_lv(lv_attr: &str, data_percent: f64) -> LogicalVolume {
+        LogicalVolume {
+            name: "mass_storage_snap".to_string(),
+            vg_name: "data".to_string(),
+            size: Size::new(8971616256.0, Unit::Bytes),
+            kernel_major: Some(254),
+            kernel_minor: Some(3),
+            origin: Some("mass_storage_root".to_string()),
+            pool_lv: None,
+            data_percent: Some(data_percent),
+            metadata_percent: None,
+            attributes: LvAttributes {
+                volume_type: lv_attr.chars().next().unwrap(),
+                permissions: 'w',
+                state: lv_attr.chars().nth(4).unwrap(),
+                open: false,
+                raw: lv_attr.to_string(),
+            },
+        }
+    }
+
+    #[test]
+    fn test_check_snapshot_lv() {
+        assert!(check_snapshot_lv(&snapshot_lv("swi-a-s---", 12.5)).is_ok());
+        match check_snapshot_lv(&snapshot_lv("swi-a-s---", 91.0)) {
+            Err(Error::SnapshotOverflow { data_percent, .. }) => assert_eq!(data_percent, 91.0),
+            _ => panic!("expected SnapshotOverflow"),
+        }
+        match check_snapshot_lv(&snapshot_lv("swi-I-s---", 100.0)) {
+            Err(Error::SnapshotInvalid(name)) => assert_eq!(name, "data/mass_storage_snap"),
+            _ => panic!("expected SnapshotInvalid"),
+        }
+    }
+
+    #[test]
+    fn test_check_snapshot_lv_fully_written_origin() {
+        // The host has written every block of the thin volume and never discards any
+        let pool_bytes: u64 = 29850861568;
+        for percent in &[LvmConfig::default().thin_percent, THIN_PERCENT_MAX] {
+            let virtual_size = thin_virtual_size(&Size::new(pool_bytes as f64, Unit::Bytes), *percent).unwrap();
+            let origin_percent = virtual_size.to_bytes(512).unwrap() as f64 * 100.0 / pool_bytes as f64;
+            assert!(check_snapshot_lv(&snapshot_lv("twi-aotz--", origin_percent)).is_ok());
+            // Leaves at least a tenth of the pool for snapshot changes
+            assert!(origin_percent + 10.0 < SNAPSHOT_ABORT_PERCENT);
+        }
+    }
+}
@@ -0,0 +1,52 @@
+use config::{Config, StorageBackendKind};
+use prepare::Step;
+use upload_command::{CommandCheck, MapMode, MappedPartition, Result, map_device_partitions};
+
+mod image_backend;
+mod lvm_backend;
+
+pub use self::image_backend::ImageBackend;
+pub use self::lvm_backend::LvmBackend;
+
+// Abort with some headroom because the host may keep writing while we clean up
+pub const SNAPSHOT_ABORT_PERCENT: f64 = 90.0;
+
+pub trait StorageBackend {
+    // Steps allocating the backing storage for the mass storage volume
+    fn create_steps<'a>(&'a self) -> Vec<Step<'a>>;
+
+    // What the create steps allocated, once they have run
+    fn summary(&self) -> Result<String>;
+
+    // Make the origin available as a block device and return its path
+    fn activate(&self) -> Result<String>;
+
+    fn deactivate(&self) -> Result<()>;
+
+    // Path of the already activated origin block device
+    fn origin_device(&self) -> Result<String>;
+
+    // Path from which the origin contents can be read without activating it
+    fn inspect_origin(&self) -> String;
+
+    // Freeze the current origin contents and return the snapshot block device
+    fn snapshot(&self) -> Result<String>;
+
+    fn snapshot_device(&self) -> Result<String>;
+
+    // Fails when the snapshot has overflowed or is about to
+    fn check_snapshot(&self) -> Result<()>;
+
+    fn release(&self, check: &CommandCheck) -> Result<()>;
+
+    fn map_snapshot(&self, mapped_prefix: &str) -> Result<Vec<MappedPartition>> {
+        map_device_partitions(&self.snapshot_device()?, mapped_prefix, MapMode::ReadOnly)
+    }
+}
+
+pub fn from_config(config: &Config) -> Box<dyn StorageBackend> {
+    match config.storage.backend {
+        StorageBackendKind::Lvm => Box::new(LvmBackend::new(config.storage.lvm.clone(), config.storage.layout)),
+        StorageBackendKind::Image => Box::new(ImageBackend::new(config.storage.image.clone())),
+    }
+}
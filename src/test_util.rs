@@ -0,0 +1,56 @@
+// Fixtures shared by the unit tests
+use std::fs;
+use std::io::{Cursor, Write};
+use std::ops::Deref;
+use std::path::{Path, PathBuf};
+use fatfs::{self, FileSystem, FsOptions};
+
+// An empty directory for one test, removed again when dropped, even if the test fails
+pub struct TempDir {
+    path: PathBuf,
+}
+
+impl TempDir {
+    pub fn new(name: &str) -> TempDir {
+        let path = ::std::env::temp_dir().join(format!("upload-stick-test-{}-{}", name, ::std::process::id()));
+        let _ = fs::remove_dir_all(&path);
+        fs::create_dir_all(&path).unwrap();
+        TempDir { path }
+    }
+}
+
+impl Deref for TempDir {
+    type Target = Path;
+
+    fn deref(&self) -> &Path {
+        &self.path
+    }
+}
+
+impl Drop for TempDir {
+    fn drop(&mut self) {
+        let _ = fs::remove_dir_all(&self.path);
+    }
+}
+
+// A small FAT volume holding the given files, with the folders in their paths
+pub fn fat_image(files: &[(&str, &[u8])]) -> Vec<u8> {
+    let mut image = Cursor::new(vec![0u8; 4 << 20]);
+    fatfs::format_volume(&mut image, fatfs::FormatVolumeOptions::new()).unwrap();
+    {
+        let fs = FileSystem::new(&mut image, FsOptions::new()).unwrap();
+        for (path, contents) in files {
+            let mut dir = fs.root_dir();
+            let mut components = path.split('/').collect::<Vec<&str>>();
+            let file_name = components.pop().unwrap();
+            for component in components {
+                dir = match dir.open_dir(component) {
+                    Ok(existing) => existing,
+                    Err(_) => dir.create_dir(component).unwrap(),
+                };
+            }
+            dir.create_file(file_name).unwrap().write_all(contents).unwrap();
+        }
+    }
+    image.into_inner()
+}
@@ -0,0 +1,193 @@
+use std::fs;
+use std::io;
+use std::path::{Path, PathBuf};
+use upload_command::{Error, Result};
+
+#[derive(Debug, Clone, PartialEq)]
+pub enum UdcState {
+    NotAttached,
+    Configured,
+    Suspended,
+    // Powered, default, addressed and the other states a host passes through while enumerating
+    Enumerating(String),
+}
+
+impl UdcState {
+    pub fn parse(state: &str) -> UdcState {
+        match state.trim() {
+            "not attached" => UdcState::NotAttached,
+            "configured" => UdcState::Configured,
+            "suspended" => UdcState::Suspended,
+            other => UdcState::Enumerating(other.to_string()),
+        }
+    }
+
+    pub fn name(&self) -> &str {
+        match self {
+            UdcState::NotAttached => "not attached",
+            UdcState::Configured => "configured",
+            UdcState::Suspended => "suspended",
+            UdcState::Enumerating(state) => state,
+        }
+    }
+}
+
+pub fn udc_root() -> PathBuf {
+    PathBuf::from("/sys/class/udc")
+}
+
+// Where upload_stick_run publishes the host connection state for other tools
+pub fn status_path() -> PathBuf {
+    PathBuf::from("/run/upload-stick/host_state")
+}
+
+// Boards with a single USB device controller are the norm, so the first one is used
+pub fn first_udc(udc_root: &Path) -> Result<Option<String>> {
+    let mut udcs = match fs::read_dir(udc_root) {
+        Ok(entries) => entries
+            .map(|entry| entry.map(|entry| entry.file_name().to_string_lossy().into_owned()))
+            .collect::<io::Result<Vec<String>>>()
+            .map_err(Error::UdcSysfs)?,
+        Err(ref err) if err.kind() == io::ErrorKind::NotFound => return Ok(None),
+        Err(err) => return Err(Error::UdcSysfs(err)),
+    };
+    udcs.sort();
+    Ok(udcs.into_iter().next())
+}
+
+// None when the machine has no USB device controller, as with the image backend on a PC
+pub fn read_state(udc_root: &Path) -> Result<Option<UdcState>> {
+    let udc = match first_udc(udc_root)? {
+        Some(udc) => udc,
+        None => return Ok(None),
+    };
+    fs::read_to_string(udc_root.join(udc).join("state"))
+        .map(|state| Some(UdcState::parse(&state)))
+        .map_err(Error::UdcSysfs)
+}
+
+pub struct UdcWatcher {
+    udc_root: PathBuf,
+    status_path: Option<PathBuf>,
+    state: Option<UdcState>,
+}
+
+impl UdcWatcher {
+    pub fn new(udc_root: &Path) -> UdcWatcher {
+        UdcWatcher {
+            udc_root: udc_root.to_path_buf(),
+            status_path: None,
+            state: None,
+        }
+    }
+
+    // Writes each new state to a file
+    pub fn publish(mut self, status_path: &Path) -> UdcWatcher {
+        self.status_path = Some(status_path.to_path_buf());
+        self
+    }
+
+    pub fn state(&self) -> Option<&UdcState> {
+        self.state.as_ref()
+    }
+
+    pub fn is_connected(&self) -> bool {
+        self.state == Some(UdcState::Configured)
+    }
+
+    // Reads the current state and returns whether it changed since the last poll
+    pub fn poll(&mut self) -> Result<bool> {
+        let state = read_state(&self.udc_root)?;
+        if state == self.state {
+            return Ok(false);
+        }
+        if let Some(ref status_path) = self.status_path {
+            let name = state.as_ref().map_or("no controller", |state| state.name());
+            if let Some(parent) = status_path.parent() {
+                fs::create_dir_all(parent).map_err(Error::HostStatePublish)?;
+            }
+            fs::write(status_path, format!("{}\n", name)).map_err(Error::HostStatePublish)?;
+        }
+        self.state = state;
+        Ok(true)
+    }
+
+    // Polls the state and returns whether the host has just gone away after having been attached
+    pub fn poll_disconnected(&mut self) -> Result<bool> {
+        let was_attached = self.state.as_ref().is_some_and(|state| *state != UdcState::NotAttached);
+        Ok(self.poll()? && was_attached && self.state == Some(UdcState::NotAttached))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use test_util::TempDir;
+
+    fn fake_sysfs(name: &str) -> TempDir {
+        let root = TempDir::new(name);
+        fs::create_dir_all(root.join("udc/20980000.usb")).unwrap();
+        root
+    }
+
+    fn set_state(root: &Path, state: &str) {
+        fs::write(root.join("udc/20980000.usb/state"), format!("{}\n", state)).unwrap();
+    }
+
+    #[test]
+    fn test_parse() {
+        assert_eq!(UdcState::parse("not attached\n"), UdcState::NotAttached);
+        assert_eq!(UdcState::parse("configured\n"), UdcState::Configured);
+        assert_eq!(UdcState::parse("suspended\n"), UdcState::Suspended);
+        assert_eq!(UdcState::parse("addressed\n"), UdcState::Enumerating("addressed".to_string()));
+    }
+
+    #[test]
+    fn test_read_state_without_udc() {
+        assert_eq!(read_state(Path::new("/nonexistent/udc")).unwrap(), None);
+    }
+
+    #[test]
+    fn test_watcher() {
+        let root = fake_sysfs("udc_watcher");
+        let status_path = root.join("run/host_state");
+        let mut watcher = UdcWatcher::new(&root.join("udc")).publish(&status_path);
+
+        // Booting without a host is not a disconnect
+        set_state(&root, "not attached");
+        assert!(!watcher.poll_disconnected().unwrap());
+        assert!(!watcher.is_connected());
+
+        set_state(&root, "default");
+        assert!(!watcher.poll_disconnected().unwrap());
+        set_state(&root, "configured");
+        assert!(!watcher.poll_disconnected().unwrap());
+        assert!(watcher.is_connected());
+        assert_eq!(fs::read_to_string(&status_path).unwrap(), "configured\n");
+
+        // Unchanged states are not reported again
+        assert!(!watcher.poll().unwrap());
+
+        set_state(&root, "suspended");
+        assert!(!watcher.poll_disconnected().unwrap());
+        assert_eq!(watcher.state(), Some(&UdcState::Suspended));
+
+        set_state(&root, "not attached");
+        assert!(watcher.poll_disconnected().unwrap());
+        assert!(!watcher.poll_disconnected().unwrap());
+        assert_eq!(fs::read_to_string(&status_path).unwrap(), "not attached\n");
+    }
+
+    #[test]
+    fn test_watcher_publish_failure() {
+        let root = fake_sysfs("udc_publish_failure");
+        // A file where the folder for the state should be
+        fs::write(root.join("run"), "").unwrap();
+        let mut watcher = UdcWatcher::new(&root.join("udc")).publish(&root.join("run/host_state"));
+        set_state(&root, "configured");
+        match watcher.poll_disconnected() {
+            Err(Error::HostStatePublish(_)) => (),
+            other => panic!("expected HostStatePublish, got {:?}", other.is_ok()),
+        }
+    }
+}
@@ -1,27 +1,68 @@
 use std::fmt;
-use std::io;
+use std::io::{self, Read, Write};
 use std::num;
 use std::string;
 use std::thread;
-use std::time::Duration;
+use std::time::{Duration, Instant};
 use std::result;
-use std::process::Command;
+use std::path::PathBuf;
+use std::process::{Command, Output, Stdio};
+use serde_json;
+use toml;
+use parted::{self, DiskLayout, Partition};
 
+#[derive(Debug)]
 pub enum Error {
     CommandNonZeroExitCode { code: i32, stdout: String, stderr: String },
     CommandTerminatedBySignal,
     CommandOther(io::Error),
     StdoutNotUtf8(string::FromUtf8Error),
-    Partition1NotFound(String),
-    PartitionFieldsNotFound(String),
+    DataPartitionNotFound(String),
+    GptHeaderNotFound(String),
+    PartitionTableRead(io::Error),
     PartitionFreeNotFound(String),
-    PartitionFreeFieldsNotFound(String),
+    PartedUnitsNotSupported(String),
+    PartedDeviceNotFound(String),
+    PartedFieldsNotFound(String),
+    PartedNumberParse(String),
+    SizeParse(String),
+    SizeNotAbsolute(String),
+    SizeNotSectorAligned(String),
     LedSysfs(io::Error),
     StatWritesNotFound(String),
     StatWritesParse(num::ParseIntError),
     StatWritesSysfs(io::Error),
     IteratingDirectory(io::Error),
-    LvsMinorParse(num::ParseIntError),
+    LvmReportParse(serde_json::Error),
+    LvmFieldParse(String),
+    LvNotFound(String),
+    LvNotActive(String),
+    VgNotFound(String),
+    InvalidArgument(String),
+    SnapshotOverflow { name: String, data_percent: f64 },
+    SnapshotInvalid(String),
+    LoopDeviceNotFound(String),
+    RootFileSystemUnsupported(String),
+    ImageCreate(io::Error),
+    DmStatusParse(String),
+    ConfigRead(io::Error),
+    ConfigParse(toml::de::Error),
+    ConfigInvalid(String),
+    VolumeRead(io::Error),
+    FsckRecord(io::Error),
+    PrepareJournal(io::Error),
+    GadgetConfigfs(io::Error),
+    UdcNotFound(String),
+    UdcSysfs(io::Error),
+    HostStatePublish(io::Error),
+    LunNotFound(String),
+    MediumLocked(String),
+    UploadDb(io::Error),
+    Cleanup(io::Error),
+    DfParse(String),
+    StatusImage(io::Error),
+    StickConfigRecord(io::Error),
+    WifiConfig(String),
 }
 
 impl fmt::Display for Error {
@@ -37,16 +78,53 @@ impl fmt::Display for Error {
             Error::CommandTerminatedBySignal => write!(f, "Command terminated by signal"),
             Error::CommandOther(err) => write!(f, "I/O error executing command: {}", err),
             Error::StdoutNotUtf8(err) => write!(f, "Could not parse stdout as UTF-8: {}", err),
-            Error::Partition1NotFound(output) => write!(f, "Could not find partition 1 in output: {}", output),
-            Error::PartitionFieldsNotFound(line) => write!(f, "Could not find required partition fields: {}", line),
-            Error::PartitionFreeNotFound(output) => write!(f, "Could not find space for partition in output: {}", output),
-            Error::PartitionFreeFieldsNotFound(line) => write!(f, "Could not find required free space fields: {}", line),
+            Error::DataPartitionNotFound(device) => write!(f, "Could not find a data partition on device: {}", device),
+            Error::GptHeaderNotFound(device) => write!(f, "Device has a protective MBR but no readable GPT: {}", device),
+            Error::PartitionTableRead(err) => write!(f, "I/O error reading partition table: {}", err),
+            Error::PartitionFreeNotFound(device) => write!(f, "Could not find space for partition on device: {}", device),
+            Error::PartedUnitsNotSupported(header) => write!(f, "Unsupported units in parted output: {}", header),
+            Error::PartedDeviceNotFound(output) => write!(f, "Could not find device line in parted output: {}", output),
+            Error::PartedFieldsNotFound(line) => write!(f, "Could not find required fields in parted line: {}", line),
+            Error::PartedNumberParse(line) => write!(f, "Could not parse number in parted line: {}", line),
+            Error::SizeParse(size) => write!(f, "Could not parse size with unit: {}", size),
+            Error::SizeNotAbsolute(size) => write!(f, "Size is not an absolute quantity: {}", size),
+            Error::SizeNotSectorAligned(size) => write!(f, "Size is not a whole number of sectors: {}", size),
             Error::LedSysfs(err) => write!(f, "I/O error controlling LEDs over sysfs: {}", err),
             Error::StatWritesNotFound(line) => write!(f, "Could not find writes field in stat output: {}", line),
             Error::StatWritesParse(err) => write!(f, "Could not parse stat writes field: {}", err),
             Error::StatWritesSysfs(err) => write!(f, "I/O error watching stat writes over sysfs: {}", err),
             Error::IteratingDirectory(err) => write!(f, "I/O error iterating over directory: {}", err),
-            Error::LvsMinorParse(err) => write!(f, "Could not parse device minor number from lvs: {}", err),
+            Error::LvmReportParse(err) => write!(f, "Could not parse LVM JSON report: {}", err),
+            Error::LvmFieldParse(field) => write!(f, "Could not parse field in LVM report: {}", field),
+            Error::LvNotFound(name) => write!(f, "Logical volume not found: {}", name),
+            Error::LvNotActive(name) => write!(f, "Logical volume has no kernel device: {}", name),
+            Error::VgNotFound(name) => write!(f, "Volume group not found: {}", name),
+            Error::InvalidArgument(arg) => write!(f, "Invalid argument: {}", arg),
+            Error::SnapshotOverflow { name, data_percent } =>
+                write!(f, "Snapshot {} is {}% full, aborting before it overflows", name, data_percent),
+            Error::SnapshotInvalid(name) => write!(f, "Snapshot {} overflowed and is no longer valid", name),
+            Error::LoopDeviceNotFound(path) => write!(f, "Could not find loop device for: {}", path),
+            Error::RootFileSystemUnsupported(file_system) => write!(f, "Cannot grow root file system of type: {}", file_system),
+            Error::ImageCreate(err) => write!(f, "I/O error creating storage image: {}", err),
+            Error::DmStatusParse(status) => write!(f, "Could not parse device-mapper status: {}", status),
+            Error::ConfigRead(err) => write!(f, "I/O error reading configuration: {}", err),
+            Error::ConfigParse(err) => write!(f, "Could not parse configuration: {}", err),
+            Error::ConfigInvalid(reason) => write!(f, "Invalid configuration: {}", reason),
+            Error::VolumeRead(err) => write!(f, "I/O error reading file system: {}", err),
+            Error::FsckRecord(err) => write!(f, "I/O error recording file system check: {}", err),
+            Error::PrepareJournal(err) => write!(f, "I/O error accessing prepare journal: {}", err),
+            Error::GadgetConfigfs(err) => write!(f, "I/O error configuring USB gadget: {}", err),
+            Error::UdcNotFound(path) => write!(f, "No USB device controller found in: {}", path),
+            Error::UdcSysfs(err) => write!(f, "I/O error reading USB device controller state: {}", err),
+            Error::HostStatePublish(err) => write!(f, "I/O error publishing host state: {}", err),
+            Error::LunNotFound(path) => write!(f, "No mass storage LUN found in: {}", path),
+            Error::MediumLocked(path) => write!(f, "Host has locked the medium in: {}", path),
+            Error::UploadDb(err) => write!(f, "I/O error accessing upload database: {}", err),
+            Error::Cleanup(err) => write!(f, "I/O error cleaning up uploaded files: {}", err),
+            Error::DfParse(output) => write!(f, "Could not parse df output: {}", output),
+            Error::StatusImage(err) => write!(f, "I/O error writing status image: {}", err),
+            Error::StickConfigRecord(err) => write!(f, "I/O error recording stick configuration: {}", err),
+            Error::WifiConfig(output) => write!(f, "Could not configure Wi-Fi network: {}", output),
         }
     }
 }
@@ -56,6 +134,17 @@ pub enum MapMode {
     ReadWrite,
 }
 
+pub struct MappedPartition {
+    pub number: u32,
+    pub mapped_name: String,
+}
+
+impl MappedPartition {
+    pub fn device_path(&self) -> PathBuf {
+        PathBuf::from("/dev/mapper").join(&self.mapped_name)
+    }
+}
+
 pub enum CommandCheck {
     IgnoreOutput,
     ExpectZeroExitCode,
@@ -63,7 +152,7 @@ pub enum CommandCheck {
 }
 
 impl CommandCheck {
-    fn execute(self: &CommandCheck, command: &mut Command) -> Result<()> {
+    pub fn execute(self: &CommandCheck, command: &mut Command) -> Result<()> {
         match self {
             CommandCheck::IgnoreOutput => {
                 command_ignore_output(command)
@@ -73,10 +162,9 @@ impl CommandCheck {
             },
             CommandCheck::Retry { count, interval } => {
                 for _ in 0 .. *count - 1 {
-                    match command_stdout(command) {
-                        Ok(_) => return Ok(()),
-                        Err(_) => {}
-                    };
+                    if command_stdout(command).is_ok() {
+                        return Ok(());
+                    }
                     thread::sleep(*interval);
                 }
                 command_stdout(command).map(|_| ())
@@ -100,16 +188,126 @@ pub fn command_ignore_output(command: &mut Command) -> Result<()> {
 pub fn command_stdout(command: &mut Command) -> Result<String> {
     let output = command
         .output()
-        .map_err(|io_error| Error::CommandOther(io_error))?;
+        .map_err(Error::CommandOther)?;
+
+    check_output(output)
+}
+
+// Shell-like rendering of a command for logs and plans
+pub fn command_line(command: &Command) -> String {
+    ::std::iter::once(command.get_program())
+        .chain(command.get_args())
+        .map(|arg| {
+            let arg = arg.to_string_lossy();
+            if arg.is_empty() || arg.contains(char::is_whitespace) {
+                format!("'{}'", arg)
+            } else {
+                arg.to_string()
+            }
+        })
+        .collect::<Vec<String>>()
+        .join(" ")
+}
+
+// For commands that read their input themselves, such as oggenc reading a file on a snapshot
+// that may overflow meanwhile. Calls check at least once per interval and kills the command
+// when it fails.
+pub fn command_stdout_monitored<F>(command: &mut Command, interval: Duration, check: F) -> Result<String>
+    where F: FnMut() -> Result<()>
+{
+    command_stdout_with_input(command, &mut io::empty(), interval, check)
+}
+
+const INPUT_CHUNK_SIZE: usize = 64 * 1024;
+
+// Streams input to the command's stdin, calling check at least once per interval
+pub fn command_stdout_with_input<F>(command: &mut Command, input: &mut dyn Read, interval: Duration, mut check: F) -> Result<String>
+    where F: FnMut() -> Result<()>
+{
+    let mut child = command
+        .stdin(Stdio::piped())
+        .stdout(Stdio::piped())
+        .stderr(Stdio::piped())
+        .spawn()
+        .map_err(Error::CommandOther)?;
+
+    let stdout_reader = read_in_background(child.stdout.take());
+    let stderr_reader = read_in_background(child.stderr.take());
+
+    let written = write_input(child.stdin.take(), input, interval, &mut check);
+    if let Err(err) = written {
+        let _ = child.kill();
+        let _ = child.wait();
+        return Err(err);
+    }
+
+    let status = loop {
+        if let Some(status) = child.try_wait().map_err(Error::CommandOther)? {
+            break status;
+        }
+
+        if let Err(err) = check() {
+            let _ = child.kill();
+            let _ = child.wait();
+            return Err(err);
+        }
+
+        thread::sleep(interval);
+    };
 
+    check_output(Output {
+        status,
+        stdout: stdout_reader.join().unwrap_or_default(),
+        stderr: stderr_reader.join().unwrap_or_default(),
+    })
+}
+
+fn write_input<W, F>(stdin: Option<W>, input: &mut dyn Read, interval: Duration, check: &mut F) -> Result<()>
+    where W: Write, F: FnMut() -> Result<()>
+{
+    let mut stdin = match stdin {
+        Some(stdin) => stdin,
+        None => return Ok(()),
+    };
+    let mut buffer = vec![0u8; INPUT_CHUNK_SIZE];
+    let mut last_check = Instant::now();
+    loop {
+        let len = input.read(&mut buffer).map_err(Error::VolumeRead)?;
+        if len == 0 {
+            return Ok(());
+        }
+        match stdin.write_all(&buffer[..len]) {
+            Ok(()) => {},
+            // The command stopped reading; its exit status tells whether that was an error
+            Err(ref err) if err.kind() == io::ErrorKind::BrokenPipe => return Ok(()),
+            Err(err) => return Err(Error::CommandOther(err)),
+        }
+        if last_check.elapsed() >= interval {
+            check()?;
+            last_check = Instant::now();
+        }
+    }
+}
+
+fn read_in_background<R: Read + Send + 'static>(pipe: Option<R>) -> thread::JoinHandle<Vec<u8>> {
+    thread::spawn(move || {
+        let mut buffer = Vec::new();
+        if let Some(mut pipe) = pipe {
+            let _ = pipe.read_to_end(&mut buffer);
+        }
+        buffer
+    })
+}
+
+fn check_output(output: Output) -> Result<String> {
     let stdout = String::from_utf8(output.stdout)
-        .map_err(|utf8_error| Error::StdoutNotUtf8(utf8_error))?;
+        .map_err(Error::StdoutNotUtf8)?;
 
     if !output.status.success() {
         let stderr = String::from_utf8_lossy(&output.stderr);
         return Err(match output.status.code() {
             Some(code) => Error::CommandNonZeroExitCode {
-                code: code,
+                code,
                 stdout: stdout.to_string(),
                 stderr: stderr.to_string()
             },
@@ -120,20 +318,37 @@ pub fn command_stdout(command: &mut Command) -> Result<String> {
     Ok(stdout)
 }
 
-pub fn map_lv_partition(lv_name: &str, mapped_name: &str, mode: MapMode) -> Result<()> {
+pub fn map_device_partition(device: &str, mapped_name: &str, mode: MapMode) -> Result<()> {
     println!("Getting storage partition");
-    let storage_parted_output = command_stdout(
-        Command::new("parted")
-            .arg("--script")
-            .arg("--machine")
-            .arg(format!("/dev/data/{}", lv_name))
-            .arg("unit").arg("s")
-            .arg("print")
-    )?;
+    let (layout, partition) = parted::read_data_partition(device)?;
+    map_partition(&layout, &partition, device, mapped_name, &mode)
+}
+
+pub fn map_device_partitions(device: &str, mapped_prefix: &str, mode: MapMode) -> Result<Vec<MappedPartition>> {
+    println!("Getting storage partitions");
+    let (layout, partitions) = parted::read_data_partitions(device)?;
+
+    let mut mapped: Vec<MappedPartition> = Vec::new();
+    for partition in partitions {
+        let mapped_name = format!("{}{}", mapped_prefix, partition.number);
+        if let Err(err) = map_partition(&layout, &partition, device, &mapped_name, &mode) {
+            for mapped_partition in mapped {
+                let _ = unmap_partition(&mapped_partition.mapped_name, CommandCheck::IgnoreOutput);
+            }
+            return Err(err);
+        }
+        mapped.push(MappedPartition {
+            number: partition.number,
+            mapped_name,
+        });
+    }
 
-    let (storage_from, storage_length) = parted_find_first_start_length(&storage_parted_output)?;
+    Ok(mapped)
+}
 
-    println!("Creating mapping to storage partition");
+fn map_partition(layout: &DiskLayout, partition: &Partition, origin: &str, mapped_name: &str, mode: &MapMode) -> Result<()> {
+    println!("Creating mapping to storage partition {} ({}) of {:?} table",
+        partition.number, partition.file_system, layout.table);
     let mut mapping_command = Command::new("dmsetup");
     mapping_command.arg("create");
     match mode {
@@ -146,17 +361,30 @@ pub fn map_lv_partition(lv_name: &str, mapped_name: &str, mode: MapMode) -> Resu
     command_stdout(
         mapping_command
             .arg("--table")
-            .arg(format!(
-                "0 {} linear /dev/data/mass_storage_root {}",
-                drop_units(&storage_length),
-                drop_units(&storage_from)
-            ))
+            .arg(linear_table(layout, partition, origin)?)
             .arg(mapped_name)
     )?;
 
     Ok(())
 }
 
+pub fn list_mappings(mapped_prefix: &str) -> Result<Vec<String>> {
+    let dmsetup_output = command_stdout(
+        Command::new("dmsetup")
+            .arg("ls")
+    )?;
+
+    Ok(dmsetup_find_names(&dmsetup_output, mapped_prefix))
+}
+
+fn dmsetup_find_names(dmsetup_output: &str, mapped_prefix: &str) -> Vec<String> {
+    dmsetup_output.lines()
+        .filter_map(|line| line.split_whitespace().next())
+        .filter(|name| name.starts_with(mapped_prefix))
+        .map(String::from)
+        .collect()
+}
+
 pub fn unmap_partition(mapped_name: &str, check: CommandCheck) -> Result<()> {
     println!("Removing mapping to storage partition");
     let mut command = Command::new("dmsetup");
@@ -167,20 +395,13 @@ pub fn unmap_partition(mapped_name: &str, check: CommandCheck) -> Result<()> {
     check.execute(&mut command)
 }
 
-fn parted_find_first_start_length(parted_output: &str) -> Result<(String, String)> {
-    let part_line = parted_output.lines()
-        .filter(|line| line.trim().starts_with("1:"))
-        .next()
-        .ok_or(Error::Partition1NotFound(parted_output.to_string()))?;
-
-    match part_line.split(":").take(4).collect::<Vec<&str>>().as_slice() {
-        [_, from, _, length] => Ok((String::from(from.trim()), String::from(length.trim()))),
-        _ => Err(Error::PartitionFieldsNotFound(part_line.to_string()))
-    }
-}
-
-fn drop_units(string: &str) -> String {
-    string.chars().take_while(|&c| char::is_numeric(c)).collect::<String>()
+fn linear_table(layout: &DiskLayout, partition: &Partition, origin: &str) -> Result<String> {
+    Ok(format!(
+        "0 {} linear {} {}",
+        partition.size.to_sectors(layout.logical_sector_size)?,
+        origin,
+        partition.start.to_sectors(layout.logical_sector_size)?
+    ))
 }
 
 #[cfg(test)]
@@ -188,18 +409,128 @@ mod tests {
     use super::*;
 
     #[test]
-    fn test_parted_find_first_start_length() {
-        let (from, length) = parted_find_first_start_length("
+    fn test_command_stdout_monitored() {
+        let stdout = command_stdout_monitored(
+            Command::new("sh").arg("-c").arg("echo monitored"),
+            Duration::from_millis(10),
+            || Ok(())
+        ).unwrap();
+        assert_eq!(stdout, "monitored\n");
+    }
+
+    #[test]
+    fn test_command_stdout_monitored_exit_code() {
+        match command_stdout_monitored(
+            Command::new("sh").arg("-c").arg("echo failed >&2; exit 3"),
+            Duration::from_millis(10),
+            || Ok(())
+        ) {
+            Err(Error::CommandNonZeroExitCode { code, stderr, .. }) => {
+                assert_eq!(code, 3);
+                assert_eq!(stderr, "failed\n");
+            },
+            _ => panic!("expected CommandNonZeroExitCode"),
+        }
+    }
+
+    #[test]
+    fn test_command_stdout_monitored_aborts() {
+        let start = std::time::Instant::now();
+        let mut checks = 0;
+        let result = command_stdout_monitored(
+            Command::new("sleep").arg("10"),
+            Duration::from_millis(10),
+            || {
+                checks += 1;
+                if checks < 3 { Ok(()) } else { Err(Error::SnapshotInvalid("test".to_string())) }
+            }
+        );
+        match result {
+            Err(Error::SnapshotInvalid(_)) => {},
+            _ => panic!("expected check error"),
+        }
+        assert!(start.elapsed() < Duration::from_secs(5));
+    }
+
+    #[test]
+    fn test_command_stdout_with_input() {
+        let stdout = command_stdout_with_input(
+            Command::new("wc").arg("--bytes"),
+            &mut io::repeat(b'x').take(1 << 20),
+            Duration::from_millis(10),
+            || Ok(())
+        ).unwrap();
+        assert_eq!(stdout.trim(), "1048576");
+    }
+
+    #[test]
+    fn test_command_stdout_with_input_aborts() {
+        let mut checks = 0;
+        let result = command_stdout_with_input(
+            &mut Command::new("cat"),
+            &mut io::repeat(b'x'),
+            Duration::from_millis(0),
+            || {
+                checks += 1;
+                if checks < 3 { Ok(()) } else { Err(Error::SnapshotInvalid("test".to_string())) }
+            }
+        );
+        match result {
+            Err(Error::SnapshotInvalid(_)) => {},
+            _ => panic!("expected check error"),
+        }
+    }
+
+    #[test]
+    fn test_command_line() {
+        let mut command = Command::new("parted");
+        command.arg("--script").arg("/dev/mmcblk0").arg("mkpart").arg("primary").arg("").arg("2GiB");
+        assert_eq!(command_line(&command), "parted --script /dev/mmcblk0 mkpart primary '' 2GiB");
+        let mut command = Command::new("mkfs.exfat");
+        command.arg("--volume-label").arg("Field recordings");
+        assert_eq!(command_line(&command), "mkfs.exfat --volume-label 'Field recordings'");
+    }
+
+    #[test]
+    fn test_dmsetup_find_names() {
+        let names = dmsetup_find_names("
+data-mass_storage_root-real	(253:1)
+mass_storage_snap_partition2	(253:6)
+data-mass_storage_snap	(253:3)
+mass_storage_snap_partition1	(253:5)
+", "mass_storage_snap_partition");
+        assert_eq!(names, vec!["mass_storage_snap_partition2", "mass_storage_snap_partition1"]);
+    }
+
+    #[test]
+    fn test_linear_table() {
+        let layout = DiskLayout::parse("
             BYT;
             /dev/dm-4:30900224s:unknown:512:512:msdos:Unknown:;
             1:8192s:30900223s:30892032s:::lba;
         ").unwrap();
-        assert_eq!(from, "8192s");
-        assert_eq!(length, "30892032s");
+        let table = linear_table(&layout, layout.partition(1).unwrap(), "/dev/data/mass_storage_root").unwrap();
+        assert_eq!(table, "0 30892032 linear /dev/data/mass_storage_root 8192");
+    }
+
+    #[test]
+    fn test_linear_table_4k_sectors() {
+        let layout = DiskLayout::parse("
+            BYT;
+            /dev/sda:3862528s:scsi:4096:4096:msdos:Disk:;
+            1:1024s:3862527s:3861504s:::lba;
+        ").unwrap();
+        let table = linear_table(&layout, layout.partition(1).unwrap(), "/dev/sda").unwrap();
+        assert_eq!(table, "0 30892032 linear /dev/sda 8192");
     }
 
     #[test]
-    fn test_drop_units() {
-        assert_eq!(drop_units("30892032s"), "30892032");
+    fn test_linear_table_rejects_bytes_off_sector() {
+        let layout = DiskLayout::parse("
+            BYT;
+            /dev/sda:15819866112B:scsi:512:512:msdos:Disk:;
+            1:4194304B:15819866111B:15815671807B:::lba;
+        ").unwrap();
+        assert!(linear_table(&layout, layout.partition(1).unwrap(), "/dev/sda").is_err());
     }
 }
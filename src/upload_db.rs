@@ -1,42 +1,227 @@
-use std::fs::{self, File, DirEntry};
-use std::path::{PathBuf};
-use std::io;
+use std::ffi::OsString;
+use std::fs::{self, File, OpenOptions};
+use std::path::{Path, PathBuf};
+use std::io::{self, Write};
+use chrono::{DateTime, Local, SecondsFormat};
+use file_name;
 
 pub struct FileEntry {
-    file_name: String,
+    partition: u32,
+    // Exact path relative to the volume root; the file name alone for top-level files
+    path: PathBuf,
     len: u64
 }
 
+// Content of an entry whose remote copy was checked against the local one
+const VERIFIED: &str = "verified";
+// Content of an entry for a file that is never uploaded, so it is not read again each scan
+const SKIPPED: &str = "skipped";
+
 fn db_path() -> PathBuf {
-    return PathBuf::from("/var/lib/upload-stick/uploaded");
+    PathBuf::from("/var/lib/upload-stick/uploaded")
+}
+
+pub const UPLOADS_CSV_HEADER: &str = "partition,file,uploaded,remote_path";
+
+// One row per upload, without the header, oldest first
+fn uploads_log_path() -> PathBuf {
+    PathBuf::from("/var/lib/upload-stick/uploads.csv")
+}
+
+// Older rows are trimmed from the log, so the total is counted separately
+fn upload_count_path() -> PathBuf {
+    PathBuf::from("/var/lib/upload-stick/upload_count")
+}
+
+// The log is trimmed back to this many rows each time this many more are added
+pub const MAX_LOGGED_UPLOADS: usize = 10000;
+
+fn partition_path(partition: u32) -> PathBuf {
+    db_path().join(format!("partition{}", partition))
+}
+
+// The name is kept byte for byte, so names that are not UTF-8 never collide
+fn entry_name(entry: &FileEntry) -> OsString {
+    let mut name = entry.path.file_name().map_or_else(OsString::new, |name| name.to_os_string());
+    name.push(format!("_{}", entry.len));
+    name
 }
 
 fn entry_path(entry: &FileEntry) -> PathBuf {
-    return db_path().join(format!("{}_{}", &entry.file_name, entry.len));
+    let dir = entry.path.parent().unwrap_or_else(|| Path::new(""));
+    partition_path(entry.partition).join(dir).join(entry_name(entry))
 }
 
-fn ensure_db_exists() -> io::Result<()> {
-    return fs::create_dir_all(&db_path());
+// Entries recorded before partitions were tracked all refer to top-level files on partition 1
+fn legacy_entry_path(entry: &FileEntry) -> Option<PathBuf> {
+    if entry.partition == 1 && entry.path.components().count() == 1 {
+        Some(db_path().join(entry_name(entry)))
+    } else {
+        None
+    }
 }
 
-pub fn from_dir_entry(dir_entry: &DirEntry) -> io::Result<FileEntry> {
-    return Ok(FileEntry {
-        file_name: dir_entry.file_name().to_string_lossy().to_string(),
-        len: dir_entry.metadata()?.len()
-    });
+fn ensure_db_exists(partition: u32) -> io::Result<()> {
+    fs::create_dir_all(partition_path(partition))
 }
 
-pub fn is_uploaded(entry: &FileEntry) -> io::Result<bool> {
-    ensure_db_exists()?;
-    return match fs::metadata(entry_path(entry)) {
+// Entries for files in folders are kept in matching folders
+fn ensure_entry_dir_exists(entry: &FileEntry) -> io::Result<()> {
+    match entry_path(entry).parent() {
+        Some(parent) => fs::create_dir_all(parent),
+        None => ensure_db_exists(entry.partition)
+    }
+}
+
+fn is_file(path: &Path) -> io::Result<bool> {
+    match fs::metadata(path) {
         Ok(metadata) => Ok(metadata.is_file()),
         Err(ref err) if err.kind() == io::ErrorKind::NotFound => Ok(false),
         Err(err) => Err(err)
-    };
+    }
+}
+
+pub fn new_entry(partition: u32, path: &Path, len: u64) -> FileEntry {
+    FileEntry {
+        partition,
+        path: path.to_path_buf(),
+        len
+    }
+}
+
+// Also true for skipped files, which need no more looking at than uploaded ones
+pub fn is_uploaded(entry: &FileEntry) -> io::Result<bool> {
+    ensure_db_exists(entry.partition)?;
+    if is_file(&entry_path(entry))? {
+        return Ok(true);
+    }
+    match legacy_entry_path(entry) {
+        Some(path) => is_file(&path),
+        None => Ok(false)
+    }
 }
 
 pub fn set_uploaded(entry: &FileEntry) -> io::Result<()> {
-    ensure_db_exists()?;
+    ensure_entry_dir_exists(entry)?;
     File::create(entry_path(entry))?;
-    return Ok(());
+    Ok(())
+}
+
+// Entries recorded before verification, or by set_uploaded, are not verified
+pub fn is_verified(entry: &FileEntry) -> io::Result<bool> {
+    match fs::read_to_string(entry_path(entry)) {
+        Ok(contents) => Ok(contents.trim() == VERIFIED),
+        Err(ref err) if err.kind() == io::ErrorKind::NotFound => Ok(false),
+        Err(err) => Err(err)
+    }
+}
+
+pub fn set_verified(entry: &FileEntry) -> io::Result<()> {
+    ensure_entry_dir_exists(entry)?;
+    fs::write(entry_path(entry), format!("{}\n", VERIFIED))
+}
+
+// A changed file has another length, so it is looked at again
+pub fn set_skipped(entry: &FileEntry) -> io::Result<()> {
+    ensure_entry_dir_exists(entry)?;
+    fs::write(entry_path(entry), format!("{}\n", SKIPPED))
+}
+
+pub fn record_upload(entry: &FileEntry, remote_path: &str, time: DateTime<Local>) -> io::Result<()> {
+    fs::create_dir_all(db_path())?;
+    let total = upload_count()? + 1;
+    {
+        let mut log = OpenOptions::new().create(true).append(true).open(uploads_log_path())?;
+        writeln!(log, "{}", upload_row(entry, remote_path, time))?;
+    }
+    fs::write(upload_count_path(), format!("{}\n", total))?;
+    if total % MAX_LOGGED_UPLOADS == 0 {
+        let tmp_path = uploads_log_path().with_extension("csv.tmp");
+        fs::write(&tmp_path, last_rows(&read_uploads_log()?, MAX_LOGGED_UPLOADS).join("\n") + "\n")?;
+        fs::rename(&tmp_path, uploads_log_path())?;
+    }
+    Ok(())
+}
+
+fn upload_row(entry: &FileEntry, remote_path: &str, time: DateTime<Local>) -> String {
+    format!("{},{},{},{}", entry.partition, csv_field(&file_name::escape(entry.path.as_os_str())),
+        time.to_rfc3339_opts(SecondsFormat::Secs, false), csv_field(remote_path))
+}
+
+fn csv_field(value: &str) -> String {
+    if value.contains([',', '"', '\n', '\r']) {
+        format!("\"{}\"", value.replace('"', "\"\""))
+    } else {
+        value.to_string()
+    }
+}
+
+fn read_uploads_log() -> io::Result<String> {
+    match fs::read_to_string(uploads_log_path()) {
+        Ok(log) => Ok(log),
+        Err(ref err) if err.kind() == io::ErrorKind::NotFound => Ok(String::new()),
+        Err(err) => Err(err)
+    }
+}
+
+fn last_rows(log: &str, max: usize) -> Vec<&str> {
+    let rows = log.lines().collect::<Vec<&str>>();
+    rows[rows.len().saturating_sub(max)..].to_vec()
+}
+
+// The total number of uploads recorded, including rows trimmed from the log
+pub fn upload_count() -> io::Result<usize> {
+    match fs::read_to_string(upload_count_path()) {
+        Ok(count) => count.trim().parse()
+            .map_err(|err| io::Error::new(io::ErrorKind::InvalidData, err)),
+        // Logs written before the count existed were never trimmed
+        Err(ref err) if err.kind() == io::ErrorKind::NotFound => Ok(read_uploads_log()?.lines().count()),
+        Err(err) => Err(err)
+    }
+}
+
+// The total number of uploads and the rows of the most recent ones
+pub fn recent_uploads(max: usize) -> io::Result<(usize, Vec<String>)> {
+    let rows = last_rows(&read_uploads_log()?, max).into_iter().map(String::from).collect();
+    Ok((upload_count()?, rows))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::ffi::OsStr;
+    use std::os::unix::ffi::OsStrExt;
+
+    #[test]
+    fn test_upload_row() {
+        let time = DateTime::parse_from_rfc3339("2026-10-19T14:03:12+02:00").unwrap().with_timezone(&Local);
+        let row = upload_row(&new_entry(1, Path::new("Take, \"best\".wav"), 1024), "upload:/Auto_Upload/Take.ogg", time);
+        assert!(row.starts_with("1,\"Take, \"\"best\"\".wav\","));
+        assert!(row.ends_with(",upload:/Auto_Upload/Take.ogg"));
+
+        let row = upload_row(&new_entry(2, Path::new(OsStr::from_bytes(b"Session/Caf\xe9.wav")), 1024), "upload:/Auto_Upload/Session/Caf%E9.ogg", time);
+        assert!(row.starts_with("2,Session/Caf%E9.wav,"));
+    }
+
+    #[test]
+    fn test_last_rows() {
+        assert_eq!(last_rows("a\nb\nc\n", 2), vec!["b", "c"]);
+        assert_eq!(last_rows("a\nb\n", 5), vec!["a", "b"]);
+        assert!(last_rows("", 5).is_empty());
+    }
+
+    #[test]
+    fn test_entry_path() {
+        assert_eq!(entry_path(&new_entry(1, Path::new("TAKE01.wav"), 1024)), db_path().join("partition1/TAKE01.wav_1024"));
+        assert_eq!(entry_path(&new_entry(2, Path::new("Session/TAKE02.wav"), 8)), db_path().join("partition2/Session/TAKE02.wav_8"));
+
+        // Lossy conversion would map both to "Caf\u{fffd}.wav"
+        let latin1 = entry_path(&new_entry(1, Path::new(OsStr::from_bytes(b"Caf\xe9.wav")), 8));
+        let cp437 = entry_path(&new_entry(1, Path::new(OsStr::from_bytes(b"Caf\x82.wav")), 8));
+        assert_eq!(latin1.file_name().unwrap().as_bytes(), b"Caf\xe9.wav_8");
+        assert_ne!(latin1, cp437);
+
+        assert!(legacy_entry_path(&new_entry(1, Path::new("TAKE01.wav"), 8)).is_some());
+        assert!(legacy_entry_path(&new_entry(1, Path::new("Session/TAKE01.wav"), 8)).is_none());
+    }
 }
@@ -0,0 +1,287 @@
+use std::io::Read;
+use std::path::Path;
+use ignore::gitignore::{Gitignore, GitignoreBuilder};
+use toml;
+use config::{self, UploadConfig};
+use file_name;
+use upload_command::{Error, Result};
+use volume::{self, Volume, VolumeEntry};
+
+// Files and folders to leave out, in gitignore syntax, relative to the folder holding it
+pub const IGNORE_FILE_NAME: &str = ".uploadignore";
+// Upload settings for the folder holding it and its subfolders
+pub const RULES_FILE_NAME: &str = ".uploadrules.toml";
+const MAX_FILE_LEN: u64 = 64 * 1024;
+// Magic numbers of the AppleDouble and AppleSingle formats macOS uses for resource forks
+const APPLE_DOUBLE_MAGIC: [u8; 4] = [0x00, 0x05, 0x16, 0x07];
+const APPLE_SINGLE_MAGIC: [u8; 4] = [0x00, 0x05, 0x16, 0x00];
+
+#[derive(Debug, Clone, PartialEq, Default, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct FolderRules {
+    // Relative to the remote directory, in place of the folder's own path
+    pub remote_folder: Option<String>,
+    pub quality: Option<f32>,
+    pub downmix: Option<bool>,
+}
+
+// A file to consider for upload with the settings of its folder
+#[derive(Debug, Clone, PartialEq)]
+pub struct ScanFile {
+    pub entry: VolumeEntry,
+    // The remote is the directory the file is uploaded into
+    pub upload: UploadConfig,
+}
+
+impl FolderRules {
+    pub fn parse(rules_toml: &str) -> Result<FolderRules> {
+        let rules: FolderRules = toml::from_str(rules_toml).map_err(Error::ConfigParse)?;
+        if let Some(ref remote_folder) = rules.remote_folder {
+            UploadConfig::default().folder_remote(remote_folder)?;
+        }
+        Ok(rules)
+    }
+
+    // Settings for a folder, from those of the volume root and of its parent folder
+    fn apply_to(&self, root: &UploadConfig, parent: &UploadConfig, folder_name: &str) -> Result<UploadConfig> {
+        let mut upload = parent.clone();
+        upload.remote = match self.remote_folder {
+            Some(ref remote_folder) => root.folder_remote(remote_folder)?,
+            None => parent.remote_path(folder_name),
+        };
+        if let Some(quality) = self.quality {
+            upload.quality = quality;
+        }
+        if let Some(downmix) = self.downmix {
+            upload.downmix = downmix;
+        }
+        upload.validate()?;
+        Ok(upload)
+    }
+}
+
+// Every file on the volume that neither the exclusion list nor a .uploadignore excludes, apart
+// from the rule files themselves. Folders whose rules cannot be read are skipped, since they may
+// have been meant to keep recordings private.
+pub fn walk(volume: &dyn Volume, upload: &UploadConfig, exclude: &[String]) -> Result<Vec<ScanFile>> {
+    let mut files = Vec::new();
+    let mut ignores = vec![config::exclude_matcher(exclude)?];
+    walk_dir(volume, Path::new(""), upload, upload, &mut ignores, &mut files)?;
+    Ok(files)
+}
+
+// Resource forks copied from macOS keep the name of the file they belong to, with a "._" prefix
+// that renaming can lose, so their contents are checked too
+pub fn is_apple_double(volume: &dyn Volume, entry: &VolumeEntry) -> Result<bool> {
+    let mut header = Vec::new();
+    volume.open(entry)?.take(APPLE_DOUBLE_MAGIC.len() as u64).read_to_end(&mut header).map_err(Error::VolumeRead)?;
+    Ok(header == APPLE_DOUBLE_MAGIC || header == APPLE_SINGLE_MAGIC)
+}
+
+fn walk_dir(volume: &dyn Volume, dir: &Path, root: &UploadConfig, upload: &UploadConfig,
+        ignores: &mut Vec<Gitignore>, files: &mut Vec<ScanFile>) -> Result<()> {
+    let mut entries = volume.read_dir(dir)?;
+    entries.sort_by(|a, b| a.file_name.cmp(&b.file_name));
+
+    let (ignore, upload) = match read_folder(volume, dir, &entries, root, upload) {
+        Ok(folder) => folder,
+        Err(err) => {
+            println!("Skipping folder {:?}: {}", dir, err);
+            return Ok(());
+        },
+    };
+    ignores.push(ignore);
+
+    for entry in entries {
+        if is_ignored(ignores, &entry) {
+            continue;
+        }
+        if entry.is_dir {
+            walk_dir(volume, &entry.os_path, root, &upload, ignores, files)?;
+        } else if entry.file_name != IGNORE_FILE_NAME && entry.file_name != RULES_FILE_NAME {
+            files.push(ScanFile { entry, upload: upload.clone() });
+        }
+    }
+
+    ignores.pop();
+    Ok(())
+}
+
+fn read_folder(volume: &dyn Volume, dir: &Path, entries: &[VolumeEntry], root: &UploadConfig, parent: &UploadConfig)
+        -> Result<(Gitignore, UploadConfig)> {
+    let find = |name: &str| entries.iter().find(|entry| !entry.is_dir && entry.file_name == name);
+
+    let mut builder = GitignoreBuilder::new(dir);
+    if let Some(entry) = find(IGNORE_FILE_NAME) {
+        for line in volume::read_text(volume, entry, MAX_FILE_LEN)?.lines() {
+            builder.add_line(None, line).map_err(|err| Error::ConfigInvalid(format!("{}: {}", entry.path, err)))?;
+        }
+    }
+    let ignore = builder.build().map_err(|err| Error::ConfigInvalid(err.to_string()))?;
+
+    let upload = match dir.file_name() {
+        None => parent.clone(),
+        Some(folder_name) => {
+            let rules = match find(RULES_FILE_NAME) {
+                Some(entry) => FolderRules::parse(&volume::read_text(volume, entry, MAX_FILE_LEN)?)?,
+                None => FolderRules::default(),
+            };
+            rules.apply_to(root, parent, &file_name::escape(folder_name))?
+        },
+    };
+    Ok((ignore, upload))
+}
+
+// As with git, the .uploadignore closest to the entry decides, and the files of an ignored folder
+// cannot be included again
+fn is_ignored(ignores: &[Gitignore], entry: &VolumeEntry) -> bool {
+    for ignore in ignores.iter().rev() {
+        let matched = ignore.matched(&entry.os_path, entry.is_dir);
+        if matched.is_ignore() {
+            return true;
+        }
+        if matched.is_whitelist() {
+            return false;
+        }
+    }
+    false
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::io::Cursor;
+    use config::ScanConfig;
+    use test_util::fat_image;
+    use volume::FatVolume;
+
+    fn fat_volume(files: &[(&str, &str)]) -> FatVolume<Cursor<Vec<u8>>> {
+        let files = files.iter().map(|(path, contents)| (*path, contents.as_bytes())).collect::<Vec<_>>();
+        FatVolume::new(Cursor::new(fat_image(&files))).unwrap()
+    }
+
+    fn remotes(files: &[ScanFile]) -> Vec<(String, String)> {
+        files.iter().map(|file| (file.entry.path.clone(), file.upload.remote.clone())).collect()
+    }
+
+    #[test]
+    fn test_walk() {
+        let volume = fat_volume(&[
+            ("TAKE01.wav", "RIFF"),
+            ("Session/TAKE02.wav", "RIFF"),
+            ("Session/Mics/TAKE03.wav", "RIFF"),
+        ]);
+        assert_eq!(remotes(&walk(&volume, &UploadConfig::default(), &[]).unwrap()), vec![
+            ("Session/Mics/TAKE03.wav".to_string(), "upload:/Auto_Upload/Session/Mics".to_string()),
+            ("Session/TAKE02.wav".to_string(), "upload:/Auto_Upload/Session".to_string()),
+            ("TAKE01.wav".to_string(), "upload:/Auto_Upload/".to_string()),
+        ]);
+    }
+
+    #[test]
+    fn test_walk_uploadignore() {
+        let volume = fat_volume(&[
+            (".uploadignore", "Private/\n*.tmp.wav\n"),
+            ("TAKE01.wav", "RIFF"),
+            ("TAKE02.tmp.wav", "RIFF"),
+            ("Private/TAKE03.wav", "RIFF"),
+            ("Session/.uploadignore", "*.wav\n!Keeper.wav\n"),
+            ("Session/TAKE04.wav", "RIFF"),
+            ("Session/Keeper.wav", "RIFF"),
+        ]);
+        let paths = walk(&volume, &UploadConfig::default(), &[]).unwrap().into_iter()
+            .map(|file| file.entry.path)
+            .collect::<Vec<_>>();
+        assert_eq!(paths, vec!["Session/Keeper.wav", "TAKE01.wav"]);
+    }
+
+    #[test]
+    fn test_walk_rules() {
+        let volume = fat_volume(&[
+            ("Gigs/.uploadrules.toml", "remote_folder = \"Live/2026\"\nquality = 3\n"),
+            ("Gigs/Encore/TAKE01.wav", "RIFF"),
+            ("Demos/.uploadrules.toml", "downmix = false\n"),
+            ("Demos/TAKE02.wav", "RIFF"),
+        ]);
+        let files = walk(&volume, &UploadConfig::default(), &[]).unwrap();
+        assert_eq!(remotes(&files), vec![
+            ("Demos/TAKE02.wav".to_string(), "upload:/Auto_Upload/Demos".to_string()),
+            ("Gigs/Encore/TAKE01.wav".to_string(), "upload:/Auto_Upload/Live/2026/Encore".to_string()),
+        ]);
+        assert!(!files[0].upload.downmix);
+        assert_eq!(files[0].upload.quality, 6.0);
+        assert_eq!(files[1].upload.quality, 3.0);
+        assert!(files[1].upload.downmix);
+    }
+
+    #[test]
+    fn test_walk_skips_invalid_folder() {
+        let volume = fat_volume(&[
+            ("TAKE01.wav", "RIFF"),
+            ("Private/.uploadrules.toml", "remote_folder = \"../Public\"\n"),
+            ("Private/TAKE02.wav", "RIFF"),
+            ("Loud/.uploadrules.toml", "quality = 11\n"),
+            ("Loud/TAKE03.wav", "RIFF"),
+        ]);
+        let paths = walk(&volume, &UploadConfig::default(), &[]).unwrap().into_iter()
+            .map(|file| file.entry.path)
+            .collect::<Vec<_>>();
+        assert_eq!(paths, vec!["TAKE01.wav"]);
+    }
+
+    #[test]
+    fn test_walk_exclude() {
+        let volume = fat_volume(&[
+            ("TAKE01.wav", "RIFF"),
+            ("._TAKE01.wav", "\u{0}\u{5}\u{16}\u{7}"),
+            (".Trashes/501/TAKE02.wav", "RIFF"),
+            ("System Volume Information/IndexerVolumeGuid", ""),
+            ("Session/.DS_Store", ""),
+            ("Session/._TAKE03.wav", "\u{0}\u{5}\u{16}\u{7}"),
+            ("Session/TAKE03.wav", "RIFF"),
+            ("SESSION2/THUMBS.DB", ""),
+        ]);
+        let exclude = ScanConfig::default().exclude;
+        let paths = |files: Vec<ScanFile>| files.into_iter().map(|file| file.entry.path).collect::<Vec<_>>();
+        assert_eq!(paths(walk(&volume, &UploadConfig::default(), &exclude).unwrap()), vec!["Session/TAKE03.wav", "TAKE01.wav"]);
+        assert_eq!(paths(walk(&volume, &UploadConfig::default(), &[]).unwrap()).len(), 8);
+    }
+
+    #[test]
+    fn test_exclude_overridden_by_uploadignore() {
+        let volume = fat_volume(&[
+            (".uploadignore", "!._keep.wav\n"),
+            ("._keep.wav", "RIFF"),
+            ("._TAKE01.wav", "RIFF"),
+        ]);
+        let files = walk(&volume, &UploadConfig::default(), &ScanConfig::default().exclude).unwrap();
+        assert_eq!(files.into_iter().map(|file| file.entry.path).collect::<Vec<_>>(), vec!["._keep.wav"]);
+    }
+
+    #[test]
+    fn test_is_apple_double() {
+        let volume = fat_volume(&[
+            ("TAKE01.wav", "\u{0}\u{5}\u{16}\u{7}\u{0}\u{2}"),
+            ("TAKE02.wav", "\u{0}\u{5}\u{16}\u{0}"),
+            ("TAKE03.wav", "RIFF\u{0}\u{5}\u{16}\u{7}"),
+            ("TAKE04.wav", "\u{0}"),
+        ]);
+        let apple_double = volume.read_dir(Path::new("")).unwrap().iter()
+            .map(|entry| (entry.file_name.clone(), is_apple_double(&volume, entry).unwrap()))
+            .collect::<Vec<_>>();
+        assert_eq!(apple_double, vec![
+            ("TAKE01.wav".to_string(), true),
+            ("TAKE02.wav".to_string(), true),
+            ("TAKE03.wav".to_string(), false),
+            ("TAKE04.wav".to_string(), false),
+        ]);
+    }
+
+    #[test]
+    fn test_parse_rules() {
+        assert_eq!(FolderRules::parse("remote_folder = \"Live/\"\n").unwrap().remote_folder, Some("Live/".to_string()));
+        assert!(FolderRules::parse("remote_folder = \"/Live\"\n").is_err());
+        assert!(FolderRules::parse("remote_folder = \"Live//2026\"\n").is_err());
+        assert!(FolderRules::parse("remote = \"upload:/Live\"\n").is_err());
+    }
+}
@@ -0,0 +1,421 @@
+use std::fs::{self, File};
+use std::io::{self, Read, Seek, SeekFrom, Write};
+use std::path::{Path, PathBuf};
+use fatfs::{self, FileSystem, FsOptions, OemCpConverter};
+use upload_command::{Error, Result};
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct VolumeEntry {
+    // Path relative to the volume root, with '/' separators, for display and matching. Names that
+    // are not UTF-8 are converted lossily, so two entries may share it.
+    pub path: String,
+    pub file_name: String,
+    // The exact path relative to the volume root, to open the file and key the upload database
+    pub os_path: PathBuf,
+    // 8.3 path, where the volume keeps one, as reported by fsck.fat
+    pub short_path: Option<String>,
+    pub len: u64,
+    pub is_dir: bool,
+}
+
+pub trait Volume {
+    fn read_dir(&self, dir: &Path) -> Result<Vec<VolumeEntry>>;
+
+    fn open<'a>(&'a self, entry: &VolumeEntry) -> Result<Box<dyn Read + 'a>>;
+
+    // Where other programs can open the file themselves, if the volume is mounted
+    fn local_path(&self, entry: &VolumeEntry) -> Option<PathBuf>;
+}
+
+pub struct MountedVolume {
+    root: PathBuf,
+}
+
+impl MountedVolume {
+    pub fn new(root: &Path) -> MountedVolume {
+        MountedVolume { root: root.to_path_buf() }
+    }
+}
+
+impl Volume for MountedVolume {
+    fn read_dir(&self, dir: &Path) -> Result<Vec<VolumeEntry>> {
+        let mut entries = Vec::new();
+        for dir_entry in fs::read_dir(self.root.join(dir))
+                .map_err(Error::IteratingDirectory)? {
+            let dir_entry = dir_entry
+                .map_err(Error::IteratingDirectory)?;
+            let metadata = dir_entry.metadata()
+                .map_err(Error::IteratingDirectory)?;
+            // Symlinks are skipped rather than followed, so a link the host left cannot lead out
+            // of the volume. Devices and pipes hold no recordings either.
+            let file_type = metadata.file_type();
+            if file_type.is_symlink() || !(file_type.is_file() || file_type.is_dir()) {
+                continue;
+            }
+            let os_path = dir.join(dir_entry.file_name());
+            let file_name = dir_entry.file_name().to_string_lossy().to_string();
+            entries.push(VolumeEntry {
+                path: os_path.to_string_lossy().to_string(),
+                file_name,
+                os_path,
+                short_path: None,
+                len: metadata.len(),
+                is_dir: metadata.is_dir(),
+            });
+        }
+        Ok(entries)
+    }
+
+    fn open<'a>(&'a self, entry: &VolumeEntry) -> Result<Box<dyn Read + 'a>> {
+        let file = File::open(self.root.join(&entry.os_path))
+            .map_err(Error::VolumeRead)?;
+        Ok(Box::new(file))
+    }
+
+    fn local_path(&self, entry: &VolumeEntry) -> Option<PathBuf> {
+        Some(self.root.join(&entry.os_path))
+    }
+}
+
+// Rejects every write so that the FAT driver can never modify the snapshot
+pub struct ReadOnlyDevice<T> {
+    inner: T,
+}
+
+impl<T: Read> Read for ReadOnlyDevice<T> {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        self.inner.read(buf)
+    }
+}
+
+impl<T: Seek> Seek for ReadOnlyDevice<T> {
+    fn seek(&mut self, pos: SeekFrom) -> io::Result<u64> {
+        self.inner.seek(pos)
+    }
+}
+
+impl<T> Write for ReadOnlyDevice<T> {
+    fn write(&mut self, _buf: &[u8]) -> io::Result<usize> {
+        Err(io::Error::new(io::ErrorKind::PermissionDenied, "volume is opened read-only"))
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        Ok(())
+    }
+}
+
+// Code page 437, the kernel's default for short names. Every byte maps to its own character, so
+// short names that differ only in bytes from 0x80 up still differ once converted.
+#[derive(Debug)]
+struct Cp437Converter;
+
+static CP437_CONVERTER: Cp437Converter = Cp437Converter;
+
+const CP437_HIGH: [char; 128] = [
+    'Ç', 'ü', 'é', 'â', 'ä', 'à', 'å', 'ç', 'ê', 'ë', 'è', 'ï', 'î', 'ì', 'Ä', 'Å',
+    'É', 'æ', 'Æ', 'ô', 'ö', 'ò', 'û', 'ù', 'ÿ', 'Ö', 'Ü', '¢', '£', '¥', '₧', 'ƒ',
+    'á', 'í', 'ó', 'ú', 'ñ', 'Ñ', 'ª', 'º', '¿', '⌐', '¬', '½', '¼', '¡', '«', '»',
+    '░', '▒', '▓', '│', '┤', '╡', '╢', '╖', '╕', '╣', '║', '╗', '╝', '╜', '╛', '┐',
+    '└', '┴', '┬', '├', '─', '┼', '╞', '╟', '╚', '╔', '╩', '╦', '╠', '═', '╬', '╧',
+    '╨', '╤', '╥', '╙', '╘', '╒', '╓', '╫', '╪', '┘', '┌', '█', '▄', '▌', '▐', '▀',
+    'α', 'ß', 'Γ', 'π', 'Σ', 'σ', 'µ', 'τ', 'Φ', 'Θ', 'Ω', 'δ', '∞', 'φ', 'ε', '∩',
+    '≡', '±', '≥', '≤', '⌠', '⌡', '÷', '≈', '°', '∙', '·', '√', 'ⁿ', '²', '■', '\u{a0}',
+];
+
+impl OemCpConverter for Cp437Converter {
+    fn decode(&self, oem_char: u8) -> char {
+        if oem_char < 0x80 {
+            oem_char as char
+        } else {
+            CP437_HIGH[(oem_char - 0x80) as usize]
+        }
+    }
+
+    fn encode(&self, uni_char: char) -> Option<u8> {
+        if (uni_char as u32) < 0x80 {
+            return Some(uni_char as u8);
+        }
+        CP437_HIGH.iter().position(|&c| c == uni_char).map(|index| 0x80 + index as u8)
+    }
+}
+
+// The name read_dir reports for an entry, unique within its directory. Long names with unpaired
+// surrogates convert lossily, so those fall back to the short name, which the directory keeps unique.
+fn entry_name<T: fatfs::ReadWriteSeek>(dir_entry: &fatfs::DirEntry<T>) -> String {
+    let file_name = dir_entry.file_name();
+    if file_name.contains(char::REPLACEMENT_CHARACTER) {
+        dir_entry.short_file_name()
+    } else {
+        file_name
+    }
+}
+
+pub struct FatVolume<T: Read + Seek> {
+    fs: FileSystem<ReadOnlyDevice<T>>,
+}
+
+impl FatVolume<File> {
+    pub fn open(device: &Path) -> Result<FatVolume<File>> {
+        let file = File::open(device)
+            .map_err(Error::VolumeRead)?;
+        FatVolume::new(file)
+    }
+}
+
+impl<T: Read + Seek> FatVolume<T> {
+    pub fn new(device: T) -> Result<FatVolume<T>> {
+        let options = FsOptions::new().update_accessed_date(false).oem_cp_converter(&CP437_CONVERTER);
+        let fs = FileSystem::new(ReadOnlyDevice { inner: device }, options)
+            .map_err(Error::VolumeRead)?;
+        Ok(FatVolume { fs })
+    }
+
+    pub fn is_dirty(&self) -> Result<bool> {
+        let flags = self.fs.read_status_flags()
+            .map_err(Error::VolumeRead)?;
+        Ok(flags.dirty())
+    }
+
+    // Looks up a path of names from read_dir. fatfs's own lookup compares names that may have been
+    // converted lossily, so it could find a different entry.
+    fn find_entry<'a>(&'a self, path: &str) -> Result<(fatfs::DirEntry<'a, ReadOnlyDevice<T>>, String)> {
+        let not_found = || Error::VolumeRead(io::Error::new(io::ErrorKind::NotFound, path.to_string()));
+        let mut current = self.fs.root_dir();
+        let mut found: Option<fatfs::DirEntry<_>> = None;
+        let mut short_path = String::new();
+        for component in path.split('/').filter(|component| !component.is_empty()) {
+            if let Some(ref dir_entry) = found {
+                if !dir_entry.is_dir() {
+                    return Err(not_found());
+                }
+                current = dir_entry.to_dir();
+            }
+            let dir_entry = current.iter()
+                .filter_map(|dir_entry| dir_entry.ok())
+                .find(|dir_entry| entry_name(dir_entry) == component)
+                .ok_or_else(not_found)?;
+            short_path = join_path(&short_path, &dir_entry.short_file_name());
+            found = Some(dir_entry);
+        }
+        found.map(|dir_entry| (dir_entry, short_path)).ok_or_else(not_found)
+    }
+
+    // The directory and its 8.3 path
+    fn dir<'a>(&'a self, dir: &str) -> Result<(fatfs::Dir<'a, ReadOnlyDevice<T>>, String)> {
+        if dir.is_empty() {
+            return Ok((self.fs.root_dir(), String::new()));
+        }
+        let (dir_entry, short_path) = self.find_entry(dir)?;
+        if !dir_entry.is_dir() {
+            return Err(Error::VolumeRead(io::Error::new(io::ErrorKind::NotFound, dir.to_string())));
+        }
+        Ok((dir_entry.to_dir(), short_path))
+    }
+}
+
+impl<T: Read + Seek> Volume for FatVolume<T> {
+    // Names are always converted to UTF-8, so paths that are not UTF-8 cannot have come from read_dir
+    fn read_dir(&self, dir: &Path) -> Result<Vec<VolumeEntry>> {
+        let dir = dir.to_str()
+            .ok_or_else(|| Error::VolumeRead(io::Error::new(io::ErrorKind::NotFound, dir.to_string_lossy().to_string())))?;
+        let (fat_dir, short_dir) = self.dir(dir)?;
+        let mut entries = Vec::new();
+        for dir_entry in fat_dir.iter() {
+            let dir_entry = dir_entry
+                .map_err(Error::IteratingDirectory)?;
+            let file_name = entry_name(&dir_entry);
+            if file_name == "." || file_name == ".." {
+                continue;
+            }
+            let path = join_path(dir, &file_name);
+            entries.push(VolumeEntry {
+                os_path: PathBuf::from(&path),
+                path,
+                file_name,
+                short_path: Some(join_path(&short_dir, &dir_entry.short_file_name())),
+                len: dir_entry.len(),
+                is_dir: dir_entry.is_dir(),
+            });
+        }
+        Ok(entries)
+    }
+
+    fn open<'a>(&'a self, entry: &VolumeEntry) -> Result<Box<dyn Read + 'a>> {
+        let (dir_entry, _) = self.find_entry(&entry.path)?;
+        if !dir_entry.is_file() {
+            return Err(Error::VolumeRead(io::Error::new(io::ErrorKind::NotFound, entry.path.clone())));
+        }
+        Ok(Box::new(dir_entry.to_file()))
+    }
+
+    fn local_path(&self, _entry: &VolumeEntry) -> Option<PathBuf> {
+        None
+    }
+}
+
+// Reads a small text file, such as a configuration file left by the user
+pub fn read_text(volume: &dyn Volume, entry: &VolumeEntry, max_len: u64) -> Result<String> {
+    if entry.len > max_len {
+        return Err(Error::ConfigInvalid(format!("{} is larger than {} bytes", entry.path, max_len)));
+    }
+    let mut contents = Vec::new();
+    volume.open(entry)?.take(max_len).read_to_end(&mut contents).map_err(Error::VolumeRead)?;
+    String::from_utf8(contents)
+        .map_err(|_| Error::ConfigInvalid(format!("{} is not UTF-8 text", entry.path)))
+}
+
+fn join_path(dir: &str, file_name: &str) -> String {
+    if dir.is_empty() {
+        file_name.to_string()
+    } else {
+        format!("{}/{}", dir, file_name)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::io::Cursor;
+    use test_util::{TempDir, fat_image};
+
+    fn file_names(entries: &[VolumeEntry]) -> Vec<String> {
+        let mut names = entries.iter().map(|entry| entry.path.clone()).collect::<Vec<String>>();
+        names.sort();
+        names
+    }
+
+    #[test]
+    fn test_fat_volume_read_dir() {
+        let image = fat_image(&[("TAKE01.wav", b"RIFF1234"), ("Session/TAKE02.wav", b"RIFF")]);
+        let volume = FatVolume::new(Cursor::new(image)).unwrap();
+
+        let root = volume.read_dir(Path::new("")).unwrap();
+        assert_eq!(file_names(&root), vec!["Session", "TAKE01.wav"]);
+        let take = root.iter().find(|entry| entry.file_name == "TAKE01.wav").unwrap();
+        assert_eq!(take.len, 8);
+        assert!(!take.is_dir);
+        assert!(root.iter().find(|entry| entry.file_name == "Session").unwrap().is_dir);
+
+        let session = volume.read_dir(Path::new("Session")).unwrap();
+        assert_eq!(file_names(&session), vec!["Session/TAKE02.wav"]);
+        assert_eq!(session[0].short_path, Some("SESSION/TAKE02.WAV".to_string()));
+    }
+
+    #[test]
+    fn test_fat_volume_open() {
+        let contents = (0..100_000u32).map(|i| i as u8).collect::<Vec<u8>>();
+        let image = fat_image(&[("Session/LONG.wav", &contents)]);
+        let volume = FatVolume::new(Cursor::new(image)).unwrap();
+
+        let entry = volume.read_dir(Path::new("Session")).unwrap().remove(0);
+        let mut read = Vec::new();
+        volume.open(&entry).unwrap().read_to_end(&mut read).unwrap();
+        assert_eq!(read, contents);
+    }
+
+    #[test]
+    fn test_fat_volume_high_byte_short_names() {
+        let mut image = fat_image(&[("CAFX.WAV", b"first"), ("CAFY.WAV", b"second")]);
+        // fatfs cannot create these, so patch the short names on disk to differ only in one high byte
+        for (name, byte) in &[(b"CAFX    WAV", 0x82), (b"CAFY    WAV", 0x90)] {
+            let offset = image.windows(name.len()).position(|window| window == &name[..]).unwrap();
+            image[offset + 3] = *byte;
+        }
+        let volume = FatVolume::new(Cursor::new(image)).unwrap();
+
+        let mut entries = volume.read_dir(Path::new("")).unwrap();
+        entries.sort_by(|a, b| a.path.cmp(&b.path));
+        assert_eq!(file_names(&entries), vec!["CAFÉ.WAV", "CAFé.WAV"]);
+        assert_ne!(entries[0].os_path, entries[1].os_path);
+        for (entry, expected) in entries.iter().zip(&["second", "first"]) {
+            let mut contents = String::new();
+            volume.open(entry).unwrap().read_to_string(&mut contents).unwrap();
+            assert_eq!(contents, *expected);
+        }
+    }
+
+    #[test]
+    fn test_cp437_converter_round_trip() {
+        for byte in 0..=255u8 {
+            assert_eq!(CP437_CONVERTER.encode(CP437_CONVERTER.decode(byte)), Some(byte));
+        }
+    }
+
+    #[test]
+    fn test_fat_volume_never_writes() {
+        let image = fat_image(&[("TAKE01.wav", b"RIFF")]);
+        let mut device = Cursor::new(image.clone());
+        {
+            let volume = FatVolume::new(&mut device).unwrap();
+            let entry = volume.read_dir(Path::new("")).unwrap().remove(0);
+            volume.open(&entry).unwrap().read_to_end(&mut Vec::new()).unwrap();
+        }
+        assert!(device.into_inner() == image);
+    }
+
+    #[test]
+    fn test_fat_volume_image_file() {
+        let dir = TempDir::new("fat_image_file");
+        let image_path = dir.join("stick.img");
+        fs::write(&image_path, fat_image(&[("TAKE01.wav", b"RIFF")])).unwrap();
+        let names = file_names(&FatVolume::open(&image_path).unwrap().read_dir(Path::new("")).unwrap());
+        assert_eq!(names, vec!["TAKE01.wav"]);
+    }
+
+    #[test]
+    fn test_mounted_volume_non_utf8_names() {
+        use std::ffi::OsStr;
+        use std::os::unix::ffi::OsStrExt;
+
+        let root = TempDir::new("non_utf8");
+        let latin1 = OsStr::from_bytes(b"Caf\xe9");
+        fs::create_dir_all(root.join(latin1)).unwrap();
+        fs::write(root.join(latin1).join(OsStr::from_bytes(b"Take\xe9.wav")), b"latin1").unwrap();
+        fs::write(root.join(latin1).join(OsStr::from_bytes(b"Take\x82.wav")), b"cp437").unwrap();
+
+        let volume = MountedVolume::new(&root);
+        let dir = volume.read_dir(Path::new("")).unwrap().remove(0);
+        assert_eq!(dir.os_path, Path::new(latin1));
+        let mut entries = volume.read_dir(&dir.os_path).unwrap();
+        entries.sort_by(|a, b| a.os_path.cmp(&b.os_path));
+        assert_eq!(entries[0].path, entries[1].path);
+        assert_ne!(entries[0].os_path, entries[1].os_path);
+        let mut contents = String::new();
+        volume.open(&entries[0]).unwrap().read_to_string(&mut contents).unwrap();
+        assert_eq!(contents, "cp437");
+    }
+
+    #[test]
+    fn test_mounted_volume_skips_symlinks() {
+        use std::os::unix::fs::symlink;
+
+        let root = TempDir::new("symlinks");
+        fs::create_dir_all(root.join("Session")).unwrap();
+        fs::write(root.join("TAKE01.wav"), b"take").unwrap();
+        symlink("/etc/passwd", root.join("passwd.wav")).unwrap();
+        symlink("/etc", root.join("etc")).unwrap();
+        symlink("TAKE01.wav", root.join("Session/TAKE02.wav")).unwrap();
+
+        let volume = MountedVolume::new(&root);
+        let mut names = volume.read_dir(Path::new("")).unwrap().into_iter()
+            .map(|entry| entry.file_name)
+            .collect::<Vec<String>>();
+        names.sort();
+        assert_eq!(names, vec!["Session", "TAKE01.wav"]);
+        assert!(volume.read_dir(Path::new("Session")).unwrap().is_empty());
+    }
+
+    #[test]
+    fn test_fat_volume_dirty() {
+        let mut image = fat_image(&[]);
+        assert!(!FatVolume::new(Cursor::new(image.clone())).unwrap().is_dirty().unwrap());
+        // FAT12/16 keep the dirty flag in bit 0 of the boot sector byte at 0x25
+        image[0x25] |= 1;
+        assert!(FatVolume::new(Cursor::new(image)).unwrap().is_dirty().unwrap());
+    }
+
+    #[test]
+    fn test_fat_volume_rejects_garbage() {
+        assert!(FatVolume::new(Cursor::new(vec![0u8; 1 << 20])).is_err());
+    }
+}